@@ -0,0 +1,87 @@
+use revault_net::noise::PublicKey as NoisePubKey;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+// We don't want a peer to make us store an arbitrarily large string
+const MAX_USER_AGENT_LEN: usize = 128;
+
+/// The software our peers identified themselves with, so that operators know which versions
+/// are in the field before deprecating protocol behaviors
+#[derive(Debug, Default)]
+pub struct UserAgents {
+    peers: Mutex<HashMap<[u8; 32], String>>,
+}
+
+impl UserAgents {
+    pub fn new() -> UserAgents {
+        UserAgents::default()
+    }
+
+    /// Record the software this peer identified itself with, replacing any previous one
+    pub fn record(&self, peer: &NoisePubKey, user_agent: &str) {
+        let user_agent: String = user_agent.chars().take(MAX_USER_AGENT_LEN).collect();
+        self.peers
+            .lock()
+            .expect("User agents lock poisoned")
+            .insert(peer.0, user_agent);
+    }
+
+    /// The number of peers per software, or None if none identified itself yet
+    pub fn summary(&self) -> Option<String> {
+        let peers = self.peers.lock().expect("User agents lock poisoned");
+        if peers.is_empty() {
+            return None;
+        }
+
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for user_agent in peers.values() {
+            *counts.entry(user_agent).or_default() += 1;
+        }
+        Some(
+            counts
+                .iter()
+                .map(|(user_agent, count)| format!("'{}': {}", user_agent, count))
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserAgents;
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    #[test]
+    fn user_agents_summary() {
+        let user_agents = UserAgents::new();
+        assert!(user_agents.summary().is_none());
+
+        let (peer_a, peer_b, peer_c) = (
+            NoisePubKey([1; 32]),
+            NoisePubKey([2; 32]),
+            NoisePubKey([3; 32]),
+        );
+        user_agents.record(&peer_a, "revaultd/0.3.0");
+        user_agents.record(&peer_b, "revaultd/0.3.0");
+        user_agents.record(&peer_c, "revaultd/0.2.0");
+        assert_eq!(
+            user_agents.summary().unwrap(),
+            "'revaultd/0.2.0': 1, 'revaultd/0.3.0': 2"
+        );
+
+        // It's per peer, so an upgrade replaces the previous version
+        user_agents.record(&peer_c, "revaultd/0.3.0");
+        assert_eq!(user_agents.summary().unwrap(), "'revaultd/0.3.0': 3");
+
+        // Overly long ones are truncated
+        user_agents.record(&peer_a, &"a".repeat(1_000));
+        assert_eq!(
+            user_agents.summary().unwrap(),
+            format!("'{}': 1, 'revaultd/0.3.0': 2", "a".repeat(128))
+        );
+    }
+}
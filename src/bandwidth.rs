@@ -0,0 +1,195 @@
+// The bytes exchanged with each peer, for coordinators on metered hosts. Peers over their daily
+// or monthly quota can't fetch data anymore until the next period, but can still send us
+// signatures and Spend transactions: we'd rather pay for those than lose them.
+
+use revault_net::{bitcoin::hashes::hex::ToHex, noise::PublicKey as NoisePubKey};
+
+use std::{collections::HashMap, error, fmt, sync::Mutex};
+
+use chrono::{DateTime, Datelike, Utc};
+
+/// A period over which we limit the bytes exchanged with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+/// A peer exchanged more bytes with us than its quota for the current period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub period: QuotaPeriod,
+    pub quota: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let period = match self.period {
+            QuotaPeriod::Day => "daily",
+            QuotaPeriod::Month => "monthly",
+        };
+        write!(
+            f,
+            "Exceeded the {} quota of {} bytes, refusing to serve more data",
+            period, self.quota
+        )
+    }
+}
+
+impl error::Error for QuotaExceeded {}
+
+#[derive(Debug, Default)]
+struct PeerUsage {
+    // The day since the common era and the month (as year and month) we are counting for
+    day: i32,
+    month: (i32, u32),
+    day_bytes: u64,
+    month_bytes: u64,
+}
+
+impl PeerUsage {
+    // Start counting anew on a new day or month
+    fn roll(&mut self, now: &DateTime<Utc>) {
+        let (day, month) = (now.num_days_from_ce(), (now.year(), now.month()));
+        if self.day != day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+}
+
+/// The bytes of the messages exchanged with each peer this day and this month, and how many
+/// we accept. The framing of the transport isn't counted.
+#[derive(Debug)]
+pub struct Bandwidth {
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    peers: Mutex<HashMap<[u8; 32], PeerUsage>>,
+}
+
+impl Bandwidth {
+    pub fn new(daily_quota: Option<u64>, monthly_quota: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            daily_quota,
+            monthly_quota,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count these bytes, sent or received, against this peer's quotas
+    pub fn record(&self, peer: &NoisePubKey, bytes: usize, now: DateTime<Utc>) {
+        let mut peers = self.peers.lock().expect("Bandwidth lock poisoned");
+        let usage = peers.entry(peer.0).or_default();
+
+        usage.roll(&now);
+        usage.day_bytes = usage.day_bytes.saturating_add(bytes as u64);
+        usage.month_bytes = usage.month_bytes.saturating_add(bytes as u64);
+    }
+
+    /// Check this peer didn't exceed its quotas for the current day and month
+    pub fn check(&self, peer: &NoisePubKey, now: DateTime<Utc>) -> Result<(), QuotaExceeded> {
+        let mut peers = self.peers.lock().expect("Bandwidth lock poisoned");
+        let usage = match peers.get_mut(&peer.0) {
+            Some(usage) => usage,
+            None => return Ok(()),
+        };
+
+        usage.roll(&now);
+        match (self.daily_quota, self.monthly_quota) {
+            (Some(quota), _) if usage.day_bytes > quota => Err(QuotaExceeded {
+                period: QuotaPeriod::Day,
+                quota,
+            }),
+            (_, Some(quota)) if usage.month_bytes > quota => Err(QuotaExceeded {
+                period: QuotaPeriod::Month,
+                quota,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The bytes exchanged with each peer this day and this month, or None if there was no
+    /// exchange yet
+    pub fn summary(&self, now: DateTime<Utc>) -> Option<String> {
+        let mut peers = self.peers.lock().expect("Bandwidth lock poisoned");
+        if peers.is_empty() {
+            return None;
+        }
+
+        let mut usages: Vec<String> = peers
+            .iter_mut()
+            .map(|(peer, usage)| {
+                usage.roll(&now);
+                format!(
+                    "'{}': {} bytes today, {} this month",
+                    peer.to_hex(),
+                    usage.day_bytes,
+                    usage.month_bytes
+                )
+            })
+            .collect();
+        usages.sort();
+        Some(usages.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bandwidth, QuotaExceeded, QuotaPeriod};
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn bandwidth_quotas() {
+        let bandwidth = Bandwidth::new(Some(1_000), Some(1_500));
+        let (peer_a, peer_b) = (NoisePubKey([1; 32]), NoisePubKey([2; 32]));
+        let now = Utc.ymd(2021, 3, 31).and_hms(12, 0, 0);
+
+        bandwidth.record(&peer_a, 600, now);
+        bandwidth.record(&peer_a, 400, now);
+        bandwidth.check(&peer_a, now).unwrap();
+        bandwidth.record(&peer_a, 1, now);
+        assert_eq!(
+            bandwidth.check(&peer_a, now),
+            Err(QuotaExceeded {
+                period: QuotaPeriod::Day,
+                quota: 1_000
+            })
+        );
+        // Quotas are per peer
+        bandwidth.check(&peer_b, now).unwrap();
+
+        // The next day we can serve it again, until its monthly quota
+        let tomorrow = Utc.ymd(2021, 4, 1).and_hms(12, 0, 0);
+        bandwidth.check(&peer_a, tomorrow).unwrap();
+        bandwidth.record(&peer_b, 1_001, now);
+        bandwidth.record(&peer_b, 600, tomorrow);
+        bandwidth.check(&peer_b, tomorrow).unwrap();
+        let next_day = Utc.ymd(2021, 4, 2).and_hms(12, 0, 0);
+        bandwidth.record(&peer_a, 600, next_day);
+        bandwidth.record(&peer_a, 900, next_day);
+        bandwidth.check(&peer_a, next_day).unwrap_err();
+        bandwidth.record(&peer_b, 901, next_day);
+        assert_eq!(
+            bandwidth.check(&peer_b, next_day),
+            Err(QuotaExceeded {
+                period: QuotaPeriod::Month,
+                quota: 1_500
+            })
+        );
+
+        assert_eq!(
+            bandwidth.summary(next_day).unwrap(),
+            format!(
+                "'{}': 1500 bytes today, 1500 this month, '{}': 901 bytes today, 1501 this month",
+                "01".repeat(32),
+                "02".repeat(32)
+            )
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{bandwidth::QuotaExceeded, db::DbError, ratelimit::RateLimited};
+use revault_net::{bitcoin::hashes::hex::ToHex, noise::PublicKey as NoisePubKey};
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Something a peer did that it should not have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// It sent us a message we could not make sense of
+    MalformedMessage,
+    /// It sent us a message larger than we accept
+    OversizedMessage,
+    /// It sent us a signature which isn't in the low-S form, one no signer produces
+    NonCanonicalSignature,
+    /// It sent us a message faster than its rate limit allows
+    RateLimited,
+    /// It sent us a message fetching data past its bandwidth quota
+    QuotaExceeded,
+}
+
+impl Misbehavior {
+    fn score(&self) -> u32 {
+        match self {
+            Self::MalformedMessage => 20,
+            Self::OversizedMessage => 50,
+            Self::NonCanonicalSignature => 10,
+            // A client may poll a bit too eagerly, only a sustained one gets banned
+            Self::RateLimited => 2,
+            Self::QuotaExceeded => 5,
+        }
+    }
+
+    /// The misbehavior a peer's message was refused for, if it was
+    pub fn of_processing_error(error: &(dyn Error + 'static)) -> Option<Misbehavior> {
+        if error.is::<serde_json::Error>() {
+            Some(Self::MalformedMessage)
+        } else if let Some(DbError::NonCanonicalSignature) = error.downcast_ref() {
+            Some(Self::NonCanonicalSignature)
+        } else if error.is::<RateLimited>() {
+            Some(Self::RateLimited)
+        } else if error.is::<QuotaExceeded>() {
+            Some(Self::QuotaExceeded)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MalformedMessage => write!(f, "malformed messages"),
+            Self::OversizedMessage => write!(f, "oversized messages"),
+            Self::NonCanonicalSignature => write!(f, "non-canonical signatures"),
+            Self::RateLimited => write!(f, "rate-limited messages"),
+            Self::QuotaExceeded => write!(f, "messages past its bandwidth quota"),
+        }
+    }
+}
+
+/// A peer we refuse connections from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ban {
+    /// Its Noise key
+    pub peer: String,
+    /// For how long it's still banned, in seconds
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct PeerScore {
+    score: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Keeps track of our peers' misbehavior score, and bans them for a while once they reach
+/// a threshold. The score is reset once the ban expires.
+#[derive(Debug)]
+pub struct BanList {
+    threshold: u32,
+    ban_duration: Duration,
+    peers: Mutex<HashMap<[u8; 32], PeerScore>>,
+}
+
+impl BanList {
+    pub fn new(threshold: u32, ban_duration: Duration) -> BanList {
+        BanList {
+            threshold,
+            ban_duration,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether we should refuse connections from this peer at the moment
+    pub fn is_banned(&self, peer: &NoisePubKey) -> bool {
+        let mut peers = self.peers.lock().expect("Ban list lock poisoned");
+
+        match peers.get(&peer.0).and_then(|p| p.banned_until) {
+            Some(banned_until) if banned_until > Instant::now() => true,
+            Some(_) => {
+                peers.remove(&peer.0);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Increase this peer's misbehavior score. Returns true if it just got banned.
+    pub fn misbehaved(&self, peer: &NoisePubKey, misbehavior: Misbehavior) -> bool {
+        let mut peers = self.peers.lock().expect("Ban list lock poisoned");
+        let peer_score = peers.entry(peer.0).or_default();
+
+        if peer_score.banned_until.is_some() {
+            return false;
+        }
+        peer_score.score = peer_score.score.saturating_add(misbehavior.score());
+        if peer_score.score >= self.threshold {
+            peer_score.banned_until = Some(Instant::now() + self.ban_duration);
+            return true;
+        }
+
+        false
+    }
+
+    /// The peers currently banned, the most recently banned first
+    pub fn bans(&self) -> Vec<Ban> {
+        let mut peers = self.peers.lock().expect("Ban list lock poisoned");
+        let now = Instant::now();
+        peers.retain(|_, p| p.banned_until.map_or(true, |until| until > now));
+
+        let mut bans: Vec<(Instant, Ban)> = peers
+            .iter()
+            .filter_map(|(key, p)| {
+                p.banned_until.map(|until| {
+                    (
+                        until,
+                        Ban {
+                            peer: key.to_hex(),
+                            remaining_secs: until.duration_since(now).as_secs(),
+                        },
+                    )
+                })
+            })
+            .collect();
+        bans.sort_by_key(|(until, _)| std::cmp::Reverse(*until));
+        bans.into_iter().map(|(_, ban)| ban).collect()
+    }
+
+    /// Lift the ban of this peer, if any, and reset its score. Returns whether it was banned.
+    pub fn unban(&self, peer: &NoisePubKey) -> bool {
+        let mut peers = self.peers.lock().expect("Ban list lock poisoned");
+        let now = Instant::now();
+
+        peers
+            .remove(&peer.0)
+            .and_then(|p| p.banned_until)
+            .map_or(false, |until| until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BanList, Misbehavior};
+    use crate::{
+        bandwidth::{QuotaExceeded, QuotaPeriod},
+        db::DbError,
+        ratelimit::RateLimited,
+    };
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    use std::time::Duration;
+
+    #[test]
+    fn ban_on_threshold() {
+        let ban_list = BanList::new(50, Duration::from_secs(3600));
+        let (peer_a, peer_b) = (NoisePubKey([1; 32]), NoisePubKey([2; 32]));
+
+        assert!(!ban_list.misbehaved(&peer_a, Misbehavior::MalformedMessage));
+        assert!(!ban_list.misbehaved(&peer_a, Misbehavior::MalformedMessage));
+        assert!(!ban_list.is_banned(&peer_a));
+        assert!(ban_list.misbehaved(&peer_a, Misbehavior::MalformedMessage));
+        assert!(ban_list.is_banned(&peer_a));
+        // It's already banned
+        assert!(!ban_list.misbehaved(&peer_a, Misbehavior::MalformedMessage));
+
+        // Scores are per peer
+        assert!(!ban_list.is_banned(&peer_b));
+        assert!(!ban_list.misbehaved(&peer_b, Misbehavior::MalformedMessage));
+        assert!(ban_list.misbehaved(&peer_b, Misbehavior::OversizedMessage));
+    }
+
+    #[test]
+    fn ban_expiry() {
+        let ban_list = BanList::new(20, Duration::from_secs(0));
+        let peer = NoisePubKey([1; 32]);
+
+        assert!(ban_list.misbehaved(&peer, Misbehavior::MalformedMessage));
+        assert!(!ban_list.is_banned(&peer));
+        // The score was reset along with the ban
+        assert!(ban_list.misbehaved(&peer, Misbehavior::MalformedMessage));
+    }
+
+    #[test]
+    fn misbehavior_of_errors() {
+        let malformed: Box<dyn std::error::Error> =
+            serde_json::from_str::<u32>("{").unwrap_err().into();
+        assert_eq!(
+            Misbehavior::of_processing_error(malformed.as_ref()),
+            Some(Misbehavior::MalformedMessage)
+        );
+        let non_canonical: Box<dyn std::error::Error> = DbError::NonCanonicalSignature.into();
+        assert_eq!(
+            Misbehavior::of_processing_error(non_canonical.as_ref()),
+            Some(Misbehavior::NonCanonicalSignature)
+        );
+        let rate_limited: Box<dyn std::error::Error> = RateLimited {
+            retry_after: Duration::from_secs(1),
+        }
+        .into();
+        assert_eq!(
+            Misbehavior::of_processing_error(rate_limited.as_ref()),
+            Some(Misbehavior::RateLimited)
+        );
+        let over_quota: Box<dyn std::error::Error> = QuotaExceeded {
+            period: QuotaPeriod::Day,
+            quota: 1_000,
+        }
+        .into();
+        assert_eq!(
+            Misbehavior::of_processing_error(over_quota.as_ref()),
+            Some(Misbehavior::QuotaExceeded)
+        );
+        // Not its fault
+        let full: Box<dyn std::error::Error> = DbError::StorageFull.into();
+        assert_eq!(Misbehavior::of_processing_error(full.as_ref()), None);
+    }
+
+    #[test]
+    fn list_and_lift_bans() {
+        let ban_list = BanList::new(20, Duration::from_secs(3600));
+        let (peer_a, peer_b, peer_c) = (
+            NoisePubKey([1; 32]),
+            NoisePubKey([2; 32]),
+            NoisePubKey([3; 32]),
+        );
+        assert!(ban_list.bans().is_empty());
+
+        assert!(ban_list.misbehaved(&peer_a, Misbehavior::MalformedMessage));
+        assert!(ban_list.misbehaved(&peer_b, Misbehavior::OversizedMessage));
+        // Scored, but not banned
+        assert!(!ban_list.misbehaved(&peer_c, Misbehavior::RateLimited));
+        let bans = ban_list.bans();
+        assert_eq!(
+            bans.iter().map(|ban| ban.peer.as_str()).collect::<Vec<_>>(),
+            vec!["02".repeat(32), "01".repeat(32)]
+        );
+        assert!(bans.iter().all(|ban| ban.remaining_secs > 3590));
+
+        assert!(ban_list.unban(&peer_a));
+        assert!(!ban_list.is_banned(&peer_a));
+        assert!(!ban_list.unban(&peer_a));
+        assert!(!ban_list.unban(&peer_c));
+        assert_eq!(ban_list.bans().len(), 1);
+        // Its score was reset
+        assert!(!ban_list.misbehaved(&peer_a, Misbehavior::NonCanonicalSignature));
+    }
+}
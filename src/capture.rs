@@ -0,0 +1,161 @@
+// Recording of the decrypted protocol frames, so that wallet developers and operators can
+// debug interoperability issues offline. Captures are meant to be shared: signatures and keys
+// are truncated to a short prefix, enough to tell them apart but not to use them.
+
+use revault_net::{bitcoin::hashes::hex::ToHex, noise::PublicKey as NoisePubKey};
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+// How many characters of a signature or key we keep
+const REDACTED_PREFIX_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// A frame a peer sent us
+    Received,
+    /// A frame we sent to a peer
+    Sent,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Received => "received",
+            Self::Sent => "sent",
+        }
+    }
+}
+
+fn redact(data: &str) -> String {
+    match data.get(..REDACTED_PREFIX_LEN) {
+        Some(prefix) if data.len() > REDACTED_PREFIX_LEN => format!("{}..", prefix),
+        _ => data.to_string(),
+    }
+}
+
+/// Truncate the signatures and public keys in this message, wherever they are
+fn redact_frame(frame: Value) -> Value {
+    match frame {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match (name.as_str(), value) {
+                        ("signature", Value::String(s)) | ("pubkey", Value::String(s)) => {
+                            Value::String(redact(&s))
+                        }
+                        // A map from public keys to signatures
+                        ("signatures", Value::Object(sigs)) => Value::Object(
+                            sigs.into_iter()
+                                .map(|(pubkey, sig)| match sig {
+                                    Value::String(sig) => {
+                                        (redact(&pubkey), Value::String(redact(&sig)))
+                                    }
+                                    sig => (redact(&pubkey), redact_frame(sig)),
+                                })
+                                .collect(),
+                        ),
+                        (_, value) => redact_frame(value),
+                    };
+                    (name, value)
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(redact_frame).collect()),
+        value => value,
+    }
+}
+
+/// A file we append a JSON line to for each frame we receive or send
+#[derive(Debug)]
+pub struct Capture {
+    file: Mutex<fs::File>,
+}
+
+impl Capture {
+    pub fn open(path: &Path) -> Result<Capture, io::Error> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Capture {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record this frame, identified the same way as in our logs. Failing to do so is not
+    /// fatal: the capture is only a debugging aid.
+    pub fn record(&self, trace_id: &str, peer: &NoisePubKey, direction: Direction, frame: &[u8]) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        // Frames we can't parse are recorded as is, they are likely what we are debugging
+        let frame = match serde_json::from_slice(frame) {
+            Ok(frame) => serde_json::json!({ "message": redact_frame(frame) }),
+            Err(_) => serde_json::json!({ "raw": String::from_utf8_lossy(frame) }),
+        };
+        let line = serde_json::json!({
+            "timestamp_ms": timestamp_ms,
+            "trace_id": trace_id,
+            "peer": redact(&peer.0.to_hex()),
+            "direction": direction.as_str(),
+            "frame": frame,
+        });
+
+        let mut file = self.file.lock().expect("Capture lock poisoned");
+        if let Err(e) = writeln!(file, "{}", line) {
+            log::warn!("Writing frame to the capture file: '{}'", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact_frame;
+
+    #[test]
+    fn redact_capture_frames() {
+        let sig = serde_json::json!({
+            "pubkey": "03ffae85b76dd0dd96cbf23348fb398ab93274466759201ecf29d0f68ddd9d1b6c",
+            "signature": "304402204b0ab8a7d95d5b67d5c1b8584a3075adcac787a315f79a9b52b5a736909c975502206def9036d3d980a7cb66f2baa64ebdcd6648d70b324c6c18c349fa240dd07ca8",
+            "id": "6a276a96807dd45ceed9cbd6fd48b5edf185623b23339a1643e19e8dcbf2e474",
+        });
+        assert_eq!(
+            redact_frame(sig),
+            serde_json::json!({
+                "pubkey": "03ffae85..",
+                "signature": "30440220..",
+                "id": "6a276a96807dd45ceed9cbd6fd48b5edf185623b23339a1643e19e8dcbf2e474",
+            })
+        );
+
+        let sigs = serde_json::json!({
+            "signatures": {
+                "028c887a4a78211ff320802134046cb1db92215614ac0a078c261ed860f3067f0f": "304402201fbe986a41b69ea65bbb94a042cb6a5edacb898f290c76d76deb5d74241d0309022065d5ad54a36962b75857ce22ddf2189e71e5a0fe6df6e6d5d0c8acdb59e16374",
+            },
+            "merkle_root": "656bb859f7d3e1a8c7fb6a4be723965b4d11a6ba285f04de7a4fd63eca576f14",
+        });
+        assert_eq!(
+            redact_frame(sigs),
+            serde_json::json!({
+                "signatures": { "028c887a..": "30440220.." },
+                "merkle_root": "656bb859f7d3e1a8c7fb6a4be723965b4d11a6ba285f04de7a4fd63eca576f14",
+            })
+        );
+
+        // Nothing else is touched
+        let spend_tx = serde_json::json!({ "transaction": null, "version": 3 });
+        assert_eq!(redact_frame(spend_tx.clone()), spend_tx);
+    }
+}
@@ -0,0 +1,279 @@
+// The errors we may report, each with a stable code. A participant's connection is closed on
+// an error processing its message, and it is told the code of the error beforehand. The control
+// socket and the one-shot commands report theirs too. `--explain-error <code>` then tells what
+// it means and what usually fixes it, without access to our logs.
+//
+// Codes are never reused nor renumbered: a new error gets a new code.
+
+use serde::Serialize;
+
+/// An error we may report, and how to address it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CatalogEntry {
+    pub code: u32,
+    pub name: &'static str,
+    pub meaning: &'static str,
+    pub remediation: &'static str,
+}
+
+// Errors processing a participant's message
+pub const MALFORMED_MESSAGE: u32 = 1000;
+pub const OUT_OF_ROLE: u32 = 1001;
+pub const RATE_LIMITED: u32 = 1002;
+pub const STORAGE_FULL: u32 = 1003;
+pub const REPLAYED_MESSAGE: u32 = 1004;
+pub const QUOTA_EXCEEDED: u32 = 1005;
+pub const POLICY_VIOLATION: u32 = 1006;
+pub const DUPLICATE: u32 = 1007;
+pub const NON_CANONICAL_SIGNATURE: u32 = 1008;
+pub const SIG_WINDOW_CLOSED: u32 = 1009;
+pub const OUTDATED_VERSION: u32 = 1010;
+pub const DATABASE_UNAVAILABLE: u32 = 1011;
+pub const CONFLICTING_SIGNATURE: u32 = 1012;
+pub const INVALID_SPEND: u32 = 1013;
+pub const CONFLICTING_SPEND: u32 = 1014;
+pub const OTHER_PROCESSING_ERROR: u32 = 1099;
+
+// Errors answering a request on the control socket
+pub const CONTROL_PARSE_ERROR: u32 = 2000;
+pub const CONTROL_INVALID_REQUEST: u32 = 2001;
+pub const CONTROL_METHOD_NOT_FOUND: u32 = 2002;
+pub const CONTROL_INVALID_PARAMS: u32 = 2003;
+pub const CONTROL_INTERNAL_ERROR: u32 = 2004;
+
+/// The failures of the one-shot commands are their exit code past this one
+pub const EXIT_CODES_BASE: u32 = 3000;
+
+pub const CATALOG: &[CatalogEntry] = &[
+    CatalogEntry {
+        code: MALFORMED_MESSAGE,
+        name: "malformed_message",
+        meaning: "The message is not valid JSON, or not one of the messages we know about.",
+        remediation: "Make sure the wallet runs a version speaking the same protocol as the \
+                      coordinator. Repeated malformed messages get the peer banned for a while.",
+    },
+    CatalogEntry {
+        code: OUT_OF_ROLE,
+        name: "out_of_role",
+        meaning: "None of the roles the sender's Noise key is configured with allows it to send \
+                  this message, such as a manager sending a signature.",
+        remediation: "Check the key is listed under the right role in the coordinator's \
+                      configuration, and that the wallet is configured with the matching key.",
+    },
+    CatalogEntry {
+        code: RATE_LIMITED,
+        name: "rate_limited",
+        meaning: "The peer sent messages faster than the configured rate limit allows.",
+        remediation: "Poll less often, or raise `message_rate` and `message_burst`.",
+    },
+    CatalogEntry {
+        code: STORAGE_FULL,
+        name: "storage_full",
+        meaning: "The database holds as much data as the coordinator was configured to store, \
+                  so it refuses new signatures and Spend transactions. Fetching still works.",
+        remediation: "Prune old data (`--prune-before`, `retention_days`) or raise \
+                      `max_stored_bytes`. `--capacity-report` tells how close to the limit \
+                      the database is.",
+    },
+    CatalogEntry {
+        code: REPLAYED_MESSAGE,
+        name: "replayed_message",
+        meaning: "The message counter is not greater than the last one received from this peer, \
+                  so the message may be a replay of an older one.",
+        remediation: "Make sure the wallet persists its message counter and doesn't share its \
+                      Noise key with another instance.",
+    },
+    CatalogEntry {
+        code: QUOTA_EXCEEDED,
+        name: "quota_exceeded",
+        meaning: "The peer exchanged more bytes with the coordinator than its daily or monthly \
+                  quota, so it isn't served more data until the next period.",
+        remediation: "Fetch signatures less often, or raise `daily_byte_quota` and \
+                      `monthly_byte_quota`.",
+    },
+    CatalogEntry {
+        code: POLICY_VIOLATION,
+        name: "policy_violation",
+        meaning: "The announced Spend transaction pays to outputs outside of the configured \
+                  destinations.",
+        remediation: "Check the Spend transaction's outputs. If they are legitimate, add the \
+                      destinations to `spend_destinations`.",
+    },
+    CatalogEntry {
+        code: DUPLICATE,
+        name: "duplicate",
+        meaning: "The data was already stored, such as the same Spend transaction announced \
+                  twice.",
+        remediation: "None needed if the wallet is retrying: the first attempt went through.",
+    },
+    CatalogEntry {
+        code: NON_CANONICAL_SIGNATURE,
+        name: "non_canonical_signature",
+        meaning: "The signature isn't in the low-S form, the only one the network relays.",
+        remediation: "Update the signing wallet, it must normalize its signatures.",
+    },
+    CatalogEntry {
+        code: SIG_WINDOW_CLOSED,
+        name: "sig_window_closed",
+        meaning: "The acceptance window the managers set for this transaction's signatures is \
+                  closed.",
+        remediation: "Ask a manager to open a new window for the transaction, then sign again.",
+    },
+    CatalogEntry {
+        code: OUTDATED_VERSION,
+        name: "outdated_version",
+        meaning: "The Spend announcement was replaced by a newer one since the wallet last \
+                  fetched it.",
+        remediation: "Fetch the current announcement and retry against its version.",
+    },
+    CatalogEntry {
+        code: DATABASE_UNAVAILABLE,
+        name: "database_unavailable",
+        meaning: "The coordinator failed to access its database, or every connection to it was \
+                  busy for too long.",
+        remediation: "Retry later, after `retry_after_ms` if the response tells. If it persists, \
+                      the coordinator's operator should check the database server and \
+                      `db_max_connections`.",
+    },
+    CatalogEntry {
+        code: CONFLICTING_SIGNATURE,
+        name: "conflicting_signature",
+        meaning: "Another signature of the same key for the same transaction is already stored, \
+                  for instance one made before re-signing with other nonces. The first one is \
+                  kept, and served to the other participants.",
+        remediation: "Keep using the stored signature. If it is the wrong one, the coordinator's \
+                      operator can delete it (`delsig` on the control socket) before signing \
+                      again.",
+    },
+    CatalogEntry {
+        code: INVALID_SPEND,
+        name: "invalid_spend",
+        meaning: "The Spend transaction isn't one the watchtowers could act upon: it has no \
+                  input or output, spends an output twice or a deposit directly, has an \
+                  unsigned input, or isn't announced for distinct deposit outpoints.",
+        remediation: "Finalize the Spend before announcing it, for the deposits whose Unvault \
+                      outputs it spends.",
+    },
+    CatalogEntry {
+        code: CONFLICTING_SPEND,
+        name: "conflicting_spend",
+        meaning: "Another Spend transaction is already announced for one of the deposit \
+                  outpoints, and the message doesn't ask to replace it.",
+        remediation: "Check with the other managers that the announced Spend is to be replaced, \
+                      then send the new one again with `\"replace\": true`, or with the \
+                      `expected_version` of the announcement it replaces.",
+    },
+    CatalogEntry {
+        code: OTHER_PROCESSING_ERROR,
+        name: "other",
+        meaning: "The message was refused for a reason not covered by another code.",
+        remediation: "Report it to the coordinator's operator, along with the time it happened.",
+    },
+    CatalogEntry {
+        code: CONTROL_PARSE_ERROR,
+        name: "control_parse_error",
+        meaning: "The control socket request is not valid JSON.",
+        remediation: "Send a single JSON-RPC 2.0 object per line.",
+    },
+    CatalogEntry {
+        code: CONTROL_INVALID_REQUEST,
+        name: "control_invalid_request",
+        meaning: "The control socket request is not a JSON-RPC 2.0 request.",
+        remediation: "Set `\"jsonrpc\": \"2.0\"` and a `method`.",
+    },
+    CatalogEntry {
+        code: CONTROL_METHOD_NOT_FOUND,
+        name: "control_method_not_found",
+        meaning: "The control socket doesn't know about this method.",
+        remediation: "Check the method's name against the README, and the coordinator's \
+                      version with `getinfo`.",
+    },
+    CatalogEntry {
+        code: CONTROL_INVALID_PARAMS,
+        name: "control_invalid_params",
+        meaning: "The method was given the wrong number of parameters, or an invalid one.",
+        remediation: "Pass the parameters positionally, as documented in the README.",
+    },
+    CatalogEntry {
+        code: CONTROL_INTERNAL_ERROR,
+        name: "control_internal_error",
+        meaning: "The coordinator failed to execute the command, usually to access its database.",
+        remediation: "Check the database server, then retry.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 1,
+        name: "command_internal",
+        meaning: "A one-shot command failed for a reason not covered below.",
+        remediation: "Check the error message printed along with the code.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 2,
+        name: "command_usage",
+        meaning: "The command line is invalid.",
+        remediation: "Check the flags against the usage printed along with the error.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 3,
+        name: "command_config",
+        meaning: "The configuration file is missing or invalid.",
+        remediation: "Pass it with `--conf`, and check the setting named in the error.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 4,
+        name: "command_database",
+        meaning: "The command could not access the database, or it refused the operation.",
+        remediation: "Check the `postgres_uri` credentials and that the server is reachable.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 5,
+        name: "command_file",
+        meaning: "The command could not read or write a file it was pointed to.",
+        remediation: "Check the path and its permissions.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 6,
+        name: "command_backup",
+        meaning: "The backup bundle could not be created or opened.",
+        remediation: "Check the passphrase, and that the bundle wasn't truncated.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 7,
+        name: "command_key",
+        meaning: "Our Noise key could not be read, decrypted, created or rotated.",
+        remediation: "Check the passphrase (or COORDINATORD_KEY_PASSPHRASE), and the \
+                      permissions of the key files in the data directory.",
+    },
+    CatalogEntry {
+        code: EXIT_CODES_BASE + 8,
+        name: "command_unhealthy",
+        meaning: "The running coordinator can't reach its database or stopped accepting \
+                  connections, or it didn't answer on its control socket.",
+        remediation: "Check the database server, or whether the coordinator is running and \
+                      shutting down. The error printed along with the code tells which.",
+    },
+];
+
+/// What this error code means, if we know about it
+pub fn explain(code: u32) -> Option<&'static CatalogEntry> {
+    CATALOG.iter().find(|entry| entry.code == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{explain, CATALOG, EXIT_CODES_BASE, STORAGE_FULL};
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn error_catalog() {
+        let codes: HashSet<u32> = CATALOG.iter().map(|entry| entry.code).collect();
+        assert_eq!(codes.len(), CATALOG.len());
+        let names: HashSet<&str> = CATALOG.iter().map(|entry| entry.name).collect();
+        assert_eq!(names.len(), CATALOG.len());
+
+        assert_eq!(explain(STORAGE_FULL).unwrap().name, "storage_full");
+        assert_eq!(explain(EXIT_CODES_BASE + 6).unwrap().name, "command_backup");
+        assert!(explain(EXIT_CODES_BASE + 9).is_none());
+        assert!(explain(0).is_none());
+    }
+}
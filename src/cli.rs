@@ -0,0 +1,71 @@
+// Reporting the outcome of our one-shot commands, either to humans or (with `--json`) to
+// deployment automation, which can also branch on the exit code.
+
+use revault_coordinatord::catalog::EXIT_CODES_BASE;
+
+use serde_json::Value;
+
+use std::process;
+
+/// Why a one-shot command failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Anything not covered below, such as failing to start the async runtime
+    Internal = 1,
+    /// The command line is invalid
+    Usage = 2,
+    /// The configuration file is missing or invalid
+    Config = 3,
+    /// We could not access the database, or it refused the operation
+    Database = 4,
+    /// We could not read or write a file we were pointed to
+    File = 5,
+    /// The backup bundle could not be created or opened, for instance with a wrong passphrase
+    Backup = 6,
+    /// Our Noise key could not be read, decrypted, created or rotated
+    Key = 7,
+    /// The running coordinator can't serve the participants, or didn't answer
+    Unhealthy = 8,
+}
+
+/// Where to report the outcome of a one-shot command
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    pub json: bool,
+}
+
+impl Output {
+    /// Report a success. The `details` object, if any, is part of the JSON output.
+    pub fn success(&self, message: &str, details: Value) {
+        if self.json {
+            let mut output = serde_json::json!({ "success": true });
+            if let Value::Object(details) = details {
+                output
+                    .as_object_mut()
+                    .expect("Just created as an object")
+                    .extend(details);
+            }
+            println!("{}", output);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    /// Report a failure and exit with this code
+    pub fn fail(&self, code: ExitCode, message: &str) -> ! {
+        if self.json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "success": false,
+                    "exit_code": code as i32,
+                    "error_code": EXIT_CODES_BASE + code as u32,
+                    "error": message,
+                })
+            );
+        } else {
+            eprintln!("{}", message);
+        }
+        process::exit(code as i32);
+    }
+}
@@ -0,0 +1,732 @@
+// A local control socket for the operators to inspect and administer a running coordinator
+// without access to its database. It speaks JSON-RPC 2.0, one request per line, and is only
+// accessible to the user running the coordinator.
+
+use crate::{
+    bans::BanList,
+    catalog::{self, explain},
+    daemon::ShutdownHandle,
+    db::{
+        check_connection, delete_sig, fetch_audit_trail, fetch_sigs, has_sig, list_spend_txs,
+        sig_progress, verify_audit_trail, DbConfig,
+    },
+    events::{Event, Events},
+    health::{DatabaseHealth, HealthReport, LastWrite, ListenerHealth},
+    limits::Limits,
+    peers::Peers,
+    sessions::Sessions,
+};
+use revault_net::{
+    bitcoin::{secp256k1::PublicKey, Txid},
+    noise::PublicKey as NoisePubKey,
+    sodiumoxide,
+};
+
+use std::{
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::broadcast::{error::RecvError, Receiver},
+    time::timeout,
+};
+
+// The error codes defined by the JSON-RPC 2.0 specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+// How long we wait for the database to accept a connection before reporting it unreachable
+const HEALTH_DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The code of our error catalog for this JSON-RPC one
+fn catalog_code(code: i64) -> u32 {
+    match code {
+        PARSE_ERROR => catalog::CONTROL_PARSE_ERROR,
+        INVALID_REQUEST => catalog::CONTROL_INVALID_REQUEST,
+        METHOD_NOT_FOUND => catalog::CONTROL_METHOD_NOT_FOUND,
+        INVALID_PARAMS => catalog::CONTROL_INVALID_PARAMS,
+        _ => catalog::CONTROL_INTERNAL_ERROR,
+    }
+}
+
+/// An error to answer a request with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// What an operator may ask us
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Our version, for how long we've been running and the number of sessions per role
+    GetInfo,
+    /// Whether we can reach the database and accept connections
+    Health,
+    /// The signatures we store for this transaction
+    ListSigs(Txid),
+    /// How many of the stakeholders signed this transaction
+    GetSigProgress(Txid),
+    /// The Spend transactions we store, and the deposit outpoints they are announced for
+    ListSpendTxs,
+    /// Delete the signature of this key for this transaction, or only tell whether there is one
+    /// to delete on a dry run
+    DelSig(Txid, PublicKey, bool),
+    /// The signatures and Spend transactions stored about this transaction, and by whom
+    AuditTx(Txid),
+    /// The signatures and Spend transactions this participant sent us
+    AuditPeer(NoisePubKey),
+    /// Whether the audit trail wasn't rewritten
+    VerifyAudit,
+    /// The peers we currently refuse connections from, for misbehaving
+    ListBans,
+    /// Accept connections from this peer again, with a clean slate
+    Unban(NoisePubKey),
+    /// What this code of our error catalog means and how to address it
+    ExplainError(u32),
+    /// Stream the connections, refusals, disconnections and bans from now on
+    SubscribeEvents,
+    /// Shut down, as on SIGTERM
+    Stop,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<String>,
+}
+
+// The parameters of this method, which must all be given and in order
+fn params<'a>(method: &str, params: &'a [String], count: usize) -> Result<&'a [String], RpcError> {
+    if params.len() != count {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("'{}' takes {} parameter(s)", method, count),
+        ));
+    }
+    Ok(params)
+}
+
+// The parameters of a method which may be asked for a dry run, by giving "dry-run" last, and
+// whether it was
+fn dry_run_params<'a>(
+    method: &str,
+    args: &'a [String],
+    count: usize,
+) -> Result<(&'a [String], bool), RpcError> {
+    match args.split_last() {
+        Some((last, rest)) if last == "dry-run" => Ok((params(method, rest, count)?, true)),
+        _ => Ok((params(method, args, count)?, false)),
+    }
+}
+
+fn txid_param(txid: &str) -> Result<Txid, RpcError> {
+    Txid::from_str(txid)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid txid '{}': {}", txid, e)))
+}
+
+fn error_code_param(code: &str) -> Result<u32, RpcError> {
+    u32::from_str(code).map_err(|e| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("Invalid error code '{}': {}", code, e),
+        )
+    })
+}
+
+fn pubkey_param(pubkey: &str) -> Result<PublicKey, RpcError> {
+    PublicKey::from_str(pubkey).map_err(|e| {
+        RpcError::new(
+            INVALID_PARAMS,
+            format!("Invalid public key '{}': {}", pubkey, e),
+        )
+    })
+}
+
+fn noise_key_param(key: &str) -> Result<NoisePubKey, RpcError> {
+    sodiumoxide::hex::decode(key)
+        .ok()
+        .and_then(|key| NoisePubKey::from_slice(&key))
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Invalid Noise key '{}'", key)))
+}
+
+/// Make sense of a request line. Returns the request id along with the command or the error
+/// to answer with, the id being null if we couldn't get it.
+pub fn parse_request(line: &str) -> (Value, Result<Command, RpcError>) {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) if e.is_syntax() || e.is_eof() => {
+            return (Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))
+        }
+        Err(e) => {
+            return (
+                Value::Null,
+                Err(RpcError::new(INVALID_REQUEST, e.to_string())),
+            )
+        }
+    };
+    if request.jsonrpc != "2.0" {
+        return (
+            request.id,
+            Err(RpcError::new(
+                INVALID_REQUEST,
+                "Only JSON-RPC 2.0 is supported",
+            )),
+        );
+    }
+
+    let method = request.method.as_str();
+    let command = match method {
+        "getinfo" => params(method, &request.params, 0).map(|_| Command::GetInfo),
+        "health" => params(method, &request.params, 0).map(|_| Command::Health),
+        "listsigs" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::ListSigs(txid_param(&params[0])?))),
+        "getsigprogress" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::GetSigProgress(txid_param(&params[0])?))),
+        "listspendtxs" => params(method, &request.params, 0).map(|_| Command::ListSpendTxs),
+        "delsig" => dry_run_params(method, &request.params, 2).and_then(|(params, dry_run)| {
+            Ok(Command::DelSig(
+                txid_param(&params[0])?,
+                pubkey_param(&params[1])?,
+                dry_run,
+            ))
+        }),
+        "audittx" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::AuditTx(txid_param(&params[0])?))),
+        "auditpeer" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::AuditPeer(noise_key_param(&params[0])?))),
+        "verifyaudit" => params(method, &request.params, 0).map(|_| Command::VerifyAudit),
+        "listbans" => params(method, &request.params, 0).map(|_| Command::ListBans),
+        "unban" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::Unban(noise_key_param(&params[0])?))),
+        "explainerror" => params(method, &request.params, 1)
+            .and_then(|params| Ok(Command::ExplainError(error_code_param(&params[0])?))),
+        "subscribe_events" => params(method, &request.params, 0).map(|_| Command::SubscribeEvents),
+        "stop" => params(method, &request.params, 0).map(|_| Command::Stop),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method '{}'", method),
+        )),
+    };
+
+    (request.id, command)
+}
+
+/// The response line to a request with this id. Errors carry the code of our error catalog
+/// as `data`.
+pub fn response_line(id: Value, result: Result<Value, RpcError>) -> String {
+    let mut line = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": error.code,
+                "message": error.message,
+                "data": { "error_code": catalog_code(error.code) },
+            },
+            "id": id,
+        }),
+    }
+    .to_string();
+    line.push('\n');
+    line
+}
+
+/// What the commands act upon
+pub(crate) struct Control {
+    started: Instant,
+    sessions: Arc<Sessions>,
+    db_config: DbConfig,
+    peers: Peers,
+    ban_list: Arc<BanList>,
+    last_write: Arc<LastWrite>,
+    limits: Arc<Limits>,
+    events: Arc<Events>,
+    shutdown: ShutdownHandle,
+}
+
+impl Control {
+    pub fn new(
+        sessions: Arc<Sessions>,
+        db_config: DbConfig,
+        peers: Peers,
+        ban_list: Arc<BanList>,
+        last_write: Arc<LastWrite>,
+        limits: Arc<Limits>,
+        events: Arc<Events>,
+        shutdown: ShutdownHandle,
+    ) -> Control {
+        Control {
+            started: Instant::now(),
+            sessions,
+            db_config,
+            peers,
+            ban_list,
+            last_write,
+            limits,
+            events,
+            shutdown,
+        }
+    }
+
+    async fn execute(&self, command: &Command) -> Result<Value, RpcError> {
+        let internal = |e: crate::db::DbError| RpcError::new(INTERNAL_ERROR, e.to_string());
+
+        match command {
+            Command::GetInfo => {
+                let [managers, stakeholders, watchtowers] = self.sessions.current_counts();
+                Ok(json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "uptime": self.started.elapsed().as_secs(),
+                    "sessions": {
+                        "managers": managers,
+                        "stakeholders": stakeholders,
+                        "watchtowers": watchtowers,
+                    },
+                    "limits": self.limits.usage(),
+                }))
+            }
+            Command::Health => {
+                let db_config = self.db_config.get();
+                let error = match timeout(HEALTH_DB_TIMEOUT, check_connection(&db_config)).await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some(format!(
+                        "No connection after {} seconds",
+                        HEALTH_DB_TIMEOUT.as_secs()
+                    )),
+                };
+                let report = HealthReport::new(
+                    DatabaseHealth {
+                        reachable: error.is_none(),
+                        error,
+                        last_write: self.last_write.get(),
+                    },
+                    ListenerHealth {
+                        address: self.shutdown.local_addr(),
+                        accepting: !self.shutdown.is_requested(),
+                    },
+                );
+                Ok(serde_json::to_value(&report).expect("Health reports always serialize"))
+            }
+            Command::ListSigs(txid) => {
+                let sigs = fetch_sigs(&self.db_config.get(), *txid, None)
+                    .await
+                    .map_err(internal)?;
+                Ok(serde_json::to_value(&sigs).expect("Signatures always serialize"))
+            }
+            Command::GetSigProgress(txid) => {
+                // As of the last reload
+                let stakeholders = self.peers.get().stakeholders.len() as u32;
+                let progress = sig_progress(&self.db_config.get(), *txid, stakeholders)
+                    .await
+                    .map_err(internal)?;
+                Ok(serde_json::to_value(&progress).expect("Progress always serializes"))
+            }
+            Command::ListSpendTxs => {
+                let spend_txs = list_spend_txs(&self.db_config.get())
+                    .await
+                    .map_err(internal)?;
+                Ok(json!({ "spend_txs": spend_txs }))
+            }
+            Command::DelSig(txid, pubkey, true) => {
+                let found = has_sig(&self.db_config.get(), *txid, *pubkey)
+                    .await
+                    .map_err(internal)?;
+                Ok(json!({ "would_delete": found }))
+            }
+            Command::DelSig(txid, pubkey, false) => {
+                let deleted = delete_sig(&self.db_config.get(), *txid, *pubkey)
+                    .await
+                    .map_err(internal)?;
+                if deleted {
+                    log::warn!(
+                        "Deleted the signature of '{}' for '{}' on the operator's request",
+                        pubkey,
+                        txid
+                    );
+                }
+                Ok(json!({ "deleted": deleted }))
+            }
+            Command::AuditTx(txid) => {
+                let trail = fetch_audit_trail(&self.db_config.get(), Some(*txid), None)
+                    .await
+                    .map_err(internal)?;
+                Ok(json!({ "audit": trail }))
+            }
+            Command::AuditPeer(key) => {
+                let trail = fetch_audit_trail(&self.db_config.get(), None, Some(&key.0))
+                    .await
+                    .map_err(internal)?;
+                Ok(json!({ "audit": trail }))
+            }
+            Command::VerifyAudit => {
+                let intact = verify_audit_trail(&self.db_config.get())
+                    .await
+                    .map_err(internal)?;
+                if !intact {
+                    log::error!("The audit trail was rewritten");
+                }
+                Ok(json!({ "intact": intact }))
+            }
+            Command::ListBans => Ok(json!({ "bans": self.ban_list.bans() })),
+            Command::Unban(key) => {
+                let unbanned = self.ban_list.unban(key);
+                if unbanned {
+                    log::warn!(
+                        "Lifted the ban of '{}' on the operator's request",
+                        sodiumoxide::hex::encode(key.0)
+                    );
+                }
+                Ok(json!({ "unbanned": unbanned }))
+            }
+            Command::ExplainError(code) => explain(*code)
+                .map(|entry| serde_json::to_value(entry).expect("Catalog entries always serialize"))
+                .ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, format!("Unknown error code {}", code))
+                }),
+            // The events are streamed once we answered
+            Command::SubscribeEvents => Ok(json!({})),
+            // We only shut down once we answered
+            Command::Stop => Ok(json!({})),
+        }
+    }
+}
+
+// Answer the requests of this client until it disconnects
+async fn handle(stream: UnixStream, control: &Control) -> Result<(), io::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, command) = parse_request(&line);
+        // Not to miss the events published until we answer
+        let events = match command {
+            Ok(Command::SubscribeEvents) => Some(control.events.subscribe()),
+            _ => None,
+        };
+        let result = match command {
+            Ok(ref command) => control.execute(command).await,
+            Err(e) => Err(e),
+        };
+        writer
+            .write_all(response_line(id, result).as_bytes())
+            .await?;
+
+        if let Ok(Command::Stop) = command {
+            log::info!("Asked to stop on the control socket, shutting down");
+            control.shutdown.shutdown();
+        }
+        if let Some(events) = events {
+            return stream_events(&mut lines, &mut writer, events).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// The notification line for this event
+fn event_line(event: &Event) -> String {
+    notification_line("event", json!(event))
+}
+
+fn notification_line(method: &str, params: Value) -> String {
+    let mut line = json!({ "jsonrpc": "2.0", "method": method, "params": params }).to_string();
+    line.push('\n');
+    line
+}
+
+// Write the events as notifications until the client disconnects. What it sends meanwhile is
+// ignored. If it doesn't keep up, it's told how many events it missed.
+async fn stream_events(
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut OwnedWriteHalf,
+    mut events: Receiver<Event>,
+) -> Result<(), io::Error> {
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(_) => continue,
+                None => return Ok(()),
+            },
+            event = events.recv() => match event {
+                Ok(event) => event_line(&event),
+                Err(RecvError::Lagged(missed)) => {
+                    notification_line("events_missed", json!({ "count": missed }))
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        };
+        writer.write_all(line.as_bytes()).await?;
+    }
+}
+
+/// Listen on this path, replacing the socket a previous run may have left behind. Only our
+/// user may connect to it.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener, io::Error> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answer the clients connecting to this listener
+pub(crate) async fn serve(listener: &UnixListener, control: Arc<Control>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &control).await {
+                        log::debug!("Control connection: '{}'", e);
+                    }
+                });
+            }
+            Err(e) => log::error!("Accepting a control connection: '{}'", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        event_line, parse_request, response_line, Command, RpcError, INVALID_PARAMS,
+        INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR,
+    };
+    use crate::{catalog, events::Event};
+    use revault_net::{
+        bitcoin::{secp256k1::PublicKey, Txid},
+        noise::PublicKey as NoisePubKey,
+    };
+
+    use std::str::FromStr;
+
+    use serde_json::{json, Value};
+
+    fn error_code(line: &str) -> i64 {
+        parse_request(line).1.unwrap_err().code
+    }
+
+    #[test]
+    fn control_requests() {
+        let txid = "264595a4ace1865dfa442bb923320b8f00413711655165ac13a470db2c5384c0";
+        let pubkey = "03ffae85b76dd0dd96cbf23348fb398ab93274466759201ecf29d0f68ddd9d1b6c";
+
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "id": 1, "method": "getinfo"}"#),
+            (json!(1), Ok(Command::GetInfo))
+        );
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "id": 1, "method": "health"}"#).1,
+            Ok(Command::Health)
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": "a", "method": "listsigs", "params": ["{}"]}}"#,
+                txid
+            )),
+            (
+                json!("a"),
+                Ok(Command::ListSigs(Txid::from_str(txid).unwrap()))
+            )
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 2, "method": "getsigprogress", "params": ["{}"]}}"#,
+                txid
+            ))
+            .1,
+            Ok(Command::GetSigProgress(Txid::from_str(txid).unwrap()))
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 2, "method": "delsig", "params": ["{}", "{}"]}}"#,
+                txid, pubkey
+            ))
+            .1,
+            Ok(Command::DelSig(
+                Txid::from_str(txid).unwrap(),
+                PublicKey::from_str(pubkey).unwrap(),
+                false
+            ))
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 2, "method": "delsig", "params": ["{}", "{}", "dry-run"]}}"#,
+                txid, pubkey
+            ))
+            .1,
+            Ok(Command::DelSig(
+                Txid::from_str(txid).unwrap(),
+                PublicKey::from_str(pubkey).unwrap(),
+                true
+            ))
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 4, "method": "audittx", "params": ["{}"]}}"#,
+                txid
+            ))
+            .1,
+            Ok(Command::AuditTx(Txid::from_str(txid).unwrap()))
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 4, "method": "auditpeer", "params": ["{}"]}}"#,
+                "0a".repeat(32)
+            ))
+            .1,
+            Ok(Command::AuditPeer(NoisePubKey([0x0a; 32])))
+        );
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "id": 4, "method": "verifyaudit"}"#).1,
+            Ok(Command::VerifyAudit)
+        );
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "id": 4, "method": "listbans"}"#).1,
+            Ok(Command::ListBans)
+        );
+        assert_eq!(
+            parse_request(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 4, "method": "unban", "params": ["{}"]}}"#,
+                "0a".repeat(32)
+            ))
+            .1,
+            Ok(Command::Unban(NoisePubKey([0x0a; 32])))
+        );
+        assert_eq!(
+            parse_request(
+                r#"{"jsonrpc": "2.0", "id": 5, "method": "explainerror", "params": ["1003"]}"#
+            )
+            .1,
+            Ok(Command::ExplainError(1003))
+        );
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "id": 6, "method": "subscribe_events"}"#).1,
+            Ok(Command::SubscribeEvents)
+        );
+        assert_eq!(
+            parse_request(r#"{"jsonrpc": "2.0", "method": "stop", "params": []}"#),
+            (Value::Null, Ok(Command::Stop))
+        );
+
+        assert_eq!(error_code(r#"{"jsonrpc": "2.0", "#), PARSE_ERROR);
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3}"#),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "1.0", "id": 3, "method": "getinfo"}"#),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3, "method": "getsigs"}"#),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            error_code(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 3, "method": "auditpeer", "params": ["{}"]}}"#,
+                pubkey
+            )),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3, "method": "unban", "params": ["0a"]}"#),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "explainerror", "params": ["E1"]}"#
+            ),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3, "method": "listsigs"}"#),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc": "2.0", "id": 3, "method": "listsigs", "params": ["aa"]}"#),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            error_code(&format!(
+                r#"{{"jsonrpc": "2.0", "id": 3, "method": "delsig", "params": ["{}", "02"]}}"#,
+                txid
+            )),
+            INVALID_PARAMS
+        );
+
+        let answer: Value =
+            serde_json::from_str(&response_line(json!(4), Ok(json!({ "deleted": true })))).unwrap();
+        assert_eq!(
+            answer,
+            json!({ "jsonrpc": "2.0", "result": { "deleted": true }, "id": 4 })
+        );
+        let answer: Value = serde_json::from_str(&response_line(
+            Value::Null,
+            Err(RpcError {
+                code: PARSE_ERROR,
+                message: "EOF".to_string(),
+            }),
+        ))
+        .unwrap();
+        assert_eq!(answer["error"]["code"], PARSE_ERROR);
+        assert_eq!(
+            answer["error"]["data"]["error_code"],
+            catalog::CONTROL_PARSE_ERROR
+        );
+        assert_eq!(answer["id"], Value::Null);
+
+        // The events are streamed as notifications, without id
+        let notification: Value = serde_json::from_str(&event_line(&Event::Banned {
+            peer: "0a".repeat(32),
+            misbehavior: "oversized messages".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(
+            notification,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "event",
+                "params": {
+                    "event": "banned",
+                    "peer": "0a".repeat(32),
+                    "misbehavior": "oversized messages",
+                },
+            })
+        );
+    }
+}
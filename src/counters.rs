@@ -0,0 +1,151 @@
+// The last message counter we got from each peer, so that a message storing data which was
+// recorded in a previous session can't be replayed in a new one, where it decrypts just fine.
+// They are persisted lazily: those received since the last flush are lost on a crash, and
+// their messages could then be replayed once.
+
+use revault_net::noise::PublicKey as NoisePubKey;
+
+use std::{collections::HashMap, error, fmt, sync::Mutex};
+
+/// A message whose counter is not greater than the last one we got from its sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayedMessage {
+    pub counter: i64,
+    pub last: i64,
+}
+
+impl fmt::Display for ReplayedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Message counter {} is not greater than the last one ({}), it may be replayed",
+            self.counter, self.last
+        )
+    }
+}
+
+impl error::Error for ReplayedMessage {}
+
+#[derive(Debug, Default)]
+struct PeerCounter {
+    last: i64,
+    persisted: i64,
+}
+
+/// The last counter of each peer which numbers its messages. Peers which never did start at 0.
+#[derive(Debug, Default)]
+pub struct MessageCounters {
+    peers: Mutex<HashMap<[u8; 32], PeerCounter>>,
+}
+
+impl MessageCounters {
+    /// Start from the counters we persisted
+    pub fn new(persisted: Vec<([u8; 32], i64)>) -> MessageCounters {
+        MessageCounters {
+            peers: Mutex::new(
+                persisted
+                    .into_iter()
+                    .map(|(peer, counter)| {
+                        (
+                            peer,
+                            PeerCounter {
+                                last: counter,
+                                persisted: counter,
+                            },
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Record the counter of a message from this peer, unless it's not greater than the last
+    /// one we got from it
+    pub fn check(&self, peer: &NoisePubKey, counter: i64) -> Result<(), ReplayedMessage> {
+        let mut peers = self.peers.lock().expect("Message counters lock poisoned");
+        let peer_counter = peers.entry(peer.0).or_default();
+
+        if counter <= peer_counter.last {
+            return Err(ReplayedMessage {
+                counter,
+                last: peer_counter.last,
+            });
+        }
+        peer_counter.last = counter;
+
+        Ok(())
+    }
+
+    /// The counters which changed since they were last persisted
+    pub fn unpersisted(&self) -> Vec<([u8; 32], i64)> {
+        self.peers
+            .lock()
+            .expect("Message counters lock poisoned")
+            .iter()
+            .filter(|(_, c)| c.last > c.persisted)
+            .map(|(peer, c)| (*peer, c.last))
+            .collect()
+    }
+
+    /// Mark these counters, as returned by `unpersisted()`, as persisted
+    pub fn persisted(&self, counters: &[([u8; 32], i64)]) {
+        let mut peers = self.peers.lock().expect("Message counters lock poisoned");
+        for (peer, counter) in counters {
+            if let Some(peer_counter) = peers.get_mut(peer) {
+                peer_counter.persisted = peer_counter.persisted.max(*counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageCounters, ReplayedMessage};
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    #[test]
+    fn replayed_counters() {
+        let (peer_a, peer_b) = (NoisePubKey([1; 32]), NoisePubKey([2; 32]));
+        let counters = MessageCounters::new(vec![(peer_a.0, 10)]);
+
+        // We start from the persisted counter
+        assert_eq!(
+            counters.check(&peer_a, 10),
+            Err(ReplayedMessage {
+                counter: 10,
+                last: 10
+            })
+        );
+        counters.check(&peer_a, 12).unwrap();
+        assert_eq!(
+            counters.check(&peer_a, 11),
+            Err(ReplayedMessage {
+                counter: 11,
+                last: 12
+            })
+        );
+
+        // Counters are per peer, and start at 0
+        counters.check(&peer_b, 1).unwrap();
+        assert!(counters.check(&peer_b, 0).is_err());
+        assert!(counters.check(&NoisePubKey([3; 32]), 0).is_err());
+    }
+
+    #[test]
+    fn lazy_persistence() {
+        let peer = NoisePubKey([1; 32]);
+        let counters = MessageCounters::new(vec![(peer.0, 10)]);
+        assert!(counters.unpersisted().is_empty());
+
+        counters.check(&peer, 11).unwrap();
+        let unpersisted = counters.unpersisted();
+        assert_eq!(unpersisted, vec![(peer.0, 11)]);
+
+        // A counter received while we were persisting is still to be persisted
+        counters.check(&peer, 12).unwrap();
+        counters.persisted(&unpersisted);
+        assert_eq!(counters.unpersisted(), vec![(peer.0, 12)]);
+        counters.persisted(&counters.unpersisted());
+        assert!(counters.unpersisted().is_empty());
+    }
+}
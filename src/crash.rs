@@ -0,0 +1,181 @@
+// Diagnostics for the post-mortem of rare crashes. On a panic we write a bundle with its
+// backtrace, the last lines we logged, the sessions we had open and a hash of our
+// configuration to the data directory, so that it doesn't depend on core dumps being
+// enabled on the host.
+
+#[cfg(feature = "daemon")]
+use crate::sessions::Sessions;
+use revault_net::bitcoin::hashes::sha256;
+
+#[cfg(feature = "daemon")]
+use std::sync::Weak;
+use std::{
+    backtrace::Backtrace,
+    collections::VecDeque,
+    fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    panic::{self, PanicHookInfo},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+// How many of the last log lines we keep around
+const RECENT_LOGS: usize = 500;
+
+// The sessions of the running coordinator, if any. We don't keep it alive for this.
+#[cfg(feature = "daemon")]
+static SESSIONS: Mutex<Option<Weak<Sessions>>> = Mutex::new(None);
+
+/// The last lines we logged, to chain to our logger
+#[derive(Debug, Clone, Default)]
+pub struct LogRing(Arc<Mutex<VecDeque<String>>>);
+
+impl LogRing {
+    pub fn new() -> LogRing {
+        LogRing::default()
+    }
+
+    // We may be panicking while logging, don't wait for ourselves
+    fn lines(&self) -> Vec<String> {
+        match self.0.try_lock() {
+            Ok(lines) => lines.iter().cloned().collect(),
+            Err(_) => vec!["<the log lines were being written>".to_string()],
+        }
+    }
+}
+
+impl log::Log for LogRing {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Ok(mut lines) = self.0.lock() {
+            if lines.len() >= RECENT_LOGS {
+                lines.pop_front();
+            }
+            lines.push_back(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Report the sessions of this coordinator in the bundles
+#[cfg(feature = "daemon")]
+pub(crate) fn watch_sessions(sessions: &Arc<Sessions>) {
+    if let Ok(mut watched) = SESSIONS.lock() {
+        *watched = Some(Arc::downgrade(sessions));
+    }
+}
+
+#[cfg(feature = "daemon")]
+fn sessions_summary() -> Value {
+    let sessions = SESSIONS
+        .try_lock()
+        .ok()
+        .and_then(|watched| watched.as_ref().and_then(Weak::upgrade));
+    match sessions.and_then(|s| s.counts()) {
+        Some([managers, stakeholders, watchtowers]) => serde_json::json!({
+            "managers": managers,
+            "stakeholders": stakeholders,
+            "watchtowers": watchtowers,
+        }),
+        None => Value::Null,
+    }
+}
+
+// Without the daemon, there are no sessions
+#[cfg(not(feature = "daemon"))]
+fn sessions_summary() -> Value {
+    Value::Null
+}
+
+fn bundle(
+    info: &PanicHookInfo,
+    backtrace: &Backtrace,
+    log_ring: &LogRing,
+    config_hash: &sha256::Hash,
+    timestamp: u64,
+) -> Value {
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "<non-string panic payload>".to_string());
+
+    serde_json::json!({
+        "timestamp": timestamp,
+        "version": env!("CARGO_PKG_VERSION"),
+        "thread": std::thread::current().name().unwrap_or("<unnamed>"),
+        "panic": message,
+        "location": info.location().map(|l| l.to_string()),
+        "backtrace": backtrace.to_string(),
+        "sessions": sessions_summary(),
+        "config_sha256": config_hash.to_string(),
+        "recent_logs": log_ring.lines(),
+    })
+}
+
+/// On a panic, write a diagnostics bundle to `crash-<timestamp>.json` in the data directory
+/// before the default handling. `config_hash` identifies the configuration we were running,
+/// without including its secrets.
+pub fn install_panic_handler(data_dir: PathBuf, config_hash: sha256::Hash, log_ring: LogRing) {
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let content = bundle(
+            info,
+            &Backtrace::force_capture(),
+            &log_ring,
+            &config_hash,
+            timestamp,
+        );
+        let bundle_path = data_dir.join(format!("crash-{}.json", timestamp));
+        // Don't log it, we may be panicking from within the logger
+        match fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&bundle_path)
+            .and_then(|mut fd| fd.write_all(content.to_string().as_bytes()))
+        {
+            Ok(()) => eprintln!("Wrote crash diagnostics to '{:?}'", bundle_path),
+            Err(e) => eprintln!("Writing crash diagnostics to '{:?}': {}", bundle_path, e),
+        }
+
+        previous_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogRing, RECENT_LOGS};
+    use log::Log;
+
+    #[test]
+    fn log_ring_keeps_last_lines() {
+        let ring = LogRing::new();
+        for i in 0..RECENT_LOGS + 10 {
+            ring.log(
+                &log::Record::builder()
+                    .args(format_args!("line {}", i))
+                    .build(),
+            );
+        }
+
+        let lines = ring.lines();
+        assert_eq!(lines.len(), RECENT_LOGS);
+        assert_eq!(lines[0], "line 10");
+        assert_eq!(lines[RECENT_LOGS - 1], format!("line {}", RECENT_LOGS + 9));
+    }
+}
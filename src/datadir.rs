@@ -0,0 +1,123 @@
+// Only one coordinator may run with a given data directory: two of them would share (and, on
+// first run, both create) the same Noise keys, and compete for the same listening address and
+// control socket. The first one takes an exclusive lock on the PID file, which it holds until
+// it exits. The operating system releases it even if we crash, so there is no stale lock to
+// clean up.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Why we could not lock the data directory
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process holds the lock on this PID file, along with its id if it wrote it
+    Locked(PathBuf, Option<u32>),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Locked(path, Some(pid)) => write!(
+                f,
+                "Another coordinator (process {}) is running with this data directory, it \
+                 holds '{}'",
+                pid,
+                path.display()
+            ),
+            Self::Locked(path, None) => write!(
+                f,
+                "Another coordinator is running with this data directory, it holds '{}'",
+                path.display()
+            ),
+            Self::Io(path, e) => write!(f, "Locking '{}': {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// The exclusive lock on a data directory, held until dropped
+#[derive(Debug)]
+pub struct DataDirLock(File);
+
+impl DataDirLock {
+    /// Lock the data directory through this PID file, and write our process id to it. Fails
+    /// right away if another process holds it.
+    pub fn acquire(pid_file: &Path) -> Result<DataDirLock, LockError> {
+        let io_error = |e| LockError::Io(pid_file.to_path_buf(), e);
+        // Don't truncate it before holding the lock, it has the id of the process holding it
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(pid_file)
+            .map_err(io_error)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let pid = fs::read_to_string(pid_file)
+                    .ok()
+                    .and_then(|content| content.trim().parse().ok());
+                return Err(LockError::Locked(pid_file.to_path_buf(), pid));
+            }
+            Err(TryLockError::Error(e)) => return Err(io_error(e)),
+        }
+
+        let lock = DataDirLock(file);
+        lock.record_pid().map_err(io_error)?;
+        Ok(lock)
+    }
+
+    /// Write the id of the current process to the PID file, such as once we daemonized
+    pub fn record_pid(&self) -> Result<(), io::Error> {
+        let mut file = &self.0;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataDirLock, LockError};
+
+    use std::process;
+
+    #[test]
+    fn datadir_lock() {
+        let data_dir =
+            std::env::temp_dir().join(format!("revault_coordinatord_lock_{}", process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let pid_file = data_dir.join("revaultd.pid");
+
+        let lock = DataDirLock::acquire(&pid_file).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&pid_file).unwrap(),
+            format!("{}\n", process::id())
+        );
+        // Another instance can't take it, and is told who holds it
+        match DataDirLock::acquire(&pid_file) {
+            Err(LockError::Locked(path, pid)) => {
+                assert_eq!(path, pid_file);
+                assert_eq!(pid, Some(process::id()));
+            }
+            res => panic!("Unexpected {:?}", res),
+        }
+        lock.record_pid().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&pid_file).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        // Until it's released
+        drop(lock);
+        DataDirLock::acquire(&pid_file).unwrap();
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+// What we do when the database goes away, such as while Postgres restarts. A failed connection
+// attempt is retried a few times, backing off exponentially with some jitter so that the
+// connection handlers don't all retry at once. After many failures in a row, we stop trying for
+// a while and fail right away: the clients are told to retry later, rather than each of them
+// waiting for its own attempts to fail. Once this cooldown is over, a single attempt tells
+// whether the database is back.
+//
+// Each server has its own breaker, so that a replica being down doesn't stop us from trying
+// the primary.
+
+use super::{pool::connect, tls::PostgresTls, DbError};
+
+use revault_net::sodiumoxide::randombytes::randombytes_uniform;
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+use tokio_postgres::{config::Host, error::SqlState, Client};
+
+// How long to wait before the first retry, it's doubled for each of the next ones
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+// How long to wait between two attempts at most
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How we retry connecting to the database, and when we stop trying for a while
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrySettings {
+    /// How many times to retry a failed connection attempt
+    pub retries: u32,
+    /// After how many failed attempts in a row to stop trying
+    pub threshold: u32,
+    /// For how long to stop trying
+    pub cooldown: Duration,
+}
+
+impl Default for RetrySettings {
+    fn default() -> RetrySettings {
+        RetrySettings {
+            retries: 3,
+            threshold: 10,
+            cooldown: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    // The failed attempts since the last success
+    failures: u32,
+    // Since when the database is unreachable, if it is
+    down_since: Option<Instant>,
+    // Until when we don't try to connect, if we stopped trying
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    const fn new() -> Breaker {
+        Breaker {
+            failures: 0,
+            down_since: None,
+            open_until: None,
+        }
+    }
+
+    // Whether to try connecting, or for how long to wait before trying again. The first
+    // attempt past the cooldown goes through, the others wait for its outcome.
+    fn check(&mut self, settings: &RetrySettings, now: Instant) -> Result<(), Duration> {
+        match self.open_until {
+            Some(until) if until > now => Err(until - now),
+            Some(_) => {
+                self.open_until = Some(now + settings.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_success(&mut self, server: &str, now: Instant) {
+        if let Some(since) = self.down_since {
+            log::info!(
+                "The database at '{}' is reachable again, after {}s",
+                server,
+                now.duration_since(since).as_secs()
+            );
+        }
+        *self = Breaker::new();
+    }
+
+    fn record_failure(&mut self, server: &str, settings: &RetrySettings, now: Instant) {
+        self.failures += 1;
+        self.down_since.get_or_insert(now);
+        if self.failures >= settings.threshold {
+            if self.open_until.is_none() {
+                log::error!(
+                    "The database at '{}' is unreachable after {} attempts, not trying again \
+                     for {}s",
+                    server,
+                    self.failures,
+                    settings.cooldown.as_secs()
+                );
+            }
+            self.open_until = Some(now + settings.cooldown);
+        }
+    }
+}
+
+// Per server, there are only a couple of them
+static BREAKERS: Mutex<Vec<(String, Breaker)>> = Mutex::new(Vec::new());
+
+fn with_breaker<T>(server: &str, f: impl FnOnce(&mut Breaker) -> T) -> T {
+    let mut breakers = BREAKERS.lock().expect("Breakers lock poisoned");
+    let i = match breakers.iter().position(|(s, _)| s == server) {
+        Some(i) => i,
+        None => {
+            breakers.push((server.to_string(), Breaker::new()));
+            breakers.len() - 1
+        }
+    };
+    f(&mut breakers[i].1)
+}
+
+// The hosts and ports of the server, to tell them apart in our logs
+fn server(config: &tokio_postgres::Config) -> String {
+    let hosts: Vec<String> = config
+        .get_hosts()
+        .iter()
+        .map(|host| match host {
+            Host::Tcp(name) => name.clone(),
+            Host::Unix(path) => path.display().to_string(),
+        })
+        .collect();
+    let ports: Vec<String> = config.get_ports().iter().map(u16::to_string).collect();
+    format!("{}:{}", hosts.join(","), ports.join(","))
+}
+
+// Whether a failure to connect may go away by itself, such as the server being restarted. A
+// rejection of our credentials won't.
+fn is_transient(error: &tokio_postgres::Error) -> bool {
+    match error.code() {
+        None => true,
+        Some(code) => [
+            SqlState::ADMIN_SHUTDOWN,
+            SqlState::CRASH_SHUTDOWN,
+            SqlState::CANNOT_CONNECT_NOW,
+            SqlState::TOO_MANY_CONNECTIONS,
+            SqlState::CONNECTION_EXCEPTION,
+            SqlState::CONNECTION_FAILURE,
+        ]
+        .contains(code),
+    }
+}
+
+// Somewhere between half of this backoff and all of it
+fn jittered(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + Duration::from_millis(randombytes_uniform(half.as_millis() as u32 + 1) as u64)
+}
+
+/// Open a new connection, retrying on transient failures. Fails right away if we stopped
+/// trying for a while.
+pub(super) async fn connect_with_retries(
+    config: &tokio_postgres::Config,
+    tls: &PostgresTls,
+    settings: &RetrySettings,
+) -> Result<Client, DbError> {
+    let server = server(config);
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+
+    loop {
+        with_breaker(&server, |breaker| breaker.check(settings, Instant::now()))
+            .map_err(DbError::Unavailable)?;
+
+        match connect(config, tls).await {
+            Ok(client) => {
+                with_breaker(&server, |breaker| {
+                    breaker.record_success(&server, Instant::now())
+                });
+                return Ok(client);
+            }
+            Err(e) if is_transient(&e) => {
+                with_breaker(&server, |breaker| {
+                    breaker.record_failure(&server, settings, Instant::now())
+                });
+                attempts += 1;
+                if attempts > settings.retries {
+                    return Err(e.into());
+                }
+                let wait = jittered(backoff);
+                log::debug!(
+                    "Connecting to the database: '{}', retrying in {}ms",
+                    e,
+                    wait.as_millis()
+                );
+                sleep(wait).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jittered, server, Breaker, RetrySettings};
+
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn circuit_breaker() {
+        let settings = RetrySettings {
+            retries: 3,
+            threshold: 3,
+            cooldown: Duration::from_secs(5),
+        };
+        let now = Instant::now();
+        let mut breaker = Breaker::new();
+        breaker.check(&settings, now).unwrap();
+        breaker.record_failure("localhost:5432", &settings, now);
+        breaker.record_failure("localhost:5432", &settings, now);
+        breaker.check(&settings, now).unwrap();
+
+        // We stop trying for a while..
+        breaker.record_failure("localhost:5432", &settings, now);
+        assert_eq!(
+            breaker.check(&settings, now + Duration::from_secs(2)),
+            Err(Duration::from_secs(3))
+        );
+        // .. then let a single attempt through
+        let later = now + Duration::from_secs(5);
+        breaker.check(&settings, later).unwrap();
+        assert_eq!(breaker.check(&settings, later), Err(Duration::from_secs(5)));
+        // If it fails we wait again, otherwise we are back to trying
+        breaker.record_failure("localhost:5432", &settings, later);
+        breaker.check(&settings, later).unwrap_err();
+        breaker.record_success("localhost:5432", later + Duration::from_secs(5));
+        breaker.check(&settings, later).unwrap();
+        assert_eq!(breaker.failures, 0);
+
+        let config =
+            tokio_postgres::Config::from_str("postgresql://user@localhost:5433/db").unwrap();
+        assert_eq!(server(&config), "localhost:5433");
+
+        for _ in 0..100 {
+            let wait = jittered(Duration::from_millis(100));
+            assert!(wait >= Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+// Figures for capacity reviews: how much we store, how fast it grows, and how long until we
+// hit the limits we were configured with or the database's own.
+
+use super::{get_connection, queries, Db, DbError};
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+// The period we measure the growth over
+const GROWTH_WINDOW: Duration = Duration::from_secs(30 * 24 * 3600);
+
+// How far ahead we project the growth, about a quarter
+const PROJECTION_DAYS: f64 = 90.0;
+
+/// Where we stand, and where we'll stand in `PROJECTION_DAYS` at the current pace. Only
+/// signatures carry a reception time, so the growth is measured from them alone.
+#[derive(Debug, Serialize)]
+pub struct CapacityReport {
+    pub signatures: i64,
+    pub spend_txs: i64,
+    pub spend_outpoints: i64,
+    pub sig_windows: i64,
+    /// The size of the data we store, as counted against `max_stored_bytes`
+    pub stored_bytes: u64,
+    /// The size of the whole database on disk, including indexes and bloat
+    pub database_bytes: i64,
+    /// The signatures received per day, averaged over the last 30 days
+    pub signatures_per_day: f64,
+    /// The bytes of signatures stored per day, averaged over the last 30 days
+    pub stored_bytes_per_day: f64,
+    pub projection_days: f64,
+    pub projected_signatures: i64,
+    pub projected_stored_bytes: u64,
+    /// The connections to our database, from all clients, and how many it accepts
+    pub db_connections: i64,
+    pub max_db_connections: i64,
+    /// The share of the blocks Postgres read for our database which were in its buffer
+    /// cache, if it read any
+    pub cache_hit_ratio: Option<f64>,
+    pub max_stored_bytes: Option<u64>,
+    /// The days left until we refuse new data at the current pace, if we ever do
+    pub days_to_storage_ceiling: Option<f64>,
+}
+
+// The days left until `current` reaches `ceiling` growing by `per_day`, if it ever does
+fn days_until(current: u64, ceiling: u64, per_day: f64) -> Option<f64> {
+    if current >= ceiling {
+        Some(0.0)
+    } else if per_day > 0.0 {
+        Some((ceiling - current) as f64 / per_day)
+    } else {
+        None
+    }
+}
+
+/// Gather the figures of a capacity report, against this storage ceiling if any
+pub async fn capacity_report(
+    db: &Db,
+    max_stored_bytes: Option<u64>,
+) -> Result<CapacityReport, DbError> {
+    let client = get_connection(db).await?;
+
+    let row = client.query_one(queries::ROW_COUNTS.sql, &[]).await?;
+    let (signatures, spend_txs, spend_outpoints, sig_windows): (i64, i64, i64, i64) =
+        (row.get(0), row.get(1), row.get(2), row.get(3));
+    let stored_bytes: i64 = client
+        .query_one(queries::STORED_BYTES.sql, &[])
+        .await?
+        .get(0);
+    let stored_bytes = stored_bytes as u64;
+    let database_bytes: i64 = client
+        .query_one(queries::DATABASE_SIZE.sql, &[])
+        .await?
+        .get(0);
+
+    let statement = client
+        .prepare_typed(
+            queries::SIGS_RECEIVED_SINCE.sql,
+            queries::SIGS_RECEIVED_SINCE.params,
+        )
+        .await?;
+    let row = client
+        .query_one(&statement, &[&(GROWTH_WINDOW.as_secs() as i64)])
+        .await?;
+    let (recent_sigs, recent_bytes): (i64, i64) = (row.get(0), row.get(1));
+    let window_days = GROWTH_WINDOW.as_secs() as f64 / (24 * 3600) as f64;
+    let signatures_per_day = recent_sigs as f64 / window_days;
+    let stored_bytes_per_day = recent_bytes as f64 / window_days;
+
+    let row = client.query_one(queries::DB_CONNECTIONS.sql, &[]).await?;
+    let (db_connections, max_db_connections): (i64, i64) = (row.get(0), row.get(1));
+    let row = client.query_one(queries::CACHE_HITS.sql, &[]).await?;
+    let (blocks_hit, blocks_read): (i64, i64) = (row.get(0), row.get(1));
+    let cache_hit_ratio = if blocks_hit + blocks_read > 0 {
+        Some(blocks_hit as f64 / (blocks_hit + blocks_read) as f64)
+    } else {
+        None
+    };
+
+    Ok(CapacityReport {
+        signatures,
+        spend_txs,
+        spend_outpoints,
+        sig_windows,
+        stored_bytes,
+        database_bytes,
+        signatures_per_day,
+        stored_bytes_per_day,
+        projection_days: PROJECTION_DAYS,
+        projected_signatures: signatures + (signatures_per_day * PROJECTION_DAYS) as i64,
+        projected_stored_bytes: stored_bytes + (stored_bytes_per_day * PROJECTION_DAYS) as u64,
+        db_connections,
+        max_db_connections,
+        cache_hit_ratio,
+        max_stored_bytes,
+        days_to_storage_ceiling: max_stored_bytes
+            .and_then(|max| days_until(stored_bytes, max, stored_bytes_per_day)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::days_until;
+
+    #[test]
+    fn storage_ceiling_headroom() {
+        assert_eq!(days_until(1_000, 2_000, 100.0), Some(10.0));
+        // Already there
+        assert_eq!(days_until(2_000, 2_000, 100.0), Some(0.0));
+        assert_eq!(days_until(3_000, 2_000, 0.0), Some(0.0));
+        // Never there
+        assert_eq!(days_until(1_000, 2_000, 0.0), None);
+    }
+}
@@ -0,0 +1,168 @@
+// The Spend transactions we push to the watchtowers subscribed to their deposit outpoints. A
+// watchtower waits for them with a request we only answer once there is one it didn't
+// acknowledge yet, or its wait is over. What it acknowledged is stored, so that whatever it
+// missed while disconnected (or didn't acknowledge before disconnecting) is pushed again once it
+// reconnects.
+//
+// The waits are woken as soon as we store a Spend transaction. The ones stored by another
+// process sharing the database are only noticed when we check again, from time to time.
+
+use super::{get_connection, queries, Db, DbError};
+use revault_net::bitcoin::{
+    consensus::encode, hashes::Hash, OutPoint, Transaction as BitcoinTransaction, Txid,
+};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Notify, time::timeout};
+
+// How often a wait checks again for a Spend transaction stored by another process
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+static SPENDS_STORED: Mutex<Option<Arc<Notify>>> = Mutex::new(None);
+
+fn spends_stored() -> Arc<Notify> {
+    SPENDS_STORED
+        .lock()
+        .expect("Spends notifier lock poisoned")
+        .get_or_insert_with(|| Arc::new(Notify::new()))
+        .clone()
+}
+
+/// Wake the watchtowers waiting for Spend transactions, as we just stored one
+pub(super) fn notify_spend_stored() {
+    spends_stored().notify_waiters();
+}
+
+/// A Spend transaction to push to a watchtower
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndeliveredSpend {
+    pub deposit_outpoint: OutPoint,
+    pub transaction: BitcoinTransaction,
+    /// The version of its announcement for this outpoint, to acknowledge it with
+    pub version: i64,
+}
+
+/// Subscribe this watchtower to the Spend transactions announced for these deposit outpoints,
+/// or for all of them if none is given. Subscriptions are never removed.
+pub async fn subscribe_spends(
+    db: &Db,
+    watchtower: &[u8],
+    outpoints: &[OutPoint],
+) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+    let statement = client
+        .prepare_typed(
+            queries::SUBSCRIBE_SPENDS.sql,
+            queries::SUBSCRIBE_SPENDS.params,
+        )
+        .await?;
+
+    if outpoints.is_empty() {
+        client
+            .execute(&statement, &[&watchtower, &None::<&[u8]>, &None::<i32>])
+            .await?;
+    }
+    for outpoint in outpoints {
+        client
+            .execute(
+                &statement,
+                &[
+                    &watchtower,
+                    &outpoint.txid.as_ref(),
+                    &Some(outpoint.vout as i32),
+                ],
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Record the announcements of these versions delivered to this watchtower
+pub async fn ack_spend_deliveries(
+    db: &Db,
+    watchtower: &[u8],
+    versions: &[i64],
+) -> Result<(), DbError> {
+    if versions.is_empty() {
+        return Ok(());
+    }
+
+    let client = get_connection(db).await?;
+    let statement = client
+        .prepare_typed(
+            queries::ACK_SPEND_DELIVERIES.sql,
+            queries::ACK_SPEND_DELIVERIES.params,
+        )
+        .await?;
+    client
+        .execute(&statement, &[&watchtower, &versions])
+        .await?;
+
+    Ok(())
+}
+
+/// Get the Spend transactions this watchtower subscribed to and didn't acknowledge yet, apart
+/// from the announcements of the `acked` versions, oldest announcement first
+pub async fn undelivered_spends(
+    db: &Db,
+    watchtower: &[u8],
+    acked: &[i64],
+    limit: i64,
+) -> Result<Vec<UndeliveredSpend>, DbError> {
+    let client = get_connection(db).await?;
+    let statement = client
+        .prepare_typed(
+            queries::UNDELIVERED_SPENDS.sql,
+            queries::UNDELIVERED_SPENDS.params,
+        )
+        .await?;
+
+    Ok(client
+        .query(&statement, &[&watchtower, &acked, &limit])
+        .await?
+        .iter()
+        .map(|row| UndeliveredSpend {
+            deposit_outpoint: OutPoint {
+                txid: Txid::from_slice(row.get::<_, &[u8]>(0)).expect("We input a txid"),
+                vout: row.get::<_, i32>(1) as u32,
+            },
+            transaction: encode::deserialize(row.get::<_, &[u8]>(2))
+                .expect("Added to DB with serialize()"),
+            version: row.get(3),
+        })
+        .collect())
+}
+
+/// Wait until there is a Spend transaction to push to this watchtower, apart from the
+/// announcements of the `acked` versions, for up to `max_wait`
+pub async fn wait_undelivered_spends(
+    db: &Db,
+    watchtower: &[u8],
+    acked: &[i64],
+    max_wait: Duration,
+) -> Result<(), DbError> {
+    let give_up = Instant::now() + max_wait;
+    let stored = spends_stored();
+
+    loop {
+        // Not to miss one stored while we check
+        let notified = stored.notified();
+        if !undelivered_spends(db, watchtower, acked, 1)
+            .await?
+            .is_empty()
+        {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if now >= give_up {
+            return Ok(());
+        }
+        let _ = timeout((give_up - now).min(RECHECK_INTERVAL), notified).await;
+    }
+}
@@ -0,0 +1,364 @@
+// The changes to our schema, in order. A database records the version of the last one applied
+// to it in the `version` table, and we apply the following ones at startup.
+//
+// The first migration is the schema we shipped before it was versioned. Its statements are
+// idempotent, so that the databases created by any previous release are brought to version 1
+// whatever their state. Later changes must be new migrations, never edits of past ones.
+
+use super::{get_connection, queries, schema::SCHEMA, Db, DbError};
+
+use serde::Serialize;
+use tokio_postgres::Client;
+
+/// A change to the schema, recorded along with its version once applied
+#[derive(Debug, Serialize)]
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    #[serde(skip)]
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        sql: SCHEMA,
+    },
+    Migration {
+        version: 2,
+        description: "Reception time of the Spend transactions, for pruning",
+        // The Spend transactions we already have are considered received now, so that they
+        // are kept for the whole retention period.
+        sql: "\
+ALTER TABLE spend_txs ADD COLUMN received_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+CREATE INDEX signatures_received_at ON signatures (received_at);
+CREATE INDEX spend_txs_received_at ON spend_txs (received_at);
+",
+    },
+    Migration {
+        version: 3,
+        description: "Index of the deposit outpoints by Spend transaction",
+        // Deleting a Spend transaction deletes its outpoints, and we list them along with it
+        sql: "CREATE INDEX spend_outpoints_spend_txid ON spend_outpoints (spend_txid);",
+    },
+    Migration {
+        version: 4,
+        description: "A single signature per key and transaction",
+        // Previous releases accepted several. We keep the first one we received, which is the
+        // one the other participants were served first.
+        sql: "\
+DELETE FROM signatures AS later USING signatures AS earlier
+    WHERE later.txid = earlier.txid AND later.pubkey = earlier.pubkey
+        AND (later.received_at, later.signature) > (earlier.received_at, earlier.signature);
+ALTER TABLE signatures ADD CONSTRAINT signatures_txid_pubkey UNIQUE (txid, pubkey);
+",
+    },
+    Migration {
+        version: 5,
+        description: "Audit trail of the signatures and Spend transactions stored",
+        // The connections processing a participant's message are tagged with its Noise key
+        // (see `audited_config()`). Recording the writes from triggers makes them part of the
+        // same transaction, and covers the imports too, which aren't tagged.
+        sql: "\
+CREATE TABLE audit (
+    id BIGSERIAL PRIMARY KEY,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    peer BYTEA,
+    operation TEXT NOT NULL CHECK (operation IN ('signature', 'spend_tx')),
+    txid BYTEA NOT NULL,
+    pubkey BYTEA,
+    deposit_txid BYTEA,
+    deposit_vout INTEGER
+);
+CREATE INDEX audit_txid ON audit (txid);
+CREATE INDEX audit_deposit_txid ON audit (deposit_txid);
+CREATE INDEX audit_peer ON audit (peer);
+
+CREATE FUNCTION audit_peer() RETURNS BYTEA AS $$
+    SELECT decode(NULLIF(current_setting('coordinatord.peer', true), ''), 'hex')
+$$ LANGUAGE SQL STABLE;
+CREATE FUNCTION audit_signature() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO audit (peer, operation, txid, pubkey)
+        VALUES (audit_peer(), 'signature', NEW.txid, NEW.pubkey);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+CREATE FUNCTION audit_spend_outpoint() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO audit (peer, operation, txid, deposit_txid, deposit_vout)
+        VALUES (audit_peer(), 'spend_tx', NEW.spend_txid, NEW.deposit_txid, NEW.deposit_vout);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+CREATE FUNCTION audit_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'The audit trail is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER signatures_audit AFTER INSERT ON signatures
+    FOR EACH ROW EXECUTE PROCEDURE audit_signature();
+CREATE TRIGGER spend_outpoints_audit AFTER INSERT OR UPDATE ON spend_outpoints
+    FOR EACH ROW EXECUTE PROCEDURE audit_spend_outpoint();
+CREATE TRIGGER audit_append_only BEFORE UPDATE OR DELETE ON audit
+    FOR EACH ROW EXECUTE PROCEDURE audit_append_only();
+CREATE TRIGGER audit_no_truncate BEFORE TRUNCATE ON audit
+    FOR EACH STATEMENT EXECUTE PROCEDURE audit_append_only();
+",
+    },
+    Migration {
+        version: 6,
+        description: "Subscriptions of the watchtowers to the Spend transactions",
+        // A subscription without outpoint is to all of them. A delivery is the last version of
+        // the announcement for an outpoint a watchtower acknowledged.
+        sql: "\
+CREATE TABLE spend_subscriptions (
+    watchtower BYTEA NOT NULL,
+    deposit_txid BYTEA,
+    deposit_vout INTEGER,
+    UNIQUE (watchtower, deposit_txid, deposit_vout)
+);
+CREATE UNIQUE INDEX spend_subscriptions_all ON spend_subscriptions (watchtower)
+    WHERE deposit_txid IS NULL;
+CREATE TABLE spend_deliveries (
+    watchtower BYTEA NOT NULL,
+    deposit_txid BYTEA NOT NULL,
+    deposit_vout INTEGER NOT NULL,
+    version BIGINT NOT NULL,
+    UNIQUE (watchtower, deposit_txid, deposit_vout),
+    FOREIGN KEY (deposit_txid, deposit_vout)
+        REFERENCES spend_outpoints (deposit_txid, deposit_vout) ON DELETE CASCADE
+);
+",
+    },
+    Migration {
+        version: 7,
+        description: "Signatures of the Unvault transactions tagged as such",
+        sql: "\
+ALTER TABLE signatures DROP CONSTRAINT signatures_tx_type_check;
+ALTER TABLE signatures ADD CONSTRAINT signatures_tx_type_check
+    CHECK (tx_type IN ('cancel', 'emergency', 'unvault-emergency', 'unvault', 'spend'));
+",
+    },
+    Migration {
+        version: 8,
+        description: "Replacements of the Spend transactions audited as such",
+        sql: "\
+ALTER TABLE audit DROP CONSTRAINT audit_operation_check;
+ALTER TABLE audit ADD CONSTRAINT audit_operation_check
+    CHECK (operation IN ('signature', 'spend_tx', 'spend_tx_replaced'));
+CREATE OR REPLACE FUNCTION audit_spend_outpoint() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO audit (peer, operation, txid, deposit_txid, deposit_vout)
+        VALUES (audit_peer(),
+                CASE WHEN TG_OP = 'UPDATE' AND OLD.spend_txid <> NEW.spend_txid
+                     THEN 'spend_tx_replaced' ELSE 'spend_tx' END,
+                NEW.spend_txid, NEW.deposit_txid, NEW.deposit_vout);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+",
+    },
+    Migration {
+        version: 9,
+        description: "The Bitcoin network the data is about",
+        // Set by the first coordinator started against the database, there is a single row
+        sql: "\
+CREATE TABLE network (
+    network TEXT NOT NULL
+);
+CREATE UNIQUE INDEX network_single_row ON network ((TRUE));
+",
+    },
+    Migration {
+        version: 10,
+        description: "Deletions audited, and the audit trail chained",
+        // A deletion is recorded along with the Noise key of the participant tagging the
+        // connection, none for the operator's (pruning, `delsig`). Each record's hash covers the
+        // previous one's, so that rewriting the trail (the triggers dropped) shows. The head of
+        // the chain is a row the writers lock until they commit, so that the records are chained
+        // in the order of their ids: a writer waits for the previous one, or fails to serialize
+        // if its transaction is serializable.
+        sql: "\
+ALTER TABLE audit DROP CONSTRAINT audit_operation_check;
+ALTER TABLE audit ADD CONSTRAINT audit_operation_check
+    CHECK (operation IN ('signature', 'spend_tx', 'spend_tx_replaced', 'signature_deleted',
+                         'spend_tx_deleted'));
+ALTER TABLE audit ADD COLUMN prev_hash BYTEA;
+ALTER TABLE audit ADD COLUMN hash BYTEA;
+CREATE TABLE audit_head (
+    hash BYTEA NOT NULL
+);
+CREATE UNIQUE INDEX audit_head_single_row ON audit_head ((TRUE));
+
+CREATE FUNCTION audit_hash(prev_hash BYTEA, id BIGINT, recorded_at TIMESTAMPTZ, peer BYTEA,
+                           operation TEXT, txid BYTEA, pubkey BYTEA, deposit_txid BYTEA,
+                           deposit_vout INTEGER) RETURNS BYTEA AS $$
+    SELECT sha256(prev_hash || convert_to(concat_ws('|', id, EXTRACT(EPOCH FROM recorded_at),
+                                                    encode(peer, 'hex'), operation,
+                                                    encode(txid, 'hex'), encode(pubkey, 'hex'),
+                                                    encode(deposit_txid, 'hex'), deposit_vout),
+                                          'UTF8'))
+$$ LANGUAGE SQL IMMUTABLE;
+CREATE FUNCTION audit_chain() RETURNS TRIGGER AS $$
+BEGIN
+    SELECT hash INTO NEW.prev_hash FROM audit_head FOR UPDATE;
+    NEW.id := nextval(pg_get_serial_sequence('audit', 'id'));
+    NEW.hash := audit_hash(NEW.prev_hash, NEW.id, NEW.recorded_at, NEW.peer, NEW.operation,
+                           NEW.txid, NEW.pubkey, NEW.deposit_txid, NEW.deposit_vout);
+    UPDATE audit_head SET hash = NEW.hash;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+CREATE FUNCTION audit_signature_deleted() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO audit (peer, operation, txid, pubkey)
+        VALUES (audit_peer(), 'signature_deleted', OLD.txid, OLD.pubkey);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+CREATE FUNCTION audit_spend_outpoint_deleted() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO audit (peer, operation, txid, deposit_txid, deposit_vout)
+        VALUES (audit_peer(), 'spend_tx_deleted', OLD.spend_txid, OLD.deposit_txid,
+                OLD.deposit_vout);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+-- The records we already have are chained as they stand
+ALTER TABLE audit DISABLE TRIGGER audit_append_only;
+DO $$
+DECLARE
+    entry audit%ROWTYPE;
+    prev BYTEA := decode(repeat('00', 32), 'hex');
+BEGIN
+    FOR entry IN SELECT * FROM audit ORDER BY id LOOP
+        UPDATE audit SET prev_hash = prev,
+                         hash = audit_hash(prev, entry.id, entry.recorded_at, entry.peer,
+                                           entry.operation, entry.txid, entry.pubkey,
+                                           entry.deposit_txid, entry.deposit_vout)
+            WHERE id = entry.id RETURNING hash INTO prev;
+    END LOOP;
+    INSERT INTO audit_head (hash) VALUES (prev);
+END
+$$;
+ALTER TABLE audit ENABLE TRIGGER audit_append_only;
+ALTER TABLE audit ALTER COLUMN prev_hash SET NOT NULL;
+ALTER TABLE audit ALTER COLUMN hash SET NOT NULL;
+
+CREATE TRIGGER audit_chain BEFORE INSERT ON audit
+    FOR EACH ROW EXECUTE PROCEDURE audit_chain();
+CREATE TRIGGER signatures_audit_deleted AFTER DELETE ON signatures
+    FOR EACH ROW EXECUTE PROCEDURE audit_signature_deleted();
+CREATE TRIGGER spend_outpoints_audit_deleted AFTER DELETE ON spend_outpoints
+    FOR EACH ROW EXECUTE PROCEDURE audit_spend_outpoint_deleted();
+",
+    },
+];
+
+/// The version of the schema once all our migrations are applied
+pub const LATEST_SCHEMA_VERSION: i32 = 10;
+
+/// Where a database stands with regard to our migrations
+#[derive(Debug, Serialize)]
+pub struct MigrationPlan {
+    /// The version of the schema, if it was ever set
+    pub current_version: Option<i32>,
+    pub latest_version: i32,
+    /// The migrations to apply, in order
+    pub pending: Vec<&'static Migration>,
+}
+
+// The migrations following this version. A database migrated by a newer release than ours
+// may not be compatible with us anymore, so we refuse it.
+fn pending_migrations(current_version: Option<i32>) -> Result<Vec<&'static Migration>, DbError> {
+    let current_version = current_version.unwrap_or(0);
+    if current_version > LATEST_SCHEMA_VERSION {
+        return Err(DbError::NewerSchema(current_version));
+    }
+
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| m.version > current_version)
+        .collect())
+}
+
+// The version table is created by the first migration, don't fail on a new database
+async fn current_version(client: &Client) -> Result<Option<i32>, tokio_postgres::Error> {
+    let has_version: bool = client
+        .query_one(queries::VERSION_TABLE_EXISTS.sql, &[])
+        .await?
+        .get(0);
+    if !has_version {
+        return Ok(None);
+    }
+
+    Ok(client
+        .query_one(queries::SCHEMA_VERSION.sql, &[])
+        .await?
+        .get(0))
+}
+
+/// Apply the pending migrations, each in its own transaction. The caller must hold the schema
+/// lock.
+pub(super) async fn migrate(client: &mut Client) -> Result<(), DbError> {
+    for migration in pending_migrations(current_version(client).await?)? {
+        log::info!(
+            "Migrating the database schema to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let db_tx = client.transaction().await?;
+        db_tx.batch_execute(migration.sql).await?;
+        let statement = db_tx
+            .prepare_typed(
+                queries::SET_SCHEMA_VERSION.sql,
+                queries::SET_SCHEMA_VERSION.params,
+            )
+            .await?;
+        db_tx.execute(&statement, &[&migration.version]).await?;
+        db_tx.commit().await?;
+    }
+
+    Ok(())
+}
+
+/// Get the migrations we would apply to this database at startup, without applying them
+pub async fn check_migrations(db: &Db) -> Result<MigrationPlan, DbError> {
+    let client = get_connection(db).await?;
+    let current_version = current_version(&client).await?;
+
+    Ok(MigrationPlan {
+        current_version,
+        latest_version: LATEST_SCHEMA_VERSION,
+        pending: pending_migrations(current_version)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pending_migrations, DbError, LATEST_SCHEMA_VERSION, MIGRATIONS};
+
+    #[test]
+    fn migrations_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+        }
+        assert_eq!(
+            MIGRATIONS.last().map(|m| m.version),
+            Some(LATEST_SCHEMA_VERSION)
+        );
+
+        assert_eq!(pending_migrations(None).unwrap().len(), MIGRATIONS.len());
+        assert!(pending_migrations(Some(LATEST_SCHEMA_VERSION))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            pending_migrations(Some(LATEST_SCHEMA_VERSION + 1)),
+            Err(DbError::NewerSchema(_))
+        ));
+    }
+}
@@ -1,17 +1,79 @@
+mod breaker;
+mod cache;
+mod capacity;
+mod deliveries;
+mod migrations;
+mod pool;
+mod queries;
+mod replica;
 mod schema;
+mod server;
+mod snapshot;
+mod spend_checks;
+mod storage;
+mod tls;
+use crate::{
+    catalog,
+    messages::{BatchedSig, Durability, TxType},
+};
+pub use breaker::RetrySettings;
+use cache::{cache_sigs, cached_sigs, invalidate_sigs, SharedSigsCache};
+pub use capacity::{capacity_report, CapacityReport};
+use deliveries::notify_spend_stored;
+pub use deliveries::{
+    ack_spend_deliveries, subscribe_spends, undelivered_spends, wait_undelivered_spends,
+    UndeliveredSpend,
+};
+pub use migrations::{check_migrations, Migration, MigrationPlan, LATEST_SCHEMA_VERSION};
+pub use pool::PoolSettings;
+use pool::{connect, get_connection, Pool};
+use replica::get_read_connection;
 use revault_net::{
     bitcoin::{
         consensus::encode,
+        hashes::{hex::ToHex, Hash},
         secp256k1::{PublicKey, Signature},
-        OutPoint, Transaction as BitcoinTransaction, Txid,
+        Network, OutPoint, Transaction as BitcoinTransaction, Txid,
     },
     message::server::Sigs,
 };
-use schema::SCHEMA;
+pub use server::{server_version, ServerVersion, MIN_SERVER_VERSION};
+pub use snapshot::{export_snapshot, import_snapshot, Snapshot};
+use spend_checks::check_spend;
+pub use spend_checks::SpendDefect;
+pub use storage::{stored_bytes, StorageGuard};
+pub use tls::{PostgresTls, TlsError};
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio_postgres::{
+    binary_copy::BinaryCopyInWriter, error::SqlState, types::Type, Client, GenericClient,
+    IsolationLevel, Statement,
+};
 
-use std::{collections::BTreeMap, fmt};
+// The read paths are single statements, which are guaranteed to see a consistent snapshot
+// under the default READ COMMITTED isolation level. We only need a stricter level for the
+// transactions updating several rows which concurrent ones may update too.
 
-use tokio_postgres::{types::Type, Client, NoTls};
+// How many times to retry an operation which failed due to a concurrent transaction
+const MAX_TRANSIENT_RETRIES: usize = 5;
+
+// Several instances may be started at once against the same database (for instance during a
+// rollout), and creating the tables concurrently may fail. So we serialize it with an
+// advisory lock, which other instances wait for up to a timeout.
+const SCHEMA_LOCK_ID: i64 = 0x7265_7661_756c_7463; // "revaultc"
+const SCHEMA_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+const SCHEMA_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+// How many transactions we prune the signatures of (or Spend transactions we prune) at once
+const PRUNE_BATCH_SIZE: i64 = 1_000;
 
 #[derive(Debug)]
 pub enum DbError {
@@ -19,6 +81,34 @@ pub enum DbError {
     Postgres(tokio_postgres::Error),
     /// Trying to insert the same data twice
     Duplicate,
+    /// Another instance held the lock on the schema creation for too long
+    SchemaLockTimeout,
+    /// Trying to store a signature for a transaction whose acceptance window is closed
+    SigWindowClosed,
+    /// Trying to replace an announcement which was already replaced, this is the version
+    /// of the current one
+    OutdatedVersion(i64),
+    /// We store as much data as we were configured to
+    StorageFull,
+    /// The database server is older than the oldest one we support
+    UnsupportedServer(ServerVersion),
+    /// The database schema was migrated to this version, which we don't know about
+    NewerSchema(i32),
+    /// The database holds the data of this other Bitcoin network
+    OtherNetwork(String),
+    /// The signature isn't in the low-S form, the only one the network relays
+    NonCanonicalSignature,
+    /// We already store another signature of this key for this transaction
+    ConflictingSignature,
+    /// The Spend transaction isn't one the watchtowers could act upon
+    InvalidSpend(SpendDefect),
+    /// Another Spend transaction is announced for this deposit outpoint, at this version, and
+    /// we weren't asked to replace it
+    ConflictingSpend(OutPoint, i64),
+    /// We have as many connections to the database as allowed, and none was freed in time
+    PoolTimeout(Duration),
+    /// The database was unreachable lately, we don't try connecting to it for this long
+    Unavailable(Duration),
 }
 
 impl fmt::Display for DbError {
@@ -26,90 +116,970 @@ impl fmt::Display for DbError {
         match self {
             Self::Postgres(e) => write!(f, "{}", e),
             Self::Duplicate => write!(f, "Trying to insert a duplicated entry"),
+            Self::SchemaLockTimeout => write!(
+                f,
+                "Timed out waiting for another instance to create the database schema"
+            ),
+            Self::SigWindowClosed => write!(
+                f,
+                "The window for accepting signatures for this transaction is closed"
+            ),
+            Self::OutdatedVersion(v) => write!(
+                f,
+                "Trying to replace an outdated announcement (current version is {})",
+                v
+            ),
+            Self::StorageFull => write!(f, "Refusing to store more data"),
+            Self::UnsupportedServer(v) => write!(
+                f,
+                "The database server runs Postgres {}, but at least Postgres {} is required",
+                v, MIN_SERVER_VERSION
+            ),
+            Self::NewerSchema(v) => write!(
+                f,
+                "The database schema is at version {}, but we only know up to version {}",
+                v, LATEST_SCHEMA_VERSION
+            ),
+            Self::OtherNetwork(network) => write!(
+                f,
+                "The database holds the data of the '{}' network, use another one",
+                network
+            ),
+            Self::NonCanonicalSignature => write!(
+                f,
+                "The signature isn't in its canonical low-S form, it would not be relayed"
+            ),
+            Self::ConflictingSignature => write!(
+                f,
+                "Another signature of this key for this transaction is already stored"
+            ),
+            Self::InvalidSpend(defect) => write!(f, "Invalid Spend transaction: {}", defect),
+            Self::ConflictingSpend(outpoint, version) => write!(
+                f,
+                "Another Spend transaction is announced for '{}' (version {})",
+                outpoint, version
+            ),
+            Self::PoolTimeout(timeout) => write!(
+                f,
+                "No database connection was available after {} seconds",
+                timeout.as_secs()
+            ),
+            Self::Unavailable(retry_after) => write!(
+                f,
+                "The database is unreachable, not trying again for {} ms",
+                retry_after.as_millis()
+            ),
         }
     }
 }
 
 impl std::error::Error for DbError {}
 
+impl DbError {
+    /// The code of our error catalog the participants are told this error with
+    pub fn error_code(&self) -> u32 {
+        match self {
+            Self::Postgres(_) | Self::PoolTimeout(_) | Self::Unavailable(_) => {
+                catalog::DATABASE_UNAVAILABLE
+            }
+            Self::Duplicate => catalog::DUPLICATE,
+            Self::SigWindowClosed => catalog::SIG_WINDOW_CLOSED,
+            Self::OutdatedVersion(_) => catalog::OUTDATED_VERSION,
+            Self::StorageFull => catalog::STORAGE_FULL,
+            Self::NonCanonicalSignature => catalog::NON_CANONICAL_SIGNATURE,
+            Self::ConflictingSignature => catalog::CONFLICTING_SIGNATURE,
+            Self::InvalidSpend(_) => catalog::INVALID_SPEND,
+            Self::ConflictingSpend(..) => catalog::CONFLICTING_SPEND,
+            // Not about processing a message
+            Self::SchemaLockTimeout
+            | Self::UnsupportedServer(_)
+            | Self::NewerSchema(_)
+            | Self::OtherNetwork(_) => catalog::OTHER_PROCESSING_ERROR,
+        }
+    }
+}
+
 impl From<tokio_postgres::Error> for DbError {
     fn from(e: tokio_postgres::Error) -> Self {
         Self::Postgres(e)
     }
 }
 
-async fn establish_connection(
-    config: &tokio_postgres::Config,
-) -> Result<Client, tokio_postgres::Error> {
-    let (client, connection) = config.connect(NoTls).await?;
+/// What a coordinator shares among all its connections to the database: the pool they are
+/// taken from, the certificates to check the server's one against and the cache of the
+/// signatures we serve. Several coordinators in the same process each have their own.
+#[derive(Clone)]
+pub struct DbHandle {
+    pool: Arc<Pool>,
+    tls: PostgresTls,
+    sigs_cache: Arc<SharedSigsCache>,
+}
+
+impl DbHandle {
+    pub fn new(pool_settings: PoolSettings, tls: PostgresTls, sigs_cache: bool) -> DbHandle {
+        DbHandle {
+            pool: Arc::new(Pool::new(pool_settings)),
+            tls,
+            sigs_cache: Arc::new(SharedSigsCache::new(sigs_cache)),
+        }
+    }
+}
+
+impl Default for DbHandle {
+    /// The default pool, the usual web authorities and no cache
+    fn default() -> DbHandle {
+        DbHandle::new(PoolSettings::default(), PostgresTls::default(), false)
+    }
+}
+
+impl fmt::Debug for DbHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DbHandle")
+            .field("pool", self.pool.settings())
+            .field("sigs_cache", &self.sigs_cache.is_enabled())
+            .finish()
+    }
+}
+
+/// The database the queries of a message or a task go to: the Postgres connection parameters
+/// to use, those of its replica if any, along with the handle of the coordinator making them.
+#[derive(Debug, Clone)]
+pub struct Db {
+    config: tokio_postgres::Config,
+    replica: Option<Arc<tokio_postgres::Config>>,
+    handle: DbHandle,
+}
+
+impl Db {
+    pub fn new(config: tokio_postgres::Config, handle: DbHandle) -> Db {
+        Db {
+            config,
+            replica: None,
+            handle,
+        }
+    }
+
+    /// Read the signatures and Spend transactions from the replica with these parameters, or
+    /// from the primary if None
+    pub fn with_replica(mut self, replica: Option<tokio_postgres::Config>) -> Db {
+        self.replica = replica.map(Arc::new);
+        self
+    }
+
+    /// The Postgres connection parameters
+    pub fn config(&self) -> &tokio_postgres::Config {
+        &self.config
+    }
+}
+
+/// The database of a coordinator, shared by all its connection handlers.
+///
+/// Connections are only reused for the same credentials, so swapping the parameters (to
+/// rotate the credentials for instance) only affects the connections made after the swap.
+/// The ones in use are left untouched until they are dropped.
+#[derive(Debug, Clone)]
+pub struct DbConfig(Arc<RwLock<Db>>);
+
+impl DbConfig {
+    pub fn new(db: Db) -> DbConfig {
+        DbConfig(Arc::new(RwLock::new(db)))
+    }
+
+    /// Get the database to use for new connections
+    pub fn get(&self) -> Db {
+        self.0
+            .read()
+            .expect("Database config lock poisoned")
+            .clone()
+    }
+
+    /// The handle the connections are made with, whichever the parameters
+    pub fn handle(&self) -> DbHandle {
+        self.get().handle
+    }
+
+    /// Use these parameters, and read from this replica or from the primary if None, for all
+    /// the new connections from now on
+    pub fn set(&self, config: tokio_postgres::Config, replica: Option<tokio_postgres::Config>) {
+        let mut db = self.0.write().expect("Database config lock poisoned");
+        db.config = config;
+        db.replica = replica.map(Arc::new);
+    }
+}
+
+/// Get the database for connections to be tagged with this trace id, which will be shown
+/// as the `application_name` in `pg_stat_activity`.
+pub fn traced_config(db: &Db, trace_id: &str) -> Db {
+    let mut db = db.clone();
+    db.config
+        .application_name(&format!("revault_coordinatord {}", trace_id));
+    db
+}
+
+/// Get the database for connections to be tagged with the Noise key of the participant whose
+/// message they process, which the audit trail records along with the data it stores.
+pub fn audited_config(db: &Db, peer: &[u8]) -> Db {
+    let mut db = db.clone();
+    let options = match db.config.get_options() {
+        Some(options) => format!("{} -c coordinatord.peer={}", options, peer.to_hex()),
+        None => format!("-c coordinatord.peer={}", peer.to_hex()),
+    };
+    db.config.options(&options);
+    db
+}
+
+/// Get the database for connections whose commits only return once applied by the synchronous
+/// standby(s) of the database, if any is configured.
+pub fn durable_config(db: &Db, durability: Durability) -> Db {
+    let mut db = db.clone();
+    if durability == Durability::Replicated {
+        let options = match db.config.get_options() {
+            Some(options) => format!("{} -c synchronous_commit=remote_apply", options),
+            None => "-c synchronous_commit=remote_apply".to_string(),
+        };
+        db.config.options(&options);
+    }
+    db
+}
+
+/// Get the database for connections whose statements are canceled after this timeout, so that
+/// we don't keep on processing a message past the deadline of its sender. Postgres has a
+/// millisecond resolution, and takes 0 as no timeout.
+pub fn deadline_config(db: &Db, timeout: Duration) -> Db {
+    let mut db = db.clone();
+    let timeout_ms = timeout.as_millis().max(1).min(i32::MAX as u128);
+    let options = match db.config.get_options() {
+        Some(options) => format!("{} -c statement_timeout={}", options, timeout_ms),
+        None => format!("-c statement_timeout={}", timeout_ms),
+    };
+    db.config.options(&options);
+    db
+}
+
+/// Whether processing failed because a statement exceeded the timeout set by
+/// `deadline_config()`
+pub fn is_deadline_exceeded(error: &(dyn std::error::Error + 'static)) -> bool {
+    let postgres_error = match error.downcast_ref::<DbError>() {
+        Some(DbError::Postgres(e)) => Some(e),
+        _ => error.downcast_ref::<tokio_postgres::Error>(),
+    };
+    postgres_error.and_then(|e| e.code()) == Some(&SqlState::QUERY_CANCELED)
+}
+
+/// The durability achieved by a write made with the `durable_config()` for this requested one.
+/// Without synchronous standby, a commit waiting for replication returns as soon as it's
+/// committed locally.
+pub async fn achieved_durability(db: &Db, requested: Durability) -> Result<Durability, DbError> {
+    if requested == Durability::Acked {
+        return Ok(Durability::Acked);
+    }
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            log::error!("Database connection error: {}", e);
+    let client = get_connection(db).await?;
+    let standbys: String = client
+        .query_one(queries::SYNC_STANDBYS.sql, &[])
+        .await?
+        .get(0);
+    if standbys.trim().is_empty() {
+        Ok(Durability::Acked)
+    } else {
+        Ok(Durability::Replicated)
+    }
+}
+
+/// Check we can connect and authenticate to the database with these parameters
+pub async fn check_connection(db: &Db) -> Result<(), tokio_postgres::Error> {
+    connect(&db.config, &db.handle.tls).await.map(|_| ())
+}
+
+pub async fn maybe_create_db(db: &Db) -> Result<(), DbError> {
+    // Not a pooled connection: the lock must be released with it, if we fail midway.
+    let mut client = connect(&db.config, &db.handle.tls).await?;
+
+    // Don't let the schema creation fail with a cryptic syntax error on an old server
+    let version = ServerVersion(
+        client
+            .query_one(queries::SERVER_VERSION.sql, &[])
+            .await?
+            .get(0),
+    );
+    if version < MIN_SERVER_VERSION {
+        return Err(DbError::UnsupportedServer(version));
+    }
+
+    let statement = client
+        .prepare_typed(
+            queries::TRY_SCHEMA_LOCK.sql,
+            queries::TRY_SCHEMA_LOCK.params,
+        )
+        .await?;
+    let start = Instant::now();
+    let mut waiting = false;
+    while !client
+        .query_one(&statement, &[&SCHEMA_LOCK_ID])
+        .await?
+        .get::<_, bool>(0)
+    {
+        if start.elapsed() >= SCHEMA_LOCK_TIMEOUT {
+            return Err(DbError::SchemaLockTimeout);
         }
-    });
+        if !waiting {
+            log::info!("Waiting for another instance to create the database schema");
+            waiting = true;
+        }
+        tokio::time::sleep(SCHEMA_LOCK_RETRY_INTERVAL).await;
+    }
+
+    // If another instance migrated the schema meanwhile, there is nothing left to do.
+    let res = migrations::migrate(&mut client).await;
+    let statement = client
+        .prepare_typed(queries::SCHEMA_UNLOCK.sql, queries::SCHEMA_UNLOCK.params)
+        .await?;
+    client.query_one(&statement, &[&SCHEMA_LOCK_ID]).await?;
+    res?;
 
-    Ok(client)
+    Ok(())
 }
 
-pub async fn maybe_create_db(config: &tokio_postgres::Config) -> Result<(), tokio_postgres::Error> {
-    let client = establish_connection(config).await?;
+/// Prepare all our queries, to check they are consistent with the schema
+#[cfg(test)]
+pub async fn prepare_all_queries(db: &Db) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
 
-    client.batch_execute(SCHEMA).await?;
+    for query in queries::ALL {
+        client.prepare_typed(query.sql, query.params).await?;
+    }
 
     Ok(())
 }
 
-pub async fn store_sig(
-    config: &tokio_postgres::Config,
+/// The differences between the columns declared in the schema module and those of the
+/// database, each as `table.column type`
+#[cfg(test)]
+pub async fn columns_drift(db: &Db) -> Result<Vec<String>, DbError> {
+    let client = get_connection(db).await?;
+
+    let actual: std::collections::BTreeSet<String> = client
+        .query(queries::TABLE_COLUMNS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| {
+            format!(
+                "{}.{} {}",
+                row.get::<_, &str>(0),
+                row.get::<_, &str>(1),
+                row.get::<_, &str>(2)
+            )
+        })
+        .collect();
+    let declared: std::collections::BTreeSet<String> = schema::COLUMNS
+        .iter()
+        .map(|(table, column, type_)| format!("{}.{} {}", table, column, type_.name()))
+        .collect();
+
+    Ok(actual.symmetric_difference(&declared).cloned().collect())
+}
+
+/// Keep a connection busy for this long, to check the statement timeouts
+#[cfg(test)]
+pub async fn pg_sleep(db: &Db, secs: f64) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+    client.execute("SELECT pg_sleep($1)", &[&secs]).await?;
+
+    Ok(())
+}
+
+/// Get the version of the database schema, if it was ever set
+pub async fn fetch_schema_version(db: &Db) -> Result<Option<i32>, DbError> {
+    let client = get_connection(db).await?;
+
+    let row = client.query_one(queries::SCHEMA_VERSION.sql, &[]).await?;
+    Ok(row.get(0))
+}
+
+/// Make sure the database holds the data of this Bitcoin network, recording it if none is set
+/// yet. The data of different networks must be kept in different databases, as nothing in the
+/// signatures or the transactions tells their network apart.
+pub async fn check_network(db: &Db, network: Network) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+
+    let statement = client
+        .prepare_typed(queries::SET_NETWORK.sql, queries::SET_NETWORK.params)
+        .await?;
+    client.execute(&statement, &[&network.to_string()]).await?;
+    let recorded: String = client.query_one(queries::NETWORK.sql, &[]).await?.get(0);
+    if recorded != network.to_string() {
+        return Err(DbError::OtherNetwork(recorded));
+    }
+    Ok(())
+}
+
+// The statements storing a signature, prepared once for a batch of them
+struct SigStatements {
+    of_key: Statement,
+    window_closed: Statement,
+    first_unexpected: Statement,
+    insert: Statement,
+    flag_unexpected: Statement,
+}
+
+async fn prepare_sig_statements<C: GenericClient>(client: &C) -> Result<SigStatements, DbError> {
+    Ok(SigStatements {
+        of_key: client
+            .prepare_typed(queries::SIG_OF_KEY.sql, queries::SIG_OF_KEY.params)
+            .await?,
+        window_closed: client
+            .prepare_typed(
+                queries::SIG_WINDOW_CLOSED.sql,
+                queries::SIG_WINDOW_CLOSED.params,
+            )
+            .await?,
+        first_unexpected: client
+            .prepare_typed(
+                queries::FIRST_SIG_UNEXPECTED.sql,
+                queries::FIRST_SIG_UNEXPECTED.params,
+            )
+            .await?,
+        insert: client
+            .prepare_typed(queries::INSERT_SIG.sql, queries::INSERT_SIG.params)
+            .await?,
+        flag_unexpected: client
+            .prepare_typed(
+                queries::FLAG_UNEXPECTED_TXID.sql,
+                queries::FLAG_UNEXPECTED_TXID.params,
+            )
+            .await?,
+    })
+}
+
+// Whether this key's signature for this transaction is already stored. Another signature than
+// this one is refused: each key signs a transaction once, and we keep the first signature we got
+// so that the other participants are never unsure which one to use.
+async fn sig_stored<C: GenericClient>(
+    client: &C,
+    statements: &SigStatements,
+    txid: Txid,
+    pubkey: PublicKey,
+    sig: &[u8],
+) -> Result<bool, DbError> {
+    match client
+        .query_opt(
+            &statements.of_key,
+            &[&txid.as_ref(), &pubkey.serialize().as_ref()],
+        )
+        .await?
+    {
+        None => Ok(false),
+        Some(row) if row.get::<_, &[u8]>(0) == sig => Ok(true),
+        Some(_) => Err(DbError::ConflictingSignature),
+    }
+}
+
+// We can't check a signature without the transaction it signs, which we are never given.
+// But signers only produce low-S signatures, and a high-S one could never be used: don't
+// store and serve it to the other participants.
+fn is_low_s(signature: &Signature) -> bool {
+    let mut normalized = *signature;
+    normalized.normalize_s();
+    normalized == *signature
+}
+
+// Check and store a signature. Returns false if we already had it.
+async fn insert_sig<C: GenericClient>(
+    client: &C,
+    statements: &SigStatements,
     txid: Txid,
     pubkey: PublicKey,
     signature: Signature,
-) -> Result<(), DbError> {
-    let client = establish_connection(config).await?;
+    tx_type: Option<TxType>,
+) -> Result<bool, DbError> {
+    if !is_low_s(&signature) {
+        return Err(DbError::NonCanonicalSignature);
+    }
     let sig = signature.serialize_der();
 
     // Make sure it's not here already
-    let statement = client
-        .prepare_typed(
-            "SELECT signature FROM signatures WHERE signature = $1",
-            &[Type::BYTEA],
+    if sig_stored(client, statements, txid, pubkey, &sig).await? {
+        return Ok(false);
+    }
+
+    // Make sure we are still accepting signatures for this transaction
+    if let Some(row) = client
+        .query_opt(&statements.window_closed, &[&txid.as_ref()])
+        .await?
+    {
+        if row.get::<_, bool>(0) {
+            return Err(DbError::SigWindowClosed);
+        }
+    }
+
+    // If managers told us which transactions to expect signatures for, the first one for any
+    // other transaction is flagged. It may well be for a mistyped txid.
+    let unexpected: bool = client
+        .query_one(&statements.first_unexpected, &[&txid.as_ref()])
+        .await?
+        .get(0);
+
+    // A signature of this key may have been inserted concurrently since we checked, in which
+    // case we don't insert this one. The same signature for another transaction or key is
+    // caught by the UNIQUE constraint.
+    let inserted = client
+        .execute(
+            &statements.insert,
+            &[
+                &txid.as_ref(),
+                &pubkey.serialize().as_ref(),
+                &sig.as_ref(),
+                &tx_type.map(TxType::as_str),
+            ],
         )
-        .await?;
-    if !client.query(&statement, &[&sig.as_ref()]).await?.is_empty() {
+        .await
+        .map_err(|e| {
+            if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                DbError::Duplicate
+            } else {
+                DbError::Postgres(e)
+            }
+        })?;
+    if inserted == 0 {
+        sig_stored(client, statements, txid, pubkey, &sig).await?;
+        return Ok(false);
+    }
+
+    if unexpected {
+        log::warn!(
+            "First signature for transaction '{}', which no manager registered",
+            txid
+        );
+        client
+            .execute(
+                &statements.flag_unexpected,
+                &[&txid.as_ref(), &pubkey.serialize().as_ref()],
+            )
+            .await?;
+    }
+
+    Ok(true)
+}
+
+pub async fn store_sig(
+    db: &Db,
+    txid: Txid,
+    pubkey: PublicKey,
+    signature: Signature,
+    tx_type: Option<TxType>,
+) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+    let statements = prepare_sig_statements(&*client).await?;
+    if !insert_sig(&*client, &statements, txid, pubkey, signature, tx_type).await? {
         return Err(DbError::Duplicate);
     }
+    invalidate_sigs(db, Some(txid));
+
+    Ok(())
+}
+
+/// Store these signatures, under the same checks as `store_sig()`, in a single transaction:
+/// either they are all stored or none is. Those we already have are skipped. Returns the
+/// number of signatures actually stored.
+pub async fn store_sigs(
+    db: &Db,
+    sigs: &[(Txid, PublicKey, Signature, Option<TxType>)],
+) -> Result<u64, DbError> {
+    let mut client = get_connection(db).await?;
+    let db_tx = client.transaction().await?;
+    let statements = prepare_sig_statements(&db_tx).await?;
+
+    // Skipping the ones we have doesn't abort the transaction, as they are not inserted. One
+    // inserted concurrently still fails the whole batch.
+    let mut stored = 0;
+    for (txid, pubkey, signature, tx_type) in sigs.iter() {
+        if insert_sig(&db_tx, &statements, *txid, *pubkey, *signature, *tx_type).await? {
+            stored += 1;
+        }
+    }
+    db_tx.commit().await?;
+    if stored > 0 {
+        for (txid, _, _, _) in sigs.iter() {
+            invalidate_sigs(db, Some(*txid));
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Register transactions managers expect signatures for. Once any is registered, the first
+/// signature for a transaction which isn't gets flagged.
+pub async fn register_txids(db: &Db, txids: &[Txid]) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+
+    let statement = client
+        .prepare_typed(queries::REGISTER_TXID.sql, queries::REGISTER_TXID.params)
+        .await?;
+    for txid in txids {
+        client.execute(&statement, &[&txid.as_ref()]).await?;
+    }
+
+    Ok(())
+}
+
+/// A transaction we got signatures for, although managers registered other ones
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnexpectedTxid {
+    pub txid: Txid,
+    /// The key of its first signature
+    pub first_pubkey: PublicKey,
+    /// As a UNIX timestamp
+    pub first_seen_at: i64,
+}
+
+/// Get the transactions whose first signature was flagged, oldest first
+pub async fn fetch_unexpected_txids(db: &Db) -> Result<Vec<UnexpectedTxid>, DbError> {
+    let client = get_connection(db).await?;
+
+    Ok(client
+        .query(queries::UNEXPECTED_TXIDS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| UnexpectedTxid {
+            txid: Txid::from_slice(row.get::<_, &[u8]>(0)).expect("We input a txid"),
+            first_pubkey: PublicKey::from_slice(row.get::<_, &[u8]>(1))
+                .expect("We input a compressed pubkey"),
+            first_seen_at: row.get(2),
+        })
+        .collect())
+}
+
+/// Get the last message counter we persisted for each participant, by Noise key
+pub async fn fetch_peer_counters(db: &Db) -> Result<Vec<([u8; 32], i64)>, DbError> {
+    let client = get_connection(db).await?;
+
+    Ok(client
+        .query(queries::PEER_COUNTERS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| {
+            (
+                row.get::<_, &[u8]>(0)
+                    .try_into()
+                    .expect("We input a Noise key"),
+                row.get(1),
+            )
+        })
+        .collect())
+}
+
+/// Persist these message counters, unless we already have greater ones
+pub async fn store_peer_counters(db: &Db, counters: &[([u8; 32], i64)]) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
 
     let statement = client
         .prepare_typed(
-            "INSERT INTO signatures (txid, pubkey, signature) VALUES ($1, $2, $3)",
-            &[Type::BYTEA, Type::BYTEA, Type::BYTEA],
+            queries::STORE_PEER_COUNTER.sql,
+            queries::STORE_PEER_COUNTER.params,
         )
         .await?;
+    for (pubkey, counter) in counters {
+        client.execute(&statement, &[&&pubkey[..], counter]).await?;
+    }
+
+    Ok(())
+}
+
+/// Only accept signatures for this transaction for `window` after its first sight, that is its
+/// first signature or this call. Calling it again changes the window but not its start.
+pub async fn set_sig_window(db: &Db, txid: Txid, window: Duration) -> Result<(), DbError> {
+    let client = get_connection(db).await?;
+
+    let statement = client
+        .prepare_typed(queries::SET_SIG_WINDOW.sql, queries::SET_SIG_WINDOW.params)
+        .await?;
+    // Postgres intervals can't be arbitrarily large, and 68 years is as good as forever here.
+    let window_secs = window.as_secs().min(i32::MAX as u64) as i64;
     client
+        .execute(&statement, &[&txid.as_ref(), &window_secs])
+        .await?;
+
+    Ok(())
+}
+
+/// What a bulk import of signatures did
+#[derive(Debug)]
+pub struct SigsImport {
+    /// How many signatures were stored
+    pub stored: u64,
+    /// How many were already stored, or repeated in the import
+    pub already_stored: u64,
+    /// The signatures which were refused, and why
+    pub rejected: Vec<(Txid, PublicKey, DbError)>,
+}
+
+/// Store a large number of signatures at once, under the same checks as `store_sig()`. The
+/// signatures failing them are returned along with the reason, rather than failing the whole
+/// import. The acceptance windows are not enforced, as this is meant for backfills by the
+/// operator. The import is refused altogether if it would take us over `max_stored_bytes`.
+///
+/// Rather than inserting them one by one, we COPY them to a temporary table first, check them
+/// against the stored ones and insert them all from there in a single statement. The
+/// signatures table is locked meanwhile, so that none is stored concurrently under our feet.
+pub async fn bulk_store_sigs(
+    db: &Db,
+    sigs: &[BatchedSig],
+    max_stored_bytes: Option<u64>,
+) -> Result<SigsImport, DbError> {
+    let mut already_stored = 0;
+    let mut rejected = Vec::new();
+
+    // The checks which don't need the database, including against the other signatures of the
+    // import itself
+    let mut of_key: HashMap<(Txid, [u8; 33]), Vec<u8>> = HashMap::new();
+    let mut signatures = HashSet::new();
+    let mut rows = Vec::with_capacity(sigs.len());
+    for BatchedSig { sig, tx_type } in sigs {
+        if !is_low_s(&sig.signature) {
+            rejected.push((sig.id, sig.pubkey, DbError::NonCanonicalSignature));
+            continue;
+        }
+        let signature = sig.signature.serialize_der().to_vec();
+        match of_key.get(&(sig.id, sig.pubkey.serialize())) {
+            Some(first) if *first == signature => already_stored += 1,
+            Some(_) => rejected.push((sig.id, sig.pubkey, DbError::ConflictingSignature)),
+            None if !signatures.insert(signature.clone()) => {
+                rejected.push((sig.id, sig.pubkey, DbError::Duplicate))
+            }
+            None => {
+                of_key.insert((sig.id, sig.pubkey.serialize()), signature.clone());
+                rows.push((sig.id, sig.pubkey, signature, *tx_type));
+            }
+        }
+    }
+
+    let mut client = get_connection(db).await?;
+    let db_tx = client.transaction().await?;
+
+    // These are tied to the temporary table, so they're not part of the queries module.
+    db_tx
+        .batch_execute(
+            "LOCK TABLE signatures IN SHARE ROW EXCLUSIVE MODE; \
+             CREATE TEMPORARY TABLE signatures_import (\
+                txid BYTEA NOT NULL, \
+                pubkey BYTEA NOT NULL, \
+                signature BYTEA NOT NULL, \
+                tx_type TEXT\
+             ) ON COMMIT DROP",
+        )
+        .await?;
+    let sink = db_tx
+        .copy_in("COPY signatures_import (txid, pubkey, signature, tx_type) FROM STDIN BINARY")
+        .await?;
+    let writer =
+        BinaryCopyInWriter::new(sink, &[Type::BYTEA, Type::BYTEA, Type::BYTEA, Type::TEXT]);
+    tokio::pin!(writer);
+    for (txid, pubkey, signature, tx_type) in &rows {
+        writer
+            .as_mut()
+            .write(&[
+                &txid.as_ref(),
+                &pubkey.serialize().as_ref(),
+                &signature.as_slice(),
+                &tx_type.map(TxType::as_str),
+            ])
+            .await?;
+    }
+    writer.finish().await?;
+
+    // The signatures which are already stored, and those conflicting with the stored ones. A
+    // signature of the same key for the same transaction takes precedence over the same
+    // signature for another one.
+    for row in db_tx
+        .query(
+            "SELECT DISTINCT ON (i.txid, i.pubkey) i.txid, i.pubkey, \
+                    s.txid = i.txid AND s.pubkey = i.pubkey AS same_key, \
+                    s.signature = i.signature \
+             FROM signatures_import AS i JOIN signatures AS s \
+               ON (s.txid = i.txid AND s.pubkey = i.pubkey) OR s.signature = i.signature \
+             ORDER BY i.txid, i.pubkey, same_key DESC",
+            &[],
+        )
+        .await?
+    {
+        let txid = Txid::from_slice(row.get::<_, &[u8]>(0)).expect("We input a txid");
+        let pubkey =
+            PublicKey::from_slice(row.get::<_, &[u8]>(1)).expect("We input a compressed pubkey");
+        match (row.get::<_, bool>(2), row.get::<_, bool>(3)) {
+            (true, true) => already_stored += 1,
+            (true, false) => rejected.push((txid, pubkey, DbError::ConflictingSignature)),
+            (false, _) => rejected.push((txid, pubkey, DbError::Duplicate)),
+        }
+    }
+    db_tx
         .execute(
-            &statement,
-            &[&txid.as_ref(), &pubkey.serialize().as_ref(), &sig.as_ref()],
+            "DELETE FROM signatures_import AS i USING signatures AS s \
+             WHERE (s.txid = i.txid AND s.pubkey = i.pubkey) OR s.signature = i.signature",
+            &[],
         )
         .await?;
 
-    Ok(())
+    if let Some(max_bytes) = max_stored_bytes {
+        let stored_bytes: i64 = db_tx
+            .query_one(queries::STORED_BYTES.sql, &[])
+            .await?
+            .get(0);
+        let import_bytes: i64 = db_tx
+            .query_one(
+                "SELECT COALESCE(SUM(octet_length(txid) + octet_length(pubkey) \
+                                     + octet_length(signature)), 0) \
+                 FROM signatures_import",
+                &[],
+            )
+            .await?
+            .get(0);
+        if (stored_bytes + import_bytes) as u64 > max_bytes {
+            return Err(DbError::StorageFull);
+        }
+    }
+
+    // If managers told us which transactions to expect signatures for, the first one for any
+    // other transaction is flagged, as for a single signature.
+    for row in db_tx
+        .query(
+            "INSERT INTO unexpected_txids (txid, first_pubkey) \
+             SELECT DISTINCT ON (i.txid) i.txid, i.pubkey FROM signatures_import AS i \
+             WHERE EXISTS (SELECT 1 FROM expected_txids) \
+               AND NOT EXISTS (SELECT 1 FROM expected_txids AS e WHERE e.txid = i.txid) \
+               AND NOT EXISTS (SELECT 1 FROM signatures AS s WHERE s.txid = i.txid) \
+             ON CONFLICT DO NOTHING \
+             RETURNING txid",
+            &[],
+        )
+        .await?
+    {
+        log::warn!(
+            "First signature for transaction '{}', which no manager registered",
+            Txid::from_slice(row.get::<_, &[u8]>(0)).expect("We input a txid")
+        );
+    }
+
+    let stored = db_tx
+        .execute(
+            "INSERT INTO signatures (txid, pubkey, signature, tx_type) \
+             SELECT txid, pubkey, signature, tx_type FROM signatures_import",
+            &[],
+        )
+        .await?;
+    db_tx.commit().await?;
+    invalidate_sigs(db, None);
+
+    Ok(SigsImport {
+        stored,
+        already_stored,
+        rejected,
+    })
 }
 
-pub async fn fetch_sigs(
-    config: &tokio_postgres::Config,
-    txid: Txid,
-) -> Result<Sigs, tokio_postgres::Error> {
-    let client = establish_connection(config).await?;
-    let mut signatures: BTreeMap<PublicKey, Signature> = BTreeMap::new();
+/// What was deleted when pruning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Pruned {
+    pub signatures: u64,
+    pub spend_txs: u64,
+}
+
+/// Delete the signatures and Spend transactions first received before this UNIX timestamp,
+/// along with the deposit outpoints of these Spends. The signatures of a transaction are only
+/// deleted once they all are older, and all at once.
+///
+/// They are deleted in batches of `PRUNE_BATCH_SIZE` transactions, each committed on its own,
+/// so that a large prune doesn't hold its locks (nor a connection) for long. If interrupted,
+/// what was deleted by the committed batches stays deleted.
+pub async fn prune_before(db: &Db, timestamp: i64) -> Result<Pruned, DbError> {
+    let mut pruned = Pruned {
+        signatures: 0,
+        spend_txs: 0,
+    };
+
+    loop {
+        let deleted = {
+            let client = get_connection(db).await?;
+            let statement = client
+                .prepare_typed(queries::PRUNE_SIGS.sql, queries::PRUNE_SIGS.params)
+                .await?;
+            client
+                .execute(&statement, &[&timestamp, &PRUNE_BATCH_SIZE])
+                .await?
+        };
+        if deleted == 0 {
+            break;
+        }
+        invalidate_sigs(db, None);
+        pruned.signatures += deleted;
+        log::debug!("Pruned {} signature(s) so far", pruned.signatures);
+        tokio::task::yield_now().await;
+    }
+
+    loop {
+        let deleted = {
+            let client = get_connection(db).await?;
+            let statement = client
+                .prepare_typed(
+                    queries::PRUNE_SPEND_TXS.sql,
+                    queries::PRUNE_SPEND_TXS.params,
+                )
+                .await?;
+            client
+                .execute(&statement, &[&timestamp, &PRUNE_BATCH_SIZE])
+                .await?
+        };
+        if deleted == 0 {
+            break;
+        }
+        pruned.spend_txs += deleted;
+        log::debug!("Pruned {} Spend transaction(s) so far", pruned.spend_txs);
+        tokio::task::yield_now().await;
+    }
+
+    Ok(pruned)
+}
+
+/// What `prune_before` would delete at this UNIX timestamp, without deleting anything
+pub async fn prunable_before(db: &Db, timestamp: i64) -> Result<Pruned, DbError> {
+    let client = get_connection(db).await?;
 
+    let statement = client
+        .prepare_typed(queries::PRUNABLE_SIGS.sql, queries::PRUNABLE_SIGS.params)
+        .await?;
+    let signatures: i64 = client.query_one(&statement, &[&timestamp]).await?.get(0);
     let statement = client
         .prepare_typed(
-            "SELECT pubkey, signature FROM signatures WHERE txid = $1",
-            &[Type::BYTEA],
+            queries::PRUNABLE_SPEND_TXS.sql,
+            queries::PRUNABLE_SPEND_TXS.params,
         )
         .await?;
-    for row in client.query(&statement, &[&txid.as_ref()]).await? {
+    let spend_txs: i64 = client.query_one(&statement, &[&timestamp]).await?.get(0);
+
+    Ok(Pruned {
+        signatures: signatures as u64,
+        spend_txs: spend_txs as u64,
+    })
+}
+
+/// Get the signatures for this transaction, only those tagged with this type if one is given.
+/// They are served from the cache if it's enabled, or read from the replica if there is one.
+pub async fn fetch_sigs(db: &Db, txid: Txid, tx_type: Option<TxType>) -> Result<Sigs, DbError> {
+    let generation = match cached_sigs(db, txid, tx_type) {
+        Some(Ok(signatures)) => return Ok(Sigs { signatures }),
+        Some(Err(generation)) => Some(generation),
+        None => None,
+    };
+
+    let (client, from_replica) = get_read_connection(db).await?;
+    let mut signatures: BTreeMap<PublicKey, Signature> = BTreeMap::new();
+
+    let statement = client
+        .prepare_typed(queries::FETCH_SIGS.sql, queries::FETCH_SIGS.params)
+        .await?;
+    for row in client
+        .query(&statement, &[&txid.as_ref(), &tx_type.map(TxType::as_str)])
+        .await?
+    {
         let pubkey: &[u8] = row.get(0);
         let pubkey = PublicKey::from_slice(&pubkey).expect("We input a compressed pubkey");
         let sig: Vec<u8> = row.get(1);
@@ -119,44 +1089,155 @@ pub async fn fetch_sigs(
             Signature::from_der(&sig).expect("We input to_der()"),
         );
     }
+    // The replica may not have the signatures stored since the cache was invalidated, which
+    // would then be missing until the next invalidation
+    if let (Some(generation), false) = (generation, from_replica) {
+        cache_sigs(db, txid, tx_type, &signatures, generation);
+    }
 
     Ok(Sigs { signatures })
 }
 
-pub async fn store_spend_tx(
-    config: &tokio_postgres::Config,
-    outpoints: &Vec<OutPoint>,
-    transaction: BitcoinTransaction,
-) -> Result<(), tokio_postgres::Error> {
-    let mut client = establish_connection(config).await?;
-    let bitcoin_txid = encode::serialize(&transaction.txid());
-    let bitcoin_tx = encode::serialize(&transaction);
+/// How far the stakeholders are into signing a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SigProgress {
+    /// The keys which signed it, with a signature tagged for one of the transactions the
+    /// stakeholders pre-sign
+    pub signed: u32,
+    /// The keys which signed it without telling which type of transaction it is
+    pub untagged: u32,
+    /// The stakeholders expected to sign it
+    pub expected: u32,
+}
 
-    // In a single transaction,
-    let db_tx = client.transaction().await?;
+/// How many keys signed this transaction, along with the number of `stakeholders` expected to.
+/// We can't tell whether the stakeholders are the ones who signed: we aren't told which of its
+/// keys a stakeholder signs with, so any key signing counts. Neither can we tell what an
+/// untagged signature is for, those are counted apart. Read from the replica if there is one.
+pub async fn sig_progress(db: &Db, txid: Txid, stakeholders: u32) -> Result<SigProgress, DbError> {
+    let (client, _) = get_read_connection(db).await?;
+
+    let statement = client
+        .prepare_typed(queries::SIG_PROGRESS.sql, queries::SIG_PROGRESS.params)
+        .await?;
+    let row = client.query_one(&statement, &[&txid.as_ref()]).await?;
+
+    Ok(SigProgress {
+        signed: row.get::<_, i64>(0) as u32,
+        untagged: row.get::<_, i64>(1) as u32,
+        expected: stakeholders,
+    })
+}
+
+/// Delete the signature of this key for this transaction. Returns whether there was one.
+pub async fn delete_sig(db: &Db, txid: Txid, pubkey: PublicKey) -> Result<bool, DbError> {
+    let client = get_connection(db).await?;
+
+    let statement = client
+        .prepare_typed(queries::DELETE_SIG.sql, queries::DELETE_SIG.params)
+        .await?;
+    let deleted = client
+        .execute(&statement, &[&txid.as_ref(), &pubkey.serialize().as_ref()])
+        .await?;
+    if deleted > 0 {
+        invalidate_sigs(db, Some(txid));
+    }
+
+    Ok(deleted > 0)
+}
+
+/// Whether we store a signature of this key for this transaction, that is whether `delete_sig`
+/// would delete one
+pub async fn has_sig(db: &Db, txid: Txid, pubkey: PublicKey) -> Result<bool, DbError> {
+    let client = get_connection(db).await?;
+
+    Ok(client
+        .query_opt(
+            queries::SIG_OF_KEY.sql,
+            &[&txid.as_ref(), &pubkey.serialize().as_ref()],
+        )
+        .await?
+        .is_some())
+}
+
+// Insert the Spend transaction along with all the vault outpoints it refers to, replacing
+// any previous mapping of these outpoints, in a single database transaction. Returns the
+// version of the new announcement.
+//
+// Concurrent replacements of the mapping for the same outpoints must not interleave, so we
+// use the SERIALIZABLE isolation level. We may therefore fail due to a concurrent
+// transaction, in which case the caller should just retry.
+async fn try_store_spend_tx(
+    client: &mut Client,
+    outpoints: &[OutPoint],
+    bitcoin_txid: &[u8],
+    bitcoin_tx: &[u8],
+    expected_version: Option<i64>,
+    replace: bool,
+) -> Result<(i64, Vec<OutPoint>), DbError> {
+    let db_tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::Serializable)
+        .start()
+        .await?;
 
-    // insert the Spend transaction,
+    // If the manager told us which announcement it is replacing, make sure another one
+    // didn't replace it in the meantime. Otherwise, it must ask explicitly to replace the
+    // announcement of another Spend. Announcing the same one again is fine.
     let statement = db_tx
         .prepare_typed(
-            "INSERT INTO spend_txs (txid, transaction) VALUES ($1, $2) \
-             ON CONFLICT DO NOTHING", // FIXME: we should make the error explicit
-            &[Type::BYTEA, Type::BYTEA],
+            queries::SPEND_OUTPOINT_ANNOUNCEMENT.sql,
+            queries::SPEND_OUTPOINT_ANNOUNCEMENT.params,
+        )
+        .await?;
+    let mut replaced = Vec::new();
+    for outpoint in outpoints.iter() {
+        let current = db_tx
+            .query_opt(
+                &statement,
+                &[&outpoint.txid.as_ref(), &(outpoint.vout as i32)],
+            )
+            .await?
+            .map(|row| (row.get::<_, i64>(0), row.get::<_, Vec<u8>>(1)));
+        let (version, spend_txid) = match current {
+            Some(current) => current,
+            None => continue,
+        };
+        match expected_version {
+            Some(expected_version) if version != expected_version => {
+                return Err(DbError::OutdatedVersion(version))
+            }
+            None if !replace && spend_txid != bitcoin_txid => {
+                return Err(DbError::ConflictingSpend(*outpoint, version))
+            }
+            _ => {}
+        }
+        if spend_txid != bitcoin_txid {
+            replaced.push(*outpoint);
+        }
+    }
+
+    let statement = db_tx
+        .prepare_typed(
+            queries::INSERT_SPEND_TX.sql,
+            queries::INSERT_SPEND_TX.params,
         )
         .await?;
     db_tx
         .execute(&statement, &[&bitcoin_txid, &bitcoin_tx])
         .await?;
 
-    // as well as all vault outpoints it refers to
+    let version: i64 = db_tx
+        .query_one(queries::NEXT_SPEND_VERSION.sql, &[])
+        .await?
+        .get(0);
+    let statement = db_tx
+        .prepare_typed(
+            queries::UPSERT_SPEND_OUTPOINT.sql,
+            queries::UPSERT_SPEND_OUTPOINT.params,
+        )
+        .await?;
     for outpoint in outpoints.iter() {
-        let statement = db_tx.prepare_typed(
-        "INSERT INTO spend_outpoints (deposit_txid, deposit_vout, spend_txid) VALUES ($1, $2, $3) \
-         ON CONFLICT (deposit_txid, deposit_vout) DO UPDATE \
-         SET deposit_txid = EXCLUDED.deposit_txid, \
-             deposit_vout = EXCLUDED.deposit_vout, \
-             spend_txid = EXCLUDED.spend_txid",
-        &[Type::BYTEA, Type::INT4, Type::BYTEA]
-        ).await?;
         db_tx
             .execute(
                 &statement,
@@ -164,36 +1245,232 @@ pub async fn store_spend_tx(
                     &outpoint.txid.as_ref(),
                     &(outpoint.vout as i32),
                     &bitcoin_txid,
+                    &version,
                 ],
             )
             .await?;
     }
 
-    db_tx.commit().await
+    db_tx.commit().await?;
+    Ok((version, replaced))
 }
 
+// Whether this error is due to a concurrent transaction, and the operation may be retried
+fn is_transient(error: &DbError) -> bool {
+    match error {
+        DbError::Postgres(e) => {
+            e.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE)
+                || e.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED)
+        }
+        _ => false,
+    }
+}
+
+/// Store the Spend transaction for these deposit outpoints, and return the version of this
+/// announcement. If `expected_version` is set, refuse to replace the announcement for any
+/// of the outpoints if its version is not the expected one. Otherwise, refuse to replace the
+/// announcement of another Spend for any of them unless `replace` is set. A Spend which isn't
+/// well-formed and signed is refused.
+pub async fn store_spend_tx(
+    db: &Db,
+    outpoints: &[OutPoint],
+    transaction: BitcoinTransaction,
+    expected_version: Option<i64>,
+    replace: bool,
+) -> Result<i64, DbError> {
+    check_spend(outpoints, &transaction).map_err(DbError::InvalidSpend)?;
+    let mut client = get_connection(db).await?;
+    let bitcoin_txid = encode::serialize(&transaction.txid());
+    let bitcoin_tx = encode::serialize(&transaction);
+
+    let mut retries = 0;
+    loop {
+        match try_store_spend_tx(
+            &mut client,
+            outpoints,
+            &bitcoin_txid,
+            &bitcoin_tx,
+            expected_version,
+            replace,
+        )
+        .await
+        {
+            Err(e) if is_transient(&e) && retries < MAX_TRANSIENT_RETRIES => {
+                retries += 1;
+                log::debug!(
+                    "Conflict with a concurrent transaction storing Spend '{}', retrying ({})",
+                    transaction.txid(),
+                    e
+                );
+            }
+            Ok((version, replaced)) => {
+                for outpoint in replaced {
+                    log::info!(
+                        "Replaced the Spend announced for '{}' with '{}'",
+                        outpoint,
+                        transaction.txid()
+                    );
+                }
+                notify_spend_stored();
+                return Ok(version);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Get the Spend transaction for this deposit outpoint, along with the version of its
+/// announcement. It's read from the replica if there is one.
 pub async fn fetch_spend_tx(
-    config: &tokio_postgres::Config,
+    db: &Db,
     outpoint: OutPoint,
-) -> Result<Option<BitcoinTransaction>, tokio_postgres::Error> {
-    let client = establish_connection(config).await?;
+) -> Result<Option<(BitcoinTransaction, i64)>, DbError> {
+    let (client, _) = get_read_connection(db).await?;
 
     let statement = client
-        .prepare_typed(
-            "SELECT transaction FROM spend_txs as txs \
-             INNER JOIN spend_outpoints as ops ON txs.txid = ops.spend_txid \
-             WHERE ops.deposit_txid = $1 AND ops.deposit_vout = $2",
-            &[Type::BYTEA, Type::INT4],
-        )
+        .prepare_typed(queries::FETCH_SPEND_TX.sql, queries::FETCH_SPEND_TX.params)
         .await?;
     let spend_tx = client
-        .query(
+        .query_opt(
             &statement,
             &[&outpoint.txid.as_ref(), &(outpoint.vout as i32)],
         )
         .await?
-        .get(0)
-        .map(|row| row.get::<_, Vec<u8>>(0));
+        .map(|row| (row.get::<_, Vec<u8>>(0), row.get::<_, i64>(1)));
+
+    Ok(spend_tx.map(|(tx, version)| {
+        (
+            encode::deserialize(&tx).expect("Added to DB with serialize()"),
+            version,
+        )
+    }))
+}
+
+/// A Spend transaction we store, and the deposit outpoints it is currently announced for
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StoredSpendTx {
+    pub txid: Txid,
+    /// As a UNIX timestamp
+    pub received_at: i64,
+    pub deposit_outpoints: Vec<OutPoint>,
+}
+
+/// Get all the Spend transactions we store, oldest first
+pub async fn list_spend_txs(db: &Db) -> Result<Vec<StoredSpendTx>, DbError> {
+    let client = get_connection(db).await?;
+    let mut spend_txs: Vec<StoredSpendTx> = Vec::new();
+
+    // There is a row per outpoint, or a single one without outpoint
+    for row in client.query(queries::LIST_SPEND_TXS.sql, &[]).await? {
+        let txid = Txid::from_slice(row.get::<_, &[u8]>(0)).expect("We input a txid");
+        let outpoint = row.get::<_, Option<&[u8]>>(2).map(|deposit_txid| OutPoint {
+            txid: Txid::from_slice(deposit_txid).expect("We input a txid"),
+            vout: row.get::<_, i32>(3) as u32,
+        });
+
+        match spend_txs.last_mut() {
+            Some(spend_tx) if spend_tx.txid == txid => spend_tx.deposit_outpoints.extend(outpoint),
+            _ => spend_txs.push(StoredSpendTx {
+                txid,
+                received_at: row.get(1),
+                deposit_outpoints: outpoint.into_iter().collect(),
+            }),
+        }
+    }
+
+    Ok(spend_txs)
+}
+
+/// A signature or a Spend transaction stored or deleted, as recorded in the audit trail
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEntry {
+    /// As a UNIX timestamp
+    pub recorded_at: i64,
+    /// The Noise key of the participant which sent it, none if it was imported
+    pub peer: Option<String>,
+    /// Either `signature`, `spend_tx`, `spend_tx_replaced` for the announcement of a Spend
+    /// replacing another one, or `signature_deleted` and `spend_tx_deleted` for the deletions
+    pub operation: String,
+    /// The transaction signed, or the Spend transaction
+    pub txid: Txid,
+    /// The key of a signature
+    pub pubkey: Option<PublicKey>,
+    /// The deposit outpoint a Spend transaction was announced for
+    pub deposit_outpoint: Option<OutPoint>,
+    /// Of this record along with the previous one's, in hex
+    pub hash: String,
+}
+
+/// Get the writes recorded in the audit trail, oldest first. If given, only those about this
+/// transaction (be it signed, a Spend or the deposit one of a Spend) and those of this
+/// participant.
+pub async fn fetch_audit_trail(
+    db: &Db,
+    txid: Option<Txid>,
+    peer: Option<&[u8]>,
+) -> Result<Vec<AuditEntry>, DbError> {
+    let client = get_connection(db).await?;
+    let txid: Option<&[u8]> = txid.as_ref().map(|txid| txid.as_ref());
+
+    Ok(client
+        .query(queries::AUDIT_TRAIL.sql, &[&txid, &peer])
+        .await?
+        .iter()
+        .map(|row| AuditEntry {
+            recorded_at: row.get(0),
+            peer: row.get::<_, Option<&[u8]>>(1).map(|peer| peer.to_hex()),
+            operation: row.get(2),
+            txid: Txid::from_slice(row.get::<_, &[u8]>(3)).expect("We input a txid"),
+            pubkey: row
+                .get::<_, Option<&[u8]>>(4)
+                .map(|pubkey| PublicKey::from_slice(pubkey).expect("We input a compressed pubkey")),
+            deposit_outpoint: row.get::<_, Option<&[u8]>>(5).map(|deposit_txid| OutPoint {
+                txid: Txid::from_slice(deposit_txid).expect("We input a txid"),
+                vout: row.get::<_, i32>(6) as u32,
+            }),
+            hash: row.get::<_, &[u8]>(7).to_hex(),
+        })
+        .collect())
+}
+
+/// Whether the audit trail is the one our triggers wrote: every record chained to the previous
+/// one, none missing at either end.
+pub async fn verify_audit_trail(db: &Db) -> Result<bool, DbError> {
+    let client = get_connection(db).await?;
+
+    Ok(client
+        .query_one(queries::AUDIT_CHAIN_INTACT.sql, &[])
+        .await?
+        .get(0))
+}
+
+/// Get all the deposit outpoints whose Spend was announced after the given version, sorted,
+/// along with the latest version among them (or the given one if there is none).
+pub async fn fetch_spend_outpoints(
+    db: &Db,
+    since_version: i64,
+) -> Result<(Vec<OutPoint>, i64), DbError> {
+    let client = get_connection(db).await?;
+    let mut outpoints = Vec::new();
+    let mut latest_version = since_version;
+
+    let statement = client
+        .prepare_typed(
+            queries::SPEND_OUTPOINTS_SINCE.sql,
+            queries::SPEND_OUTPOINTS_SINCE.params,
+        )
+        .await?;
+    for row in client.query(&statement, &[&since_version]).await? {
+        let txid: &[u8] = row.get(0);
+        let vout: i32 = row.get(1);
+        let version: i64 = row.get(2);
+
+        outpoints.push(OutPoint {
+            txid: Txid::from_slice(txid).expect("We input a txid"),
+            vout: vout as u32,
+        });
+        latest_version = latest_version.max(version);
+    }
 
-    Ok(spend_tx.map(|tx| encode::deserialize(&tx).expect("Added to DB with serialize()")))
+    Ok((outpoints, latest_version))
 }
@@ -0,0 +1,462 @@
+// All the statements we run against the tables defined in the schema, along with the types
+// of their parameters. Keeping them in a single place and checking in the tests that each of
+// them can be prepared against a freshly created schema catches any drift between the two
+// before it reaches production. The parameters binding a column take their type from its
+// declaration in the schema module, so that a column renamed or removed from the declarations
+// breaks the build of the queries binding it.
+
+use super::schema::{
+    audit, expected_txids, network, peer_counters, sig_windows, signatures, spend_deliveries,
+    spend_outpoints, spend_subscriptions, spend_txs, unexpected_txids, version,
+};
+
+use tokio_postgres::types::Type;
+
+/// A statement along with the types of its parameters
+#[derive(Debug)]
+pub struct Query {
+    pub sql: &'static str,
+    pub params: &'static [Type],
+}
+
+pub const SCHEMA_VERSION: Query = Query {
+    sql: "SELECT MAX(version) FROM version",
+    params: &[],
+};
+
+pub const VERSION_TABLE_EXISTS: Query = Query {
+    sql: "SELECT to_regclass('version') IS NOT NULL",
+    params: &[],
+};
+
+pub const SET_SCHEMA_VERSION: Query = Query {
+    sql: "INSERT INTO version (version) VALUES ($1)",
+    params: &[version::version],
+};
+
+pub const NETWORK: Query = Query {
+    sql: "SELECT network FROM network",
+    params: &[],
+};
+
+// Only if none is set yet
+pub const SET_NETWORK: Query = Query {
+    sql: "INSERT INTO network (network) VALUES ($1) ON CONFLICT DO NOTHING",
+    params: &[network::network],
+};
+
+pub const SERVER_VERSION: Query = Query {
+    sql: "SELECT current_setting('server_version_num')::INTEGER",
+    params: &[],
+};
+
+pub const TRY_SCHEMA_LOCK: Query = Query {
+    sql: "SELECT pg_try_advisory_lock($1)",
+    params: &[Type::INT8],
+};
+
+pub const SCHEMA_UNLOCK: Query = Query {
+    sql: "SELECT pg_advisory_unlock($1)",
+    params: &[Type::INT8],
+};
+
+pub const SIG_OF_KEY: Query = Query {
+    sql: "SELECT signature FROM signatures WHERE txid = $1 AND pubkey = $2",
+    params: &[signatures::txid, signatures::pubkey],
+};
+
+pub const INSERT_SIG: Query = Query {
+    sql: "INSERT INTO signatures (txid, pubkey, signature, tx_type) VALUES ($1, $2, $3, $4) \
+          ON CONFLICT (txid, pubkey) DO NOTHING",
+    params: &[
+        signatures::txid,
+        signatures::pubkey,
+        signatures::signature,
+        signatures::tx_type,
+    ],
+};
+
+pub const SET_SIG_WINDOW: Query = Query {
+    sql: "INSERT INTO sig_windows (txid, window_secs) VALUES ($1, $2) \
+          ON CONFLICT (txid) DO UPDATE SET window_secs = EXCLUDED.window_secs",
+    params: &[sig_windows::txid, sig_windows::window_secs],
+};
+
+pub const SIG_WINDOW_CLOSED: Query = Query {
+    sql: "SELECT NOW() > LEAST(w.declared_at, \
+                               (SELECT MIN(received_at) FROM signatures WHERE txid = $1)) \
+                         + w.window_secs * INTERVAL '1 second' \
+          FROM sig_windows AS w WHERE w.txid = $1",
+    params: &[sig_windows::txid],
+};
+
+pub const REGISTER_TXID: Query = Query {
+    sql: "INSERT INTO expected_txids (txid) VALUES ($1) ON CONFLICT DO NOTHING",
+    params: &[expected_txids::txid],
+};
+
+pub const FIRST_SIG_UNEXPECTED: Query = Query {
+    sql: "SELECT NOT EXISTS (SELECT 1 FROM signatures WHERE txid = $1) \
+          AND EXISTS (SELECT 1 FROM expected_txids) \
+          AND NOT EXISTS (SELECT 1 FROM expected_txids WHERE txid = $1)",
+    params: &[signatures::txid],
+};
+
+pub const FLAG_UNEXPECTED_TXID: Query = Query {
+    sql: "INSERT INTO unexpected_txids (txid, first_pubkey) VALUES ($1, $2) \
+          ON CONFLICT DO NOTHING",
+    params: &[unexpected_txids::txid, unexpected_txids::first_pubkey],
+};
+
+pub const UNEXPECTED_TXIDS: Query = Query {
+    sql: "SELECT txid, first_pubkey, EXTRACT(EPOCH FROM first_seen_at)::BIGINT \
+          FROM unexpected_txids ORDER BY first_seen_at",
+    params: &[],
+};
+
+pub const PEER_COUNTERS: Query = Query {
+    sql: "SELECT pubkey, counter FROM peer_counters",
+    params: &[],
+};
+
+pub const STORE_PEER_COUNTER: Query = Query {
+    sql: "INSERT INTO peer_counters (pubkey, counter) VALUES ($1, $2) \
+          ON CONFLICT (pubkey) DO UPDATE \
+          SET counter = GREATEST(peer_counters.counter, EXCLUDED.counter)",
+    params: &[peer_counters::pubkey, peer_counters::counter],
+};
+
+pub const FETCH_SIGS: Query = Query {
+    sql: "SELECT pubkey, signature FROM signatures \
+          WHERE txid = $1 AND ($2::TEXT IS NULL OR tx_type = $2)",
+    params: &[signatures::txid, signatures::tx_type],
+};
+
+pub const SIG_PROGRESS: Query = Query {
+    sql: "SELECT COUNT(*) FILTER (WHERE tx_type IN \
+                                  ('cancel', 'emergency', 'unvault-emergency', 'unvault')), \
+                 COUNT(*) FILTER (WHERE tx_type IS NULL) \
+          FROM signatures WHERE txid = $1",
+    params: &[signatures::txid],
+};
+
+pub const DELETE_SIG: Query = Query {
+    sql: "DELETE FROM signatures WHERE txid = $1 AND pubkey = $2",
+    params: &[signatures::txid, signatures::pubkey],
+};
+
+pub const INSERT_SPEND_TX: Query = Query {
+    sql: "INSERT INTO spend_txs (txid, transaction) VALUES ($1, $2) \
+          ON CONFLICT DO NOTHING", // FIXME: we should make the error explicit
+    params: &[spend_txs::txid, spend_txs::transaction],
+};
+
+pub const NEXT_SPEND_VERSION: Query = Query {
+    sql: "SELECT nextval('spend_versions')",
+    params: &[],
+};
+
+pub const SPEND_OUTPOINT_ANNOUNCEMENT: Query = Query {
+    sql: "SELECT version, spend_txid FROM spend_outpoints \
+          WHERE deposit_txid = $1 AND deposit_vout = $2",
+    params: &[spend_outpoints::deposit_txid, spend_outpoints::deposit_vout],
+};
+
+pub const UPSERT_SPEND_OUTPOINT: Query = Query {
+    sql: "INSERT INTO spend_outpoints (deposit_txid, deposit_vout, spend_txid, version) \
+          VALUES ($1, $2, $3, $4) \
+          ON CONFLICT (deposit_txid, deposit_vout) DO UPDATE \
+          SET deposit_txid = EXCLUDED.deposit_txid, \
+              deposit_vout = EXCLUDED.deposit_vout, \
+              spend_txid = EXCLUDED.spend_txid, \
+              version = EXCLUDED.version",
+    params: &[
+        spend_outpoints::deposit_txid,
+        spend_outpoints::deposit_vout,
+        spend_outpoints::spend_txid,
+        spend_outpoints::version,
+    ],
+};
+
+pub const FETCH_SPEND_TX: Query = Query {
+    sql: "SELECT transaction, ops.version FROM spend_txs as txs \
+          INNER JOIN spend_outpoints as ops ON txs.txid = ops.spend_txid \
+          WHERE ops.deposit_txid = $1 AND ops.deposit_vout = $2",
+    params: &[spend_outpoints::deposit_txid, spend_outpoints::deposit_vout],
+};
+
+pub const SPEND_OUTPOINTS_SINCE: Query = Query {
+    sql: "SELECT deposit_txid, deposit_vout, version FROM spend_outpoints \
+          WHERE version > $1 ORDER BY deposit_txid, deposit_vout",
+    params: &[spend_outpoints::version],
+};
+
+// The Spend transactions whose outpoints were all replaced have none left
+pub const LIST_SPEND_TXS: Query = Query {
+    sql: "SELECT txs.txid, EXTRACT(EPOCH FROM txs.received_at)::BIGINT, \
+          ops.deposit_txid, ops.deposit_vout FROM spend_txs as txs \
+          LEFT JOIN spend_outpoints as ops ON txs.txid = ops.spend_txid \
+          ORDER BY txs.received_at, txs.txid, ops.deposit_txid, ops.deposit_vout",
+    params: &[],
+};
+
+pub const SUBSCRIBE_SPENDS: Query = Query {
+    sql: "INSERT INTO spend_subscriptions (watchtower, deposit_txid, deposit_vout) \
+          VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+    params: &[
+        spend_subscriptions::watchtower,
+        spend_subscriptions::deposit_txid,
+        spend_subscriptions::deposit_vout,
+    ],
+};
+
+// Those being acknowledged are left out, as they will be recorded delivered
+pub const UNDELIVERED_SPENDS: Query = Query {
+    sql: "SELECT ops.deposit_txid, ops.deposit_vout, txs.transaction, ops.version \
+          FROM spend_outpoints AS ops JOIN spend_txs AS txs ON txs.txid = ops.spend_txid \
+          WHERE EXISTS (SELECT 1 FROM spend_subscriptions AS s WHERE s.watchtower = $1 \
+                        AND (s.deposit_txid IS NULL OR (s.deposit_txid = ops.deposit_txid \
+                                                        AND s.deposit_vout = ops.deposit_vout))) \
+          AND NOT EXISTS (SELECT 1 FROM spend_deliveries AS d WHERE d.watchtower = $1 \
+                          AND d.deposit_txid = ops.deposit_txid \
+                          AND d.deposit_vout = ops.deposit_vout AND d.version >= ops.version) \
+          AND NOT ops.version = ANY($2) \
+          ORDER BY ops.version, ops.deposit_txid, ops.deposit_vout LIMIT $3",
+    params: &[
+        spend_subscriptions::watchtower,
+        Type::INT8_ARRAY,
+        Type::INT8,
+    ],
+};
+
+// An announcement replaced since it was pushed isn't recorded, the new one is to be pushed
+pub const ACK_SPEND_DELIVERIES: Query = Query {
+    sql: "INSERT INTO spend_deliveries (watchtower, deposit_txid, deposit_vout, version) \
+          SELECT $1, deposit_txid, deposit_vout, version FROM spend_outpoints \
+          WHERE version = ANY($2) \
+          ON CONFLICT (watchtower, deposit_txid, deposit_vout) DO UPDATE \
+          SET version = GREATEST(spend_deliveries.version, EXCLUDED.version)",
+    params: &[spend_deliveries::watchtower, Type::INT8_ARRAY],
+};
+
+pub const AUDIT_TRAIL: Query = Query {
+    sql: "SELECT EXTRACT(EPOCH FROM recorded_at)::BIGINT, peer, operation, txid, pubkey, \
+          deposit_txid, deposit_vout, hash FROM audit \
+          WHERE ($1::BYTEA IS NULL OR txid = $1 OR deposit_txid = $1) \
+          AND ($2::BYTEA IS NULL OR peer = $2) ORDER BY id",
+    params: &[audit::txid, audit::peer],
+};
+
+// Each record must hash to what it holds and follow the previous one, the first one following
+// zeroes, and the last one must be the head of the chain.
+pub const AUDIT_CHAIN_INTACT: Query = Query {
+    sql: "SELECT NOT EXISTS ( \
+              SELECT 1 FROM ( \
+                  SELECT prev_hash, hash, LAG(hash) OVER (ORDER BY id) AS previous, \
+                  audit_hash(prev_hash, id, recorded_at, peer, operation, txid, pubkey, \
+                             deposit_txid, deposit_vout) AS expected FROM audit) AS chain \
+              WHERE hash <> expected \
+              OR prev_hash <> COALESCE(previous, decode(repeat('00', 32), 'hex'))) \
+          AND (SELECT hash FROM audit_head) = COALESCE( \
+              (SELECT hash FROM audit ORDER BY id DESC LIMIT 1), decode(repeat('00', 32), 'hex'))",
+    params: &[],
+};
+
+pub const HAS_DATA: Query = Query {
+    sql: "SELECT EXISTS (SELECT 1 FROM signatures) OR EXISTS (SELECT 1 FROM spend_txs) \
+          OR EXISTS (SELECT 1 FROM sig_windows)",
+    params: &[],
+};
+
+pub const ALL_SIGS: Query = Query {
+    sql: "SELECT txid, pubkey, signature, EXTRACT(EPOCH FROM received_at)::BIGINT, tx_type \
+          FROM signatures",
+    params: &[],
+};
+
+pub const IMPORT_SIG: Query = Query {
+    sql: "INSERT INTO signatures (txid, pubkey, signature, received_at, tx_type) \
+          VALUES ($1, $2, $3, COALESCE(to_timestamp($4), NOW()), $5)",
+    params: &[
+        signatures::txid,
+        signatures::pubkey,
+        signatures::signature,
+        Type::INT8,
+        signatures::tx_type,
+    ],
+};
+
+pub const ALL_SIG_WINDOWS: Query = Query {
+    sql: "SELECT txid, EXTRACT(EPOCH FROM declared_at)::BIGINT, window_secs FROM sig_windows",
+    params: &[],
+};
+
+pub const IMPORT_SIG_WINDOW: Query = Query {
+    sql: "INSERT INTO sig_windows (txid, declared_at, window_secs) \
+          VALUES ($1, to_timestamp($2), $3)",
+    params: &[sig_windows::txid, Type::INT8, sig_windows::window_secs],
+};
+
+pub const ALL_SPEND_TXS: Query = Query {
+    sql: "SELECT txid, transaction FROM spend_txs",
+    params: &[],
+};
+
+pub const ALL_SPEND_OUTPOINTS: Query = Query {
+    sql: "SELECT deposit_txid, deposit_vout, spend_txid, version FROM spend_outpoints",
+    params: &[],
+};
+
+pub const PEEK_SPEND_VERSION: Query = Query {
+    sql: "SELECT CASE WHEN is_called THEN last_value + 1 ELSE last_value END \
+          FROM spend_versions",
+    params: &[],
+};
+
+pub const RESET_SPEND_VERSION: Query = Query {
+    sql: "SELECT setval('spend_versions', $1, false)",
+    params: &[Type::INT8],
+};
+
+// All the signatures of a transaction go at once, the acceptance window of a transaction with
+// a signature left would otherwise start at this one. So only the transactions whose every
+// signature is older are pruned, up to $2 of them.
+pub const PRUNE_SIGS: Query = Query {
+    sql: "DELETE FROM signatures WHERE txid IN ( \
+              SELECT txid FROM signatures GROUP BY txid \
+              HAVING MAX(received_at) < to_timestamp($1::FLOAT8) LIMIT $2)",
+    params: &[Type::INT8, Type::INT8],
+};
+
+// Their outpoints go along, as they cascade. Up to $2 of them.
+pub const PRUNE_SPEND_TXS: Query = Query {
+    sql: "DELETE FROM spend_txs WHERE txid IN ( \
+              SELECT txid FROM spend_txs WHERE received_at < to_timestamp($1::FLOAT8) LIMIT $2)",
+    params: &[Type::INT8, Type::INT8],
+};
+
+// What the two above would delete, in all their batches
+pub const PRUNABLE_SIGS: Query = Query {
+    sql: "SELECT COUNT(*) FROM signatures WHERE txid IN ( \
+              SELECT txid FROM signatures GROUP BY txid \
+              HAVING MAX(received_at) < to_timestamp($1::FLOAT8))",
+    params: &[Type::INT8],
+};
+
+pub const PRUNABLE_SPEND_TXS: Query = Query {
+    sql: "SELECT COUNT(*) FROM spend_txs WHERE received_at < to_timestamp($1::FLOAT8)",
+    params: &[Type::INT8],
+};
+
+pub const SYNC_STANDBYS: Query = Query {
+    sql: "SELECT current_setting('synchronous_standby_names')",
+    params: &[],
+};
+
+pub const STORED_BYTES: Query = Query {
+    sql: "SELECT \
+          (SELECT COALESCE(SUM(octet_length(txid) + octet_length(pubkey) \
+                               + octet_length(signature)), 0) FROM signatures) \
+          + (SELECT COALESCE(SUM(octet_length(txid) + octet_length(transaction)), 0) \
+             FROM spend_txs) \
+          + (SELECT COALESCE(SUM(octet_length(deposit_txid) \
+                                 + COALESCE(octet_length(spend_txid), 0)), 0) \
+             FROM spend_outpoints)",
+    params: &[],
+};
+
+pub const ROW_COUNTS: Query = Query {
+    sql: "SELECT (SELECT COUNT(*) FROM signatures), (SELECT COUNT(*) FROM spend_txs), \
+          (SELECT COUNT(*) FROM spend_outpoints), (SELECT COUNT(*) FROM sig_windows)",
+    params: &[],
+};
+
+pub const SIGS_RECEIVED_SINCE: Query = Query {
+    sql: "SELECT COUNT(*), COALESCE(SUM(octet_length(txid) + octet_length(pubkey) \
+                                        + octet_length(signature)), 0) \
+          FROM signatures WHERE received_at > NOW() - $1 * INTERVAL '1 second'",
+    params: &[Type::INT8],
+};
+
+pub const DATABASE_SIZE: Query = Query {
+    sql: "SELECT pg_database_size(current_database())",
+    params: &[],
+};
+
+pub const DB_CONNECTIONS: Query = Query {
+    sql: "SELECT (SELECT COUNT(*) FROM pg_stat_activity WHERE datname = current_database()), \
+          current_setting('max_connections')::BIGINT",
+    params: &[],
+};
+
+pub const CACHE_HITS: Query = Query {
+    sql: "SELECT COALESCE(SUM(blks_hit), 0)::BIGINT, COALESCE(SUM(blks_read), 0)::BIGINT \
+          FROM pg_stat_database WHERE datname = current_database()",
+    params: &[],
+};
+
+// The columns of our tables in the database, for the tests to check their declarations
+#[cfg(test)]
+pub const TABLE_COLUMNS: Query = Query {
+    sql: "SELECT table_name::TEXT, column_name::TEXT, udt_name::TEXT \
+          FROM information_schema.columns WHERE table_schema = current_schema()",
+    params: &[],
+};
+
+/// Every query above, for the tests to check them against the schema
+#[cfg(test)]
+pub const ALL: &[&Query] = &[
+    &SCHEMA_VERSION,
+    &VERSION_TABLE_EXISTS,
+    &SET_SCHEMA_VERSION,
+    &NETWORK,
+    &SET_NETWORK,
+    &SERVER_VERSION,
+    &TRY_SCHEMA_LOCK,
+    &SCHEMA_UNLOCK,
+    &SIG_OF_KEY,
+    &INSERT_SIG,
+    &SET_SIG_WINDOW,
+    &SIG_WINDOW_CLOSED,
+    &REGISTER_TXID,
+    &FIRST_SIG_UNEXPECTED,
+    &FLAG_UNEXPECTED_TXID,
+    &UNEXPECTED_TXIDS,
+    &PEER_COUNTERS,
+    &STORE_PEER_COUNTER,
+    &FETCH_SIGS,
+    &SIG_PROGRESS,
+    &DELETE_SIG,
+    &INSERT_SPEND_TX,
+    &NEXT_SPEND_VERSION,
+    &SPEND_OUTPOINT_ANNOUNCEMENT,
+    &UPSERT_SPEND_OUTPOINT,
+    &FETCH_SPEND_TX,
+    &SPEND_OUTPOINTS_SINCE,
+    &LIST_SPEND_TXS,
+    &SUBSCRIBE_SPENDS,
+    &UNDELIVERED_SPENDS,
+    &ACK_SPEND_DELIVERIES,
+    &AUDIT_TRAIL,
+    &AUDIT_CHAIN_INTACT,
+    &HAS_DATA,
+    &ALL_SIGS,
+    &IMPORT_SIG,
+    &ALL_SIG_WINDOWS,
+    &IMPORT_SIG_WINDOW,
+    &ALL_SPEND_TXS,
+    &ALL_SPEND_OUTPOINTS,
+    &PEEK_SPEND_VERSION,
+    &RESET_SPEND_VERSION,
+    &PRUNE_SIGS,
+    &PRUNE_SPEND_TXS,
+    &PRUNABLE_SIGS,
+    &PRUNABLE_SPEND_TXS,
+    &SYNC_STANDBYS,
+    &STORED_BYTES,
+    &ROW_COUNTS,
+    &SIGS_RECEIVED_SINCE,
+    &DATABASE_SIZE,
+    &DB_CONNECTIONS,
+    &CACHE_HITS,
+];
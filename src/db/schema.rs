@@ -1,3 +1,4 @@
+// The schema as of its first migration. Changes to it go in new migrations instead.
 pub const SCHEMA: &str = "\
 CREATE TABLE IF NOT EXISTS version (
     version INTEGER UNIQUE NOT NULL
@@ -20,4 +21,137 @@ CREATE TABLE IF NOT EXISTS spend_outpoints (
     spend_txid BYTEA REFERENCES spend_txs (txid) ON DELETE CASCADE,
     UNIQUE (deposit_txid, deposit_vout)
 );
+
+-- Every Spend announcement gets a new version, which managers can use to make sure they
+-- are replacing the announcement they think they are.
+CREATE SEQUENCE IF NOT EXISTS spend_versions;
+ALTER TABLE spend_outpoints ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 0;
+-- Watchtowers fetch the outpoints announced since a given version.
+CREATE INDEX IF NOT EXISTS spend_outpoints_version ON spend_outpoints (version);
+
+-- Managers may only accept signatures for a transaction during a window starting at its first
+-- sight: either its first signature or the declaration of the window.
+ALTER TABLE signatures ADD COLUMN IF NOT EXISTS received_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+-- Clients may tell us what kind of transaction a signature is for.
+ALTER TABLE signatures ADD COLUMN IF NOT EXISTS tx_type TEXT
+    CHECK (tx_type IN ('cancel', 'emergency', 'unvault-emergency', 'spend'));
+CREATE TABLE IF NOT EXISTS sig_windows (
+    txid BYTEA UNIQUE NOT NULL,
+    declared_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    window_secs BIGINT NOT NULL
+);
+
+-- Managers may register the transactions they expect signatures for. The first signature for
+-- another one is flagged, as it may be for a mistyped txid.
+CREATE TABLE IF NOT EXISTS expected_txids (
+    txid BYTEA UNIQUE NOT NULL,
+    registered_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE TABLE IF NOT EXISTS unexpected_txids (
+    txid BYTEA UNIQUE NOT NULL,
+    first_pubkey BYTEA NOT NULL,
+    first_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- The last message counter of each participant numbering its messages, persisted from time
+-- to time so that messages from a previous session can't be replayed after a restart.
+CREATE TABLE IF NOT EXISTS peer_counters (
+    pubkey BYTEA UNIQUE NOT NULL,
+    counter BIGINT NOT NULL
+);
 ";
+
+// Declare the columns of our tables, each as a constant of its type in a module named after
+// its table, so that a query referring to a column which isn't declared doesn't build. Not
+// every column is a parameter of a query.
+macro_rules! columns {
+    ($($table:ident { $($column:ident: $type:ident),* $(,)? })*) => {
+        $(
+            #[allow(dead_code, non_upper_case_globals)]
+            pub mod $table {
+                use tokio_postgres::types::Type;
+
+                $(pub const $column: Type = Type::$type;)*
+            }
+        )*
+
+        /// All the columns declared, by table, along with their type
+        #[cfg(test)]
+        pub const COLUMNS: &[(&str, &str, tokio_postgres::types::Type)] = &[
+            $($((stringify!($table), stringify!($column), $table::$column),)*)*
+        ];
+    };
+}
+
+// The columns once all the migrations are applied. The tests check them against the schema
+// of a migrated database, a migration adding or changing one must update them.
+columns! {
+    version {
+        version: INT4,
+    }
+    signatures {
+        txid: BYTEA,
+        pubkey: BYTEA,
+        signature: BYTEA,
+        received_at: TIMESTAMPTZ,
+        tx_type: TEXT,
+    }
+    spend_txs {
+        txid: BYTEA,
+        transaction: BYTEA,
+        received_at: TIMESTAMPTZ,
+    }
+    spend_outpoints {
+        deposit_txid: BYTEA,
+        deposit_vout: INT4,
+        spend_txid: BYTEA,
+        version: INT8,
+    }
+    sig_windows {
+        txid: BYTEA,
+        declared_at: TIMESTAMPTZ,
+        window_secs: INT8,
+    }
+    expected_txids {
+        txid: BYTEA,
+        registered_at: TIMESTAMPTZ,
+    }
+    unexpected_txids {
+        txid: BYTEA,
+        first_pubkey: BYTEA,
+        first_seen_at: TIMESTAMPTZ,
+    }
+    peer_counters {
+        pubkey: BYTEA,
+        counter: INT8,
+    }
+    audit {
+        id: INT8,
+        recorded_at: TIMESTAMPTZ,
+        peer: BYTEA,
+        operation: TEXT,
+        txid: BYTEA,
+        pubkey: BYTEA,
+        deposit_txid: BYTEA,
+        deposit_vout: INT4,
+        prev_hash: BYTEA,
+        hash: BYTEA,
+    }
+    audit_head {
+        hash: BYTEA,
+    }
+    spend_subscriptions {
+        watchtower: BYTEA,
+        deposit_txid: BYTEA,
+        deposit_vout: INT4,
+    }
+    spend_deliveries {
+        watchtower: BYTEA,
+        deposit_txid: BYTEA,
+        deposit_vout: INT4,
+        version: INT8,
+    }
+    network {
+        network: TEXT,
+    }
+}
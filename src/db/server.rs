@@ -0,0 +1,52 @@
+// The Postgres server we are talking to. All our statements are supported from 9.6 on (for
+// `ADD COLUMN IF NOT EXISTS`), so rather than adapting them to older servers we refuse to
+// start against one with a clear message.
+
+use super::{get_connection, queries, Db, DbError};
+
+use std::fmt;
+
+/// A Postgres server version, as its `server_version_num`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServerVersion(pub i32);
+
+/// The oldest server we support
+pub const MIN_SERVER_VERSION: ServerVersion = ServerVersion(90600);
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (major, minor) = (self.0 / 10000, self.0 / 100 % 100);
+        // Up to 9.6 the major version had two components, and the last one is the patch
+        // level. From 10 on it has a single one, and the middle one is always 0.
+        if major < 10 {
+            write!(f, "{}.{}.{}", major, minor, self.0 % 100)
+        } else {
+            write!(f, "{}.{}", major, self.0 % 10000)
+        }
+    }
+}
+
+/// Get the version of the server we are connected to
+pub async fn server_version(db: &Db) -> Result<ServerVersion, DbError> {
+    let client = get_connection(db).await?;
+    let version: i32 = client
+        .query_one(queries::SERVER_VERSION.sql, &[])
+        .await?
+        .get(0);
+    Ok(ServerVersion(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerVersion, MIN_SERVER_VERSION};
+
+    #[test]
+    fn server_versions() {
+        assert_eq!(MIN_SERVER_VERSION.to_string(), "9.6.0");
+        assert_eq!(ServerVersion(90524).to_string(), "9.5.24");
+        assert_eq!(ServerVersion(100023).to_string(), "10.23");
+        assert_eq!(ServerVersion(150004).to_string(), "15.4");
+        assert!(ServerVersion(90524) < MIN_SERVER_VERSION);
+        assert!(ServerVersion(100023) > MIN_SERVER_VERSION);
+    }
+}
@@ -0,0 +1,293 @@
+// A consistent copy of the whole database content, to bootstrap a standby coordinator from a
+// running one without setting up Postgres replication.
+
+use super::{get_connection, invalidate_sigs, queries, Db, DbError};
+use revault_net::bitcoin::hashes::hex::{FromHex, ToHex};
+
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio_postgres::IsolationLevel;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// An error originating from the Postgres backend
+    Postgres(tokio_postgres::Error),
+    /// Any other error accessing the database
+    Db(DbError),
+    /// Trying to import a snapshot into a database which already has some data
+    NotEmpty,
+    /// The snapshot was taken with a different schema version than ours
+    SchemaMismatch {
+        snapshot: Option<i32>,
+        ours: Option<i32>,
+    },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Postgres(e) => write!(f, "{}", e),
+            Self::Db(e) => write!(f, "{}", e),
+            Self::NotEmpty => write!(f, "The database already contains some data"),
+            Self::SchemaMismatch { snapshot, ours } => write!(
+                f,
+                "The snapshot schema version ({:?}) is not the database's one ({:?})",
+                snapshot, ours
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<tokio_postgres::Error> for SnapshotError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        Self::Postgres(e)
+    }
+}
+
+impl From<DbError> for SnapshotError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::Postgres(e) => Self::Postgres(e),
+            e => Self::Db(e),
+        }
+    }
+}
+
+fn serialize_hex<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&data.to_hex())
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(d)?;
+    Vec::from_hex(&s).map_err(de::Error::custom)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SigRow {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub txid: Vec<u8>,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub pubkey: Vec<u8>,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub signature: Vec<u8>,
+    /// As a UNIX timestamp. Absent from older snapshots, in which case it's set to the import
+    /// time.
+    #[serde(default)]
+    pub received_at: Option<i64>,
+    #[serde(default)]
+    pub tx_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpendTxRow {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub txid: Vec<u8>,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub transaction: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpendOutpointRow {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub deposit_txid: Vec<u8>,
+    pub deposit_vout: i32,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub spend_txid: Vec<u8>,
+    pub version: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SigWindowRow {
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub txid: Vec<u8>,
+    /// As a UNIX timestamp
+    pub declared_at: i64,
+    pub window_secs: i64,
+}
+
+/// The content of all our tables at a given point in time
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub schema_version: Option<i32>,
+    pub signatures: Vec<SigRow>,
+    pub spend_txs: Vec<SpendTxRow>,
+    pub spend_outpoints: Vec<SpendOutpointRow>,
+    #[serde(default)]
+    pub sig_windows: Vec<SigWindowRow>,
+    /// The version the next Spend announcement will get
+    pub next_spend_version: i64,
+}
+
+/// Read the whole database content. This happens in a single REPEATABLE READ transaction so
+/// that the snapshot is consistent even if participants keep sending us data meanwhile.
+pub async fn export_snapshot(db: &Db) -> Result<Snapshot, SnapshotError> {
+    let mut client = get_connection(db).await?;
+    let db_tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+
+    let schema_version = db_tx
+        .query_one(queries::SCHEMA_VERSION.sql, &[])
+        .await?
+        .get(0);
+    let signatures = db_tx
+        .query(queries::ALL_SIGS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| SigRow {
+            txid: row.get(0),
+            pubkey: row.get(1),
+            signature: row.get(2),
+            received_at: row.get(3),
+            tx_type: row.get(4),
+        })
+        .collect();
+    let sig_windows = db_tx
+        .query(queries::ALL_SIG_WINDOWS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| SigWindowRow {
+            txid: row.get(0),
+            declared_at: row.get(1),
+            window_secs: row.get(2),
+        })
+        .collect();
+    let spend_txs = db_tx
+        .query(queries::ALL_SPEND_TXS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| SpendTxRow {
+            txid: row.get(0),
+            transaction: row.get(1),
+        })
+        .collect();
+    let spend_outpoints = db_tx
+        .query(queries::ALL_SPEND_OUTPOINTS.sql, &[])
+        .await?
+        .iter()
+        .map(|row| SpendOutpointRow {
+            deposit_txid: row.get(0),
+            deposit_vout: row.get(1),
+            spend_txid: row.get(2),
+            version: row.get(3),
+        })
+        .collect();
+    // Sequences aren't transactional, so this may be ahead of the announcements we read
+    // above. That's fine as versions only need to be increasing.
+    let next_spend_version = db_tx
+        .query_one(queries::PEEK_SPEND_VERSION.sql, &[])
+        .await?
+        .get(0);
+
+    db_tx.commit().await?;
+    Ok(Snapshot {
+        schema_version,
+        signatures,
+        spend_txs,
+        spend_outpoints,
+        sig_windows,
+        next_spend_version,
+    })
+}
+
+/// Fill an empty database with the content of this snapshot, in a single transaction.
+pub async fn import_snapshot(db: &Db, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+    let mut client = get_connection(db).await?;
+    let db_tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::Serializable)
+        .start()
+        .await?;
+
+    let schema_version = db_tx
+        .query_one(queries::SCHEMA_VERSION.sql, &[])
+        .await?
+        .get(0);
+    if snapshot.schema_version != schema_version {
+        return Err(SnapshotError::SchemaMismatch {
+            snapshot: snapshot.schema_version,
+            ours: schema_version,
+        });
+    }
+    if db_tx.query_one(queries::HAS_DATA.sql, &[]).await?.get(0) {
+        return Err(SnapshotError::NotEmpty);
+    }
+
+    let statement = db_tx
+        .prepare_typed(queries::IMPORT_SIG.sql, queries::IMPORT_SIG.params)
+        .await?;
+    for sig in snapshot.signatures.iter() {
+        db_tx
+            .execute(
+                &statement,
+                &[
+                    &sig.txid,
+                    &sig.pubkey,
+                    &sig.signature,
+                    &sig.received_at,
+                    &sig.tx_type,
+                ],
+            )
+            .await?;
+    }
+    let statement = db_tx
+        .prepare_typed(
+            queries::IMPORT_SIG_WINDOW.sql,
+            queries::IMPORT_SIG_WINDOW.params,
+        )
+        .await?;
+    for window in snapshot.sig_windows.iter() {
+        db_tx
+            .execute(
+                &statement,
+                &[&window.txid, &window.declared_at, &window.window_secs],
+            )
+            .await?;
+    }
+    let statement = db_tx
+        .prepare_typed(
+            queries::INSERT_SPEND_TX.sql,
+            queries::INSERT_SPEND_TX.params,
+        )
+        .await?;
+    for spend_tx in snapshot.spend_txs.iter() {
+        db_tx
+            .execute(&statement, &[&spend_tx.txid, &spend_tx.transaction])
+            .await?;
+    }
+    let statement = db_tx
+        .prepare_typed(
+            queries::UPSERT_SPEND_OUTPOINT.sql,
+            queries::UPSERT_SPEND_OUTPOINT.params,
+        )
+        .await?;
+    for outpoint in snapshot.spend_outpoints.iter() {
+        db_tx
+            .execute(
+                &statement,
+                &[
+                    &outpoint.deposit_txid,
+                    &outpoint.deposit_vout,
+                    &outpoint.spend_txid,
+                    &outpoint.version,
+                ],
+            )
+            .await?;
+    }
+    db_tx
+        .query_one(
+            queries::RESET_SPEND_VERSION.sql,
+            &[&snapshot.next_spend_version],
+        )
+        .await?;
+
+    db_tx.commit().await?;
+    invalidate_sigs(db, None);
+    Ok(())
+}
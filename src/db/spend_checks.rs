@@ -0,0 +1,145 @@
+// What we check of a Spend transaction before storing it. We only get the transaction and the
+// deposit outpoints it's announced for, not the Unvault transactions it spends, so we can't tell
+// whether it spends the Unvault outputs of these very deposits. We can make sure it's one the
+// watchtowers may act upon though: announced for distinct deposits, spending distinct outputs
+// which are not the deposits themselves, and signed.
+
+use revault_net::bitcoin::{OutPoint, Transaction};
+
+use std::{collections::HashSet, fmt};
+
+/// Why a Spend transaction can't be stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpendDefect {
+    /// It's not announced for any deposit outpoint
+    NoDepositOutpoint,
+    /// It's announced twice for this deposit outpoint
+    DuplicatedDepositOutpoint(OutPoint),
+    /// It has no input, or no output
+    Empty,
+    /// It spends this output twice
+    DuplicatedInput(OutPoint),
+    /// It spends this deposit outpoint directly, instead of the output of its Unvault
+    SpendsDeposit(OutPoint),
+    /// The input at this index isn't signed
+    UnsignedInput(usize),
+}
+
+impl fmt::Display for SpendDefect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoDepositOutpoint => write!(f, "not announced for any deposit outpoint"),
+            Self::DuplicatedDepositOutpoint(o) => {
+                write!(f, "announced twice for deposit outpoint '{}'", o)
+            }
+            Self::Empty => write!(f, "no input or no output"),
+            Self::DuplicatedInput(o) => write!(f, "spends '{}' twice", o),
+            Self::SpendsDeposit(o) => write!(
+                f,
+                "spends deposit outpoint '{}' directly instead of its Unvault output",
+                o
+            ),
+            Self::UnsignedInput(i) => write!(f, "input {} isn't signed", i),
+        }
+    }
+}
+
+/// Check this Spend transaction, announced for these deposit outpoints, can be stored
+pub(super) fn check_spend(
+    deposit_outpoints: &[OutPoint],
+    transaction: &Transaction,
+) -> Result<(), SpendDefect> {
+    if deposit_outpoints.is_empty() {
+        return Err(SpendDefect::NoDepositOutpoint);
+    }
+    let mut deposits = HashSet::new();
+    for outpoint in deposit_outpoints {
+        if !deposits.insert(outpoint) {
+            return Err(SpendDefect::DuplicatedDepositOutpoint(*outpoint));
+        }
+    }
+
+    if transaction.input.is_empty() || transaction.output.is_empty() {
+        return Err(SpendDefect::Empty);
+    }
+    let mut spent = HashSet::new();
+    for (i, input) in transaction.input.iter().enumerate() {
+        if !spent.insert(input.previous_output) {
+            return Err(SpendDefect::DuplicatedInput(input.previous_output));
+        }
+        if deposits.contains(&input.previous_output) {
+            return Err(SpendDefect::SpendsDeposit(input.previous_output));
+        }
+        if input.witness.is_empty() {
+            return Err(SpendDefect::UnsignedInput(i));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_spend, SpendDefect};
+    use revault_net::bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+    use std::str::FromStr;
+
+    #[test]
+    fn spend_checks() {
+        let deposit = OutPoint::from_str(
+            "4e37824b0bd0843bb94c290956374ffa1752d4c6bc9089fcbd20e1e63518b25e:0",
+        )
+        .unwrap();
+        let unvault = OutPoint::from_str(
+            "dbf7040be3ce465638373f48fb681bf3ae334691c328294f908baadfb927e942:0",
+        )
+        .unwrap();
+        let signed_input = |previous_output| TxIn {
+            previous_output,
+            witness: vec![vec![0x01]],
+            ..TxIn::default()
+        };
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![signed_input(unvault)],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        check_spend(&[deposit], &transaction).unwrap();
+
+        assert_eq!(
+            check_spend(&[], &transaction),
+            Err(SpendDefect::NoDepositOutpoint)
+        );
+        assert_eq!(
+            check_spend(&[deposit, deposit], &transaction),
+            Err(SpendDefect::DuplicatedDepositOutpoint(deposit))
+        );
+
+        transaction.input.push(signed_input(unvault));
+        assert_eq!(
+            check_spend(&[deposit], &transaction),
+            Err(SpendDefect::DuplicatedInput(unvault))
+        );
+        transaction.input[1] = signed_input(deposit);
+        assert_eq!(
+            check_spend(&[deposit], &transaction),
+            Err(SpendDefect::SpendsDeposit(deposit))
+        );
+        transaction.input[1] = TxIn::default();
+        assert_eq!(
+            check_spend(&[deposit], &transaction),
+            Err(SpendDefect::UnsignedInput(1))
+        );
+
+        transaction.input.clear();
+        assert_eq!(
+            check_spend(&[deposit], &transaction),
+            Err(SpendDefect::Empty)
+        );
+    }
+}
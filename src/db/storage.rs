@@ -0,0 +1,85 @@
+// A ceiling on the data we store, so that a flood of messages can't fill the disk Postgres
+// (and its WAL) lives on and take the whole host down.
+
+use super::{get_connection, queries, Db, DbError};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Once full, only accept new data again after getting under this share of the ceiling, so
+// that we don't flap around it.
+const RESUME_PERCENT: u64 = 90;
+
+/// Whether we are refusing new data, as updated from the size of what we store
+#[derive(Debug)]
+pub struct StorageGuard {
+    max_bytes: u64,
+    full: AtomicBool,
+}
+
+impl StorageGuard {
+    pub fn new(max_bytes: u64) -> StorageGuard {
+        StorageGuard {
+            max_bytes,
+            full: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Update our state from the number of bytes we currently store
+    pub fn update(&self, stored_bytes: u64) {
+        let resume_bytes = self.max_bytes / 100 * RESUME_PERCENT;
+        if stored_bytes >= self.max_bytes {
+            if !self.full.swap(true, Ordering::Relaxed) {
+                log::error!(
+                    "Storing {} bytes, over the maximum of {}. Refusing new data until we \
+                     get under {} bytes.",
+                    stored_bytes,
+                    self.max_bytes,
+                    resume_bytes
+                );
+            }
+        } else if stored_bytes < resume_bytes && self.full.swap(false, Ordering::Relaxed) {
+            log::warn!(
+                "Storing {} bytes, accepting new data again (maximum is {})",
+                stored_bytes,
+                self.max_bytes
+            );
+        }
+    }
+}
+
+/// The total size of the signatures, Spend transactions and outpoints we store
+pub async fn stored_bytes(db: &Db) -> Result<u64, DbError> {
+    let client = get_connection(db).await?;
+    let stored_bytes: i64 = client
+        .query_one(queries::STORED_BYTES.sql, &[])
+        .await?
+        .get(0);
+    Ok(stored_bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StorageGuard;
+
+    #[test]
+    fn storage_guard_hysteresis() {
+        let guard = StorageGuard::new(1_000);
+        assert!(!guard.is_full());
+        guard.update(999);
+        assert!(!guard.is_full());
+        guard.update(1_000);
+        assert!(guard.is_full());
+        // We don't accept new data as soon as we get under the ceiling..
+        guard.update(950);
+        assert!(guard.is_full());
+        // .. but once we get sufficiently under it.
+        guard.update(899);
+        assert!(!guard.is_full());
+        guard.update(950);
+        assert!(!guard.is_full());
+    }
+}
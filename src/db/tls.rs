@@ -0,0 +1,110 @@
+// The connections to the database over TLS. They are only made with `sslmode=require`: with
+// the default `prefer` we connect without TLS as we always did, rather than failing to check
+// the certificate of a server which happens to support it.
+//
+// The server's certificate is checked against its host name, and against the configured
+// authorities or the usual web ones. We present a certificate of our own if one is configured.
+
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use std::{error, fmt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsError(String);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for TlsError {}
+
+/// The certificates to check the database server's one against, and ours if any
+#[derive(Clone)]
+pub struct PostgresTls(MakeRustlsConnect);
+
+impl PostgresTls {
+    /// `root_certs` are the PEM certificates of the authorities to trust, the usual web ones if
+    /// None. `client_cert` is our PEM certificate chain along with its PEM private key.
+    pub fn new(
+        root_certs: Option<&[u8]>,
+        client_cert: Option<(&[u8], &[u8])>,
+    ) -> Result<PostgresTls, TlsError> {
+        let mut roots = RootCertStore::empty();
+        match root_certs {
+            Some(mut pem) => {
+                let certs = rustls_pemfile::certs(&mut pem)
+                    .map_err(|e| TlsError(format!("Parsing the root certificates: {}", e)))?;
+                if roots.add_parsable_certificates(&certs).0 == 0 {
+                    return Err(TlsError("No valid root certificate".to_string()));
+                }
+            }
+            None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                |anchor| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        anchor.subject,
+                        anchor.spki,
+                        anchor.name_constraints,
+                    )
+                },
+            )),
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((mut cert_pem, mut key_pem)) => {
+                let chain = rustls_pemfile::certs(&mut cert_pem)
+                    .map_err(|e| TlsError(format!("Parsing the client certificate: {}", e)))?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect::<Vec<_>>();
+                if chain.is_empty() {
+                    return Err(TlsError("No client certificate".to_string()));
+                }
+                let key = rustls_pemfile::read_all(&mut key_pem)
+                    .map_err(|e| TlsError(format!("Parsing the client key: {}", e)))?
+                    .into_iter()
+                    .find_map(|item| match item {
+                        Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                            Some(PrivateKey(key))
+                        }
+                        _ => None,
+                    })
+                    .ok_or_else(|| TlsError("No client private key".to_string()))?;
+                builder
+                    .with_single_cert(chain, key)
+                    .map_err(|e| TlsError(format!("Invalid client certificate: {}", e)))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(PostgresTls(MakeRustlsConnect::new(config)))
+    }
+
+    pub(super) fn connector(&self) -> MakeRustlsConnect {
+        self.0.clone()
+    }
+}
+
+impl Default for PostgresTls {
+    fn default() -> PostgresTls {
+        PostgresTls::new(None, None).expect("The web authorities are valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostgresTls;
+
+    #[test]
+    fn tls_certificates() {
+        PostgresTls::default();
+        assert!(PostgresTls::new(Some(b"not a certificate"), None).is_err());
+        assert!(PostgresTls::new(None, Some((b"", b""))).is_err());
+    }
+}
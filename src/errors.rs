@@ -0,0 +1,265 @@
+use crate::{
+    bandwidth::QuotaExceeded, catalog, counters::ReplayedMessage, db::DbError,
+    policy::PolicyViolation, processing::OutOfRole, ratelimit::RateLimited,
+};
+
+use std::{
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The subsystem an error happened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Reading from or writing to an established connection
+    Transport,
+    /// Performing the Noise handshake with a new peer
+    Handshake,
+    /// Making sense of a message
+    Protocol,
+    /// Refusing a peer we know about, such as a banned one
+    Auth,
+    /// Accessing the database
+    Db,
+    /// Acting upon a well-formed message
+    Validation,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 6] = [
+        Self::Transport,
+        Self::Handshake,
+        Self::Protocol,
+        Self::Auth,
+        Self::Db,
+        Self::Validation,
+    ];
+
+    /// Which subsystem an error returned by the processing of a message comes from
+    pub fn of_processing_error(error: &(dyn Error + 'static)) -> ErrorKind {
+        if error.is::<serde_json::Error>() || error.is::<OutOfRole>() {
+            Self::Protocol
+        } else if error.is::<RateLimited>() {
+            Self::Auth
+        } else if error.is::<tokio_postgres::Error>() {
+            Self::Db
+        } else {
+            // The database may refuse the data too, which isn't a database failure
+            match error.downcast_ref::<DbError>() {
+                Some(DbError::Postgres(_))
+                | Some(DbError::PoolTimeout(_))
+                | Some(DbError::Unavailable(_)) => Self::Db,
+                _ => Self::Validation,
+            }
+        }
+    }
+}
+
+/// The code of an error returned by the processing of a message, as told to its sender
+pub fn error_code(error: &(dyn Error + 'static)) -> u32 {
+    if error.is::<serde_json::Error>() {
+        catalog::MALFORMED_MESSAGE
+    } else if error.is::<OutOfRole>() {
+        catalog::OUT_OF_ROLE
+    } else if error.is::<RateLimited>() {
+        catalog::RATE_LIMITED
+    } else if error.is::<ReplayedMessage>() {
+        catalog::REPLAYED_MESSAGE
+    } else if error.is::<QuotaExceeded>() {
+        catalog::QUOTA_EXCEEDED
+    } else if error.is::<PolicyViolation>() {
+        catalog::POLICY_VIOLATION
+    } else if error.is::<tokio_postgres::Error>() {
+        catalog::DATABASE_UNAVAILABLE
+    } else {
+        error
+            .downcast_ref::<DbError>()
+            .map_or(catalog::OTHER_PROCESSING_ERROR, DbError::error_code)
+    }
+}
+
+/// For how long the sender of a message refused with this error should wait before retrying,
+/// if it's only a matter of time
+pub fn retry_after(error: &(dyn Error + 'static)) -> Option<Duration> {
+    if let Some(RateLimited { retry_after }) = error.downcast_ref() {
+        Some(*retry_after)
+    } else if let Some(DbError::Unavailable(retry_after)) = error.downcast_ref() {
+        Some(*retry_after)
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Transport => write!(f, "transport"),
+            Self::Handshake => write!(f, "handshake"),
+            Self::Protocol => write!(f, "protocol"),
+            Self::Auth => write!(f, "auth"),
+            Self::Db => write!(f, "db"),
+            Self::Validation => write!(f, "validation"),
+        }
+    }
+}
+
+/// The number of errors per subsystem since the last summary
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    counts: [AtomicU64; 6],
+}
+
+impl ErrorCounters {
+    pub fn new() -> ErrorCounters {
+        ErrorCounters::default()
+    }
+
+    pub fn record(&self, kind: ErrorKind) {
+        self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get a summary of the errors recorded since the last call, or None if there was none
+    pub fn summary(&self) -> Option<String> {
+        let counts: Vec<(ErrorKind, u64)> = ErrorKind::ALL
+            .iter()
+            .map(|kind| {
+                (
+                    *kind,
+                    self.counts[*kind as usize].swap(0, Ordering::Relaxed),
+                )
+            })
+            .collect();
+        if counts.iter().all(|(_, count)| *count == 0) {
+            return None;
+        }
+
+        Some(
+            counts
+                .iter()
+                .map(|(kind, count)| format!("{}: {}", kind, count))
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{error_code, retry_after, ErrorCounters, ErrorKind};
+    use crate::{
+        catalog,
+        counters::ReplayedMessage,
+        db::{DbError, SpendDefect},
+        processing::OutOfRole,
+        ratelimit::RateLimited,
+    };
+    use revault_net::bitcoin::OutPoint;
+
+    use std::time::Duration;
+
+    #[test]
+    fn error_summary() {
+        let counters = ErrorCounters::new();
+        assert!(counters.summary().is_none());
+
+        counters.record(ErrorKind::Db);
+        counters.record(ErrorKind::Db);
+        counters.record(ErrorKind::Auth);
+        assert_eq!(
+            counters.summary().unwrap(),
+            "transport: 0, handshake: 0, protocol: 0, auth: 1, db: 2, validation: 0"
+        );
+        // It's reset once summarized
+        assert!(counters.summary().is_none());
+
+        let malformed: Box<dyn std::error::Error> =
+            serde_json::from_str::<u32>("{").unwrap_err().into();
+        assert_eq!(
+            ErrorKind::of_processing_error(malformed.as_ref()),
+            ErrorKind::Protocol
+        );
+        let out_of_role: Box<dyn std::error::Error> = OutOfRole { message: "sig" }.into();
+        assert_eq!(
+            ErrorKind::of_processing_error(out_of_role.as_ref()),
+            ErrorKind::Protocol
+        );
+        let rate_limited: Box<dyn std::error::Error> = RateLimited {
+            retry_after: Duration::from_millis(500),
+        }
+        .into();
+        assert_eq!(
+            ErrorKind::of_processing_error(rate_limited.as_ref()),
+            ErrorKind::Auth
+        );
+        let duplicate: Box<dyn std::error::Error> = DbError::Duplicate.into();
+        assert_eq!(
+            ErrorKind::of_processing_error(duplicate.as_ref()),
+            ErrorKind::Validation
+        );
+        let pool_timeout: Box<dyn std::error::Error> =
+            DbError::PoolTimeout(Duration::from_secs(30)).into();
+        assert_eq!(
+            ErrorKind::of_processing_error(pool_timeout.as_ref()),
+            ErrorKind::Db
+        );
+        let other: Box<dyn std::error::Error> = "Nope".into();
+        assert_eq!(
+            ErrorKind::of_processing_error(other.as_ref()),
+            ErrorKind::Validation
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        let malformed: Box<dyn std::error::Error> =
+            serde_json::from_str::<u32>("{").unwrap_err().into();
+        assert_eq!(error_code(malformed.as_ref()), catalog::MALFORMED_MESSAGE);
+        let out_of_role: Box<dyn std::error::Error> = OutOfRole { message: "sig" }.into();
+        assert_eq!(error_code(out_of_role.as_ref()), catalog::OUT_OF_ROLE);
+        let replayed: Box<dyn std::error::Error> = ReplayedMessage {
+            counter: 2,
+            last: 3,
+        }
+        .into();
+        assert_eq!(error_code(replayed.as_ref()), catalog::REPLAYED_MESSAGE);
+        let storage_full: Box<dyn std::error::Error> = DbError::StorageFull.into();
+        assert_eq!(error_code(storage_full.as_ref()), catalog::STORAGE_FULL);
+        let conflicting: Box<dyn std::error::Error> = DbError::ConflictingSignature.into();
+        assert_eq!(
+            error_code(conflicting.as_ref()),
+            catalog::CONFLICTING_SIGNATURE
+        );
+        let invalid_spend: Box<dyn std::error::Error> =
+            DbError::InvalidSpend(SpendDefect::Empty).into();
+        assert_eq!(error_code(invalid_spend.as_ref()), catalog::INVALID_SPEND);
+        let conflicting_spend: Box<dyn std::error::Error> =
+            DbError::ConflictingSpend(OutPoint::default(), 1).into();
+        assert_eq!(
+            error_code(conflicting_spend.as_ref()),
+            catalog::CONFLICTING_SPEND
+        );
+        let outdated: Box<dyn std::error::Error> = DbError::OutdatedVersion(3).into();
+        assert_eq!(error_code(outdated.as_ref()), catalog::OUTDATED_VERSION);
+        let pool_timeout: Box<dyn std::error::Error> =
+            DbError::PoolTimeout(Duration::from_secs(30)).into();
+        assert_eq!(
+            error_code(pool_timeout.as_ref()),
+            catalog::DATABASE_UNAVAILABLE
+        );
+        let unavailable: Box<dyn std::error::Error> =
+            DbError::Unavailable(Duration::from_millis(1500)).into();
+        assert_eq!(
+            error_code(unavailable.as_ref()),
+            catalog::DATABASE_UNAVAILABLE
+        );
+        assert_eq!(
+            retry_after(unavailable.as_ref()),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after(pool_timeout.as_ref()), None);
+        let other: Box<dyn std::error::Error> = "Nope".into();
+        assert_eq!(error_code(other.as_ref()), catalog::OTHER_PROCESSING_ERROR);
+    }
+}
@@ -0,0 +1,89 @@
+// The lifecycle of the connections, streamed to the operators who subscribed to it on the
+// control socket: who connects and as what, who we refuse and why, who disconnects and who
+// gets banned.
+
+use crate::sessions::Role;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// How many events a subscriber may lag behind before it misses the oldest ones
+const EVENTS_CAPACITY: usize = 256;
+
+/// Something that happened to a connection. The peers are identified by their Noise key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A participant connected with these roles
+    Connected {
+        conn_id: u64,
+        peer: String,
+        roles: &'static [Role],
+    },
+    /// We refused the connection of a peer which completed the handshake
+    Refused { peer: String, reason: String },
+    /// A connection was closed, by either side
+    Disconnected { conn_id: u64, peer: String },
+    /// A peer got banned after misbehaving too much
+    Banned { peer: String, misbehavior: String },
+}
+
+/// Where the events are published. Nothing is kept when there is no subscriber.
+#[derive(Debug)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Events {
+        Events(broadcast::channel(EVENTS_CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: Event) {
+        // It only fails when nobody is listening
+        let _ = self.0.send(event);
+    }
+
+    /// Get the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Events};
+    use crate::sessions::Role;
+
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[test]
+    fn published_to_subscribers() {
+        let events = Events::default();
+        // Nobody is listening yet
+        events.publish(Event::Disconnected {
+            conn_id: 1,
+            peer: "0a".repeat(32),
+        });
+
+        let mut subscriber = events.subscribe();
+        let connected = Event::Connected {
+            conn_id: 2,
+            peer: "0a".repeat(32),
+            roles: &[Role::Manager, Role::Stakeholder],
+        };
+        events.publish(connected.clone());
+        assert_eq!(subscriber.try_recv(), Ok(connected.clone()));
+        assert_eq!(subscriber.try_recv(), Err(TryRecvError::Empty));
+
+        assert_eq!(
+            serde_json::to_value(&connected).unwrap(),
+            serde_json::json!({
+                "event": "connected",
+                "conn_id": 2,
+                "peer": "0a".repeat(32),
+                "roles": ["manager", "stakeholder"],
+            })
+        );
+    }
+}
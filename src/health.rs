@@ -0,0 +1,106 @@
+// What the liveness and readiness probes are told about a running coordinator: whether it can
+// reach its database, whether it accepts connections and when it last stored data a participant
+// sent. It's answered on the control socket, which `--health` queries.
+
+use serde::Serialize;
+
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+/// When we last stored a participant's data, since we started
+#[derive(Debug, Default)]
+pub struct LastWrite(AtomicI64);
+
+impl LastWrite {
+    pub fn record(&self, timestamp: i64) {
+        self.0.fetch_max(timestamp, Ordering::Relaxed);
+    }
+
+    /// The timestamp of the last write, if any
+    pub fn get(&self) -> Option<i64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DatabaseHealth {
+    pub reachable: bool,
+    /// Why we could not reach it
+    pub error: Option<String>,
+    /// The timestamp of the last time we stored a participant's data, if we did since we started
+    pub last_write: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListenerHealth {
+    pub address: SocketAddr,
+    /// Whether we accept new connections, which we stop doing when shutting down
+    pub accepting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    /// Whether we can serve the participants
+    pub healthy: bool,
+    pub database: DatabaseHealth,
+    pub listener: ListenerHealth,
+}
+
+impl HealthReport {
+    pub fn new(database: DatabaseHealth, listener: ListenerHealth) -> HealthReport {
+        HealthReport {
+            healthy: database.reachable && listener.accepting,
+            database,
+            listener,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatabaseHealth, HealthReport, LastWrite, ListenerHealth};
+
+    #[test]
+    fn health_report() {
+        let last_write = LastWrite::default();
+        assert_eq!(last_write.get(), None);
+        last_write.record(1_700_000_100);
+        // Concurrent writes may be recorded out of order
+        last_write.record(1_700_000_000);
+        assert_eq!(last_write.get(), Some(1_700_000_100));
+
+        let database = DatabaseHealth {
+            reachable: true,
+            error: None,
+            last_write: last_write.get(),
+        };
+        let listener = ListenerHealth {
+            address: "127.0.0.1:8383".parse().unwrap(),
+            accepting: true,
+        };
+        assert!(HealthReport::new(database.clone(), listener.clone()).healthy);
+
+        let shutting_down = ListenerHealth {
+            accepting: false,
+            ..listener.clone()
+        };
+        assert!(!HealthReport::new(database, shutting_down).healthy);
+
+        let unreachable = DatabaseHealth {
+            reachable: false,
+            error: Some("connection refused".to_string()),
+            last_write: None,
+        };
+        let report = HealthReport::new(unreachable, listener);
+        assert!(!report.healthy);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap()["listener"]["address"],
+            "127.0.0.1:8383"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+// Our Noise static key, which participants authenticate us with. It is generated on first run
+// and stored in the data directory, either as is or encrypted with a passphrase. Under systemd
+// it may instead be passed as an (encrypted) credential named `noise_secret`, which systemd
+// decrypts for us.
+//
+// Rotating the key keeps the previous one along with the time it retires at. Until then, the
+// participants which were not yet given the new public key may keep connecting with the
+// previous one on its own address: a Noise handshake only succeeds with the key the initiator
+// expects, and we can't tell which one it is before trying.
+
+use crate::backup::derive_key;
+use revault_net::{
+    noise::{PublicKey as NoisePubKey, SecretKey as NoisePrivKey},
+    sodiumoxide::crypto::{box_, pwhash::argon2id13, scalarmult::curve25519, secretbox},
+};
+
+use std::{
+    convert::TryInto,
+    env, fmt, fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+// Identifies an encrypted key file, and the version of its format. Plain key files are the
+// 32 bytes of the key.
+const KEY_MAGIC: &[u8; 8] = b"RVCONKY1";
+
+// The name of the systemd credential holding the key
+const KEY_CREDENTIAL: &str = "noise_secret";
+
+/// The environment variable holding the passphrase our key is encrypted with, if it's not to be
+/// asked for
+pub const KEY_PASSPHRASE_ENV: &str = "COORDINATORD_KEY_PASSPHRASE";
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct KeyError(pub String);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Noise key error: {}", self.0)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// The public part of this key, which the participants are configured with
+pub fn public_key(secret: &NoisePrivKey) -> NoisePubKey {
+    NoisePubKey(curve25519::scalarmult_base(&curve25519::Scalar(secret.0)).0)
+}
+
+/// Whether the content of this key file needs a passphrase to be read
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(KEY_MAGIC)
+}
+
+/// The content of a key file for this key, encrypted if a passphrase is given. An encrypted
+/// file is laid out as `magic | salt | nonce | ciphertext`.
+pub fn encode_key(secret: &NoisePrivKey, passphrase: Option<&[u8]>) -> Result<Vec<u8>, KeyError> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => return Ok(secret.0.to_vec()),
+    };
+
+    let salt = argon2id13::gen_salt();
+    let key = derive_key(passphrase, &salt).map_err(|e| KeyError(e.0))?;
+    let nonce = secretbox::gen_nonce();
+
+    let mut content = KEY_MAGIC.to_vec();
+    content.extend_from_slice(&salt.0);
+    content.extend_from_slice(&nonce.0);
+    content.extend_from_slice(&secretbox::seal(&secret.0, &nonce, &key));
+    Ok(content)
+}
+
+/// Get the key out of the content of a key file
+pub fn decode_key(content: &[u8], passphrase: Option<&[u8]>) -> Result<NoisePrivKey, KeyError> {
+    let plaintext = if is_encrypted(content) {
+        let passphrase = passphrase
+            .ok_or_else(|| KeyError("The key is encrypted, a passphrase is needed".to_string()))?;
+        let salt_start = KEY_MAGIC.len();
+        let nonce_start = salt_start + argon2id13::SALTBYTES;
+        let ciphertext_start = nonce_start + secretbox::NONCEBYTES;
+        if content.len() < ciphertext_start {
+            return Err(KeyError("Truncated key file".to_string()));
+        }
+
+        let salt = argon2id13::Salt::from_slice(&content[salt_start..nonce_start])
+            .expect("Sliced to the right size");
+        let nonce = secretbox::Nonce::from_slice(&content[nonce_start..ciphertext_start])
+            .expect("Sliced to the right size");
+        let key = derive_key(passphrase, &salt).map_err(|e| KeyError(e.0))?;
+        secretbox::open(&content[ciphertext_start..], &nonce, &key)
+            .map_err(|_| KeyError("Invalid passphrase or corrupted key file".to_string()))?
+    } else {
+        content.to_vec()
+    };
+
+    let secret: [u8; box_::SECRETKEYBYTES] = plaintext
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeyError(format!("Invalid key size: {} bytes", plaintext.len())))?;
+    if secret == [0; box_::SECRETKEYBYTES] {
+        return Err(KeyError("Invalid all-zero key".to_string()));
+    }
+    Ok(NoisePrivKey(secret))
+}
+
+// Never overwrite a key, we may be called on the wrong directory. Key files are read-only.
+fn write_new_key_file(path: &Path, content: &[u8]) -> Result<(), KeyError> {
+    let mut fd = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+        .map_err(|e| KeyError(format!("Creating '{:?}': {}", path, e)))?;
+    fd.write_all(content)
+        .map_err(|e| KeyError(format!("Writing to '{:?}': {}", path, e)))
+}
+
+fn read_key_file(path: &Path) -> Result<Option<Vec<u8>>, KeyError> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(KeyError(format!("Reading '{:?}': {}", path, e))),
+    }
+}
+
+/// Whether the key in this file, if any, is encrypted
+pub fn key_file_encrypted(secret_file: &Path) -> Result<bool, KeyError> {
+    Ok(read_key_file(secret_file)?.map_or(false, |content| is_encrypted(&content)))
+}
+
+/// The key systemd passed us as a credential, if any
+pub fn credential_key() -> Result<Option<NoisePrivKey>, KeyError> {
+    let credentials_dir = match env::var_os("CREDENTIALS_DIRECTORY") {
+        Some(dir) => PathBuf::from(dir),
+        None => return Ok(None),
+    };
+    read_key_file(&credentials_dir.join(KEY_CREDENTIAL))?
+        .map(|content| decode_key(&content, None))
+        .transpose()
+}
+
+/// Read our key from this file, or generate it (encrypted if a passphrase is given) if there
+/// is none yet. Returns whether it was generated along with the key.
+pub fn load_or_create_key(
+    secret_file: &Path,
+    passphrase: Option<&[u8]>,
+) -> Result<(NoisePrivKey, bool), KeyError> {
+    if let Some(content) = read_key_file(secret_file)? {
+        return Ok((decode_key(&content, passphrase)?, false));
+    }
+
+    let secret = box_::gen_keypair().1;
+    write_new_key_file(secret_file, &encode_key(&secret, passphrase)?)?;
+    Ok((secret, true))
+}
+
+/// The key we used before the last rotation, still accepted until `retire_at`
+#[derive(Debug, Clone)]
+pub struct PreviousKey {
+    pub secret: NoisePrivKey,
+    /// The timestamp after which we don't accept it anymore
+    pub retire_at: i64,
+}
+
+/// The key we used before the last rotation, if any. Its file is laid out as
+/// `retire_at (big-endian i64) | key file content`.
+pub fn read_previous_key(
+    previous_file: &Path,
+    passphrase: Option<&[u8]>,
+) -> Result<Option<PreviousKey>, KeyError> {
+    let content = match read_key_file(previous_file)? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    if content.len() < 8 {
+        return Err(KeyError("Truncated previous key file".to_string()));
+    }
+
+    let retire_at = i64::from_be_bytes(content[..8].try_into().expect("Sliced to 8 bytes"));
+    Ok(Some(PreviousKey {
+        secret: decode_key(&content[8..], passphrase)?,
+        retire_at,
+    }))
+}
+
+/// Our key from this file, generated if there is none yet, along with the one before the last
+/// rotation if it's still around
+pub fn stored_keys(
+    secret_file: &Path,
+    previous_file: &Path,
+    passphrase: Option<&[u8]>,
+) -> Result<(NoisePrivKey, Option<PreviousKey>), KeyError> {
+    let (secret, created) = load_or_create_key(secret_file, passphrase)?;
+    if created {
+        log::info!(
+            "No Noise private key at '{:?}', generated a new one",
+            secret_file
+        );
+    }
+    let previous_key = read_previous_key(previous_file, passphrase)?;
+
+    Ok((secret, previous_key))
+}
+
+/// Replace our key with a new one, encrypted if a passphrase is given, and keep the current one
+/// as the previous key until `retire_at`. This replaces the previous key, if any. Returns the
+/// new key.
+pub fn rotate_key(
+    secret_file: &Path,
+    previous_file: &Path,
+    passphrase: Option<&[u8]>,
+    retire_at: i64,
+) -> Result<NoisePrivKey, KeyError> {
+    let current = read_key_file(secret_file)?
+        .ok_or_else(|| KeyError(format!("No key to rotate at '{:?}'", secret_file)))?;
+    // Make sure we can read it back before retiring it
+    decode_key(&current, passphrase)?;
+
+    // Write both files aside first, so that a failure leaves the current key in place
+    let new_secret = box_::gen_keypair().1;
+    let new_file = secret_file.with_extension("new");
+    let retiring_file = previous_file.with_extension("new");
+    for file in &[&new_file, &retiring_file] {
+        if file.exists() {
+            fs::remove_file(file).map_err(|e| KeyError(format!("Removing '{:?}': {}", file, e)))?;
+        }
+    }
+    let mut previous = retire_at.to_be_bytes().to_vec();
+    previous.extend_from_slice(&current);
+    write_new_key_file(&retiring_file, &previous)?;
+    write_new_key_file(&new_file, &encode_key(&new_secret, passphrase)?)?;
+
+    fs::rename(&retiring_file, previous_file)
+        .map_err(|e| KeyError(format!("Renaming to '{:?}': {}", previous_file, e)))?;
+    fs::rename(&new_file, secret_file)
+        .map_err(|e| KeyError(format!("Renaming to '{:?}': {}", secret_file, e)))?;
+    Ok(new_secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_key, encode_key, is_encrypted, load_or_create_key, public_key, read_previous_key,
+        rotate_key,
+    };
+    use revault_net::sodiumoxide::{self, crypto::box_::gen_keypair};
+
+    use std::fs;
+
+    #[test]
+    fn key_files() {
+        sodiumoxide::init().unwrap();
+        let (pubkey, secret) = gen_keypair();
+        assert_eq!(public_key(&secret), pubkey);
+
+        let plain = encode_key(&secret, None).unwrap();
+        assert_eq!(plain, secret.0.to_vec());
+        assert!(!is_encrypted(&plain));
+        assert_eq!(decode_key(&plain, None).unwrap(), secret);
+        // A passphrase is not needed, but doesn't hurt
+        assert_eq!(decode_key(&plain, Some(b"unused")).unwrap(), secret);
+
+        let encrypted = encode_key(&secret, Some(b"correct horse")).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(
+            decode_key(&encrypted, Some(b"correct horse")).unwrap(),
+            secret
+        );
+        decode_key(&encrypted, None).unwrap_err();
+        decode_key(&encrypted, Some(b"battery staple")).unwrap_err();
+        decode_key(&encrypted[..20], Some(b"correct horse")).unwrap_err();
+        decode_key(&[0; 32], None).unwrap_err();
+        decode_key(&[1; 31], None).unwrap_err();
+
+        let data_dir =
+            std::env::temp_dir().join(format!("revault_coordinatord_keys_{}", std::process::id()));
+        fs::create_dir_all(&data_dir).unwrap();
+        let (secret_file, previous_file) = (
+            data_dir.join("noise_secret"),
+            data_dir.join("noise_secret.previous"),
+        );
+
+        // It's created on first run, then read back
+        let (first, created) = load_or_create_key(&secret_file, Some(b"correct horse")).unwrap();
+        assert!(created);
+        assert_eq!(
+            load_or_create_key(&secret_file, Some(b"correct horse")).unwrap(),
+            (first.clone(), false)
+        );
+        load_or_create_key(&secret_file, None).unwrap_err();
+        assert!(read_previous_key(&previous_file, None).unwrap().is_none());
+
+        // The current key becomes the previous one
+        let second = rotate_key(
+            &secret_file,
+            &previous_file,
+            Some(b"correct horse"),
+            1_700_000_000,
+        )
+        .unwrap();
+        assert_ne!(second, first);
+        assert_eq!(
+            load_or_create_key(&secret_file, Some(b"correct horse")).unwrap(),
+            (second.clone(), false)
+        );
+        let previous = read_previous_key(&previous_file, Some(b"correct horse"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (previous.secret, previous.retire_at),
+            (first, 1_700_000_000)
+        );
+
+        // Not with the wrong passphrase though
+        rotate_key(&secret_file, &previous_file, Some(b"battery staple"), 0).unwrap_err();
+        assert_eq!(
+            load_or_create_key(&secret_file, Some(b"correct horse")).unwrap(),
+            (second, false)
+        );
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
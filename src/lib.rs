@@ -0,0 +1,62 @@
+#[cfg(feature = "daemon")]
+mod agents;
+pub mod backup;
+#[cfg(feature = "daemon")]
+mod bandwidth;
+#[cfg(feature = "daemon")]
+mod bans;
+#[cfg(feature = "daemon")]
+mod capture;
+pub mod catalog;
+pub mod config;
+#[cfg(feature = "daemon")]
+mod control;
+pub mod coordinatord;
+#[cfg(feature = "daemon")]
+mod counters;
+pub mod crash;
+#[cfg(feature = "daemon")]
+mod daemon;
+#[cfg(feature = "daemon")]
+pub mod datadir;
+pub mod db;
+#[cfg(feature = "daemon")]
+mod errors;
+#[cfg(feature = "daemon")]
+mod events;
+#[cfg(feature = "daemon")]
+mod health;
+pub mod keys;
+#[cfg(feature = "daemon")]
+mod limits;
+pub mod logging;
+#[cfg(feature = "daemon")]
+mod loopback;
+pub mod messages;
+#[cfg(feature = "daemon")]
+mod offsite;
+#[cfg(feature = "daemon")]
+mod peers;
+#[cfg(feature = "daemon")]
+mod policy;
+#[cfg(feature = "daemon")]
+mod processing;
+#[cfg(feature = "daemon")]
+mod ratelimit;
+pub mod redact;
+#[cfg(feature = "daemon")]
+mod relay;
+#[cfg(feature = "daemon")]
+mod sessions;
+#[cfg(feature = "daemon")]
+mod supervisor;
+#[cfg(feature = "daemon")]
+mod systemd;
+#[cfg(feature = "daemon")]
+mod tor;
+pub mod vectors;
+
+#[cfg(feature = "daemon")]
+pub use daemon::{Builder, Coordinator, ShutdownHandle};
+#[cfg(feature = "daemon")]
+pub use loopback::{LoopbackClient, LoopbackConnector, LoopbackError};
@@ -0,0 +1,198 @@
+// How much we take on at once: the connections we keep open, whatever the roles of the peers,
+// and the messages we process concurrently, as each of them holds a database connection for the
+// time of its queries. A connection over its limit is refused, while a message over its limit
+// waits for one being processed to complete: its deadline, if any, keeps running meanwhile.
+
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// A connection or a message, counted against its limit until dropped
+#[derive(Debug)]
+pub struct Slot(Option<OwnedSemaphorePermit>);
+
+#[derive(Debug)]
+struct Limit {
+    max: u32,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Limit {
+    fn new(max: u32) -> Limit {
+        Limit {
+            max,
+            semaphore: Arc::new(Semaphore::new(max as usize)),
+        }
+    }
+
+    fn usage(&self) -> LimitUsage {
+        LimitUsage {
+            in_use: self.max - self.semaphore.available_permits() as u32,
+            max: self.max,
+        }
+    }
+}
+
+/// How many slots of a limit are taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LimitUsage {
+    pub in_use: u32,
+    pub max: u32,
+}
+
+/// How close we are to the limits, and how often we hit them since we started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LimitsUsage {
+    /// None if not limited
+    pub connections: Option<LimitUsage>,
+    pub messages: Option<LimitUsage>,
+    pub refused_connections: u64,
+    pub delayed_messages: u64,
+}
+
+impl LimitsUsage {
+    /// What we had to refuse or delay since this previous usage, or None if nothing
+    pub fn saturation_since(&self, previous: &LimitsUsage) -> Option<String> {
+        let refused = self.refused_connections - previous.refused_connections;
+        let delayed = self.delayed_messages - previous.delayed_messages;
+        if refused == 0 && delayed == 0 {
+            return None;
+        }
+
+        Some(format!(
+            "refused {} connection(s), delayed {} message(s)",
+            refused, delayed
+        ))
+    }
+}
+
+/// The connections and messages being handled, and how many we accept
+#[derive(Debug)]
+pub struct Limits {
+    connections: Option<Limit>,
+    messages: Option<Limit>,
+    refused_connections: AtomicU64,
+    delayed_messages: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(max_connections: Option<u32>, max_concurrent_messages: Option<u32>) -> Limits {
+        Limits {
+            connections: max_connections.map(Limit::new),
+            messages: max_concurrent_messages.map(Limit::new),
+            refused_connections: AtomicU64::new(0),
+            delayed_messages: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether any limit is set
+    pub fn is_limited(&self) -> bool {
+        self.connections.is_some() || self.messages.is_some()
+    }
+
+    /// Take a slot for a new connection, unless as many as we accept are already established
+    pub fn connection_slot(&self) -> Option<Slot> {
+        let limit = match self.connections {
+            Some(ref limit) => limit,
+            None => return Some(Slot(None)),
+        };
+        match limit.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(Slot(Some(permit))),
+            Err(_) => {
+                self.refused_connections.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Take a slot for processing a message, waiting for one to be freed if need be. Returns
+    /// whether we had to wait along with the slot.
+    pub async fn message_slot(&self) -> (Slot, bool) {
+        let limit = match self.messages {
+            Some(ref limit) => limit,
+            None => return (Slot(None), false),
+        };
+        if let Ok(permit) = limit.semaphore.clone().try_acquire_owned() {
+            return (Slot(Some(permit)), false);
+        }
+
+        self.delayed_messages.fetch_add(1, Ordering::Relaxed);
+        let permit = limit
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("We never close the semaphore");
+        (Slot(Some(permit)), true)
+    }
+
+    pub fn usage(&self) -> LimitsUsage {
+        LimitsUsage {
+            connections: self.connections.as_ref().map(Limit::usage),
+            messages: self.messages.as_ref().map(Limit::usage),
+            refused_connections: self.refused_connections.load(Ordering::Relaxed),
+            delayed_messages: self.delayed_messages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitUsage, Limits};
+
+    use std::time::Duration;
+    use tokio::{runtime::Builder as RuntimeBuilder, time::timeout};
+
+    #[test]
+    fn limits() {
+        let rt = RuntimeBuilder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let unlimited = Limits::new(None, None);
+            assert!(!unlimited.is_limited());
+            let _slots: Vec<_> = (0..100)
+                .map(|_| unlimited.connection_slot().unwrap())
+                .collect();
+            assert!(!unlimited.message_slot().await.1);
+            assert_eq!(unlimited.usage().connections, None);
+
+            let limits = Limits::new(Some(2), Some(1));
+            assert!(limits.is_limited());
+            let first = limits.connection_slot().unwrap();
+            let _second = limits.connection_slot().unwrap();
+            assert!(limits.connection_slot().is_none());
+            assert_eq!(
+                limits.usage().connections,
+                Some(LimitUsage { in_use: 2, max: 2 })
+            );
+            // A connection ending frees its slot
+            drop(first);
+            let _third = limits.connection_slot().unwrap();
+
+            let before = limits.usage();
+            assert_eq!(before.refused_connections, 1);
+            let (slot, delayed) = limits.message_slot().await;
+            assert!(!delayed);
+            // The next message waits for the first one to be processed
+            timeout(Duration::from_millis(50), limits.message_slot())
+                .await
+                .unwrap_err();
+            drop(slot);
+            assert!(limits.message_slot().await.1);
+
+            let after = limits.usage();
+            assert_eq!(after.delayed_messages, 2);
+            assert_eq!(
+                after.saturation_since(&before).unwrap(),
+                "refused 0 connection(s), delayed 2 message(s)"
+            );
+            assert_eq!(after.saturation_since(&after), None);
+        });
+    }
+}
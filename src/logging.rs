@@ -0,0 +1,146 @@
+// The context our log lines are tagged with, for logs shipped to a central store. Each
+// connection handler runs with the id, key and roles of its peer, and the message it is
+// processing, so that the lines logged while doing so can be found without parsing them.
+
+#[cfg(feature = "daemon")]
+use crate::sessions::Role;
+#[cfg(feature = "daemon")]
+use revault_net::{bitcoin::hashes::hex::ToHex, noise::PublicKey as NoisePubKey};
+
+#[cfg(feature = "daemon")]
+use std::{cell::RefCell, future::Future};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// How to write the log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per message
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[cfg(feature = "daemon")]
+#[derive(Debug)]
+struct ConnectionContext {
+    conn_id: u64,
+    peer: NoisePubKey,
+    roles: &'static [Role],
+    // The trace id and name of the message being processed, if we know about it
+    message: RefCell<Option<(String, Option<&'static str>)>>,
+}
+
+#[cfg(feature = "daemon")]
+tokio::task_local! {
+    static CONNECTION: ConnectionContext;
+}
+
+/// Run this connection handler with its context
+#[cfg(feature = "daemon")]
+pub(crate) async fn with_connection<F: Future>(
+    conn_id: u64,
+    peer: NoisePubKey,
+    roles: &'static [Role],
+    handler: F,
+) -> F::Output {
+    let context = ConnectionContext {
+        conn_id,
+        peer,
+        roles,
+        message: RefCell::new(None),
+    };
+    CONNECTION.scope(context, handler).await
+}
+
+/// Tag the next lines of this connection with the message we are now processing
+#[cfg(feature = "daemon")]
+pub(crate) fn set_message(trace_id: &str, name: Option<&'static str>) {
+    let _ = CONNECTION.try_with(|c| *c.message.borrow_mut() = Some((trace_id.to_string(), name)));
+}
+
+/// The line to log for this record, its message being already scrubbed of secrets. Lines
+/// logged outside of a connection handler have no connection fields.
+pub fn json_line(record: &log::Record, message: String) -> String {
+    let mut line = Map::new();
+    line.insert("time".to_string(), chrono::Utc::now().to_rfc3339().into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("message".to_string(), message.into());
+
+    #[cfg(feature = "daemon")]
+    let _ = CONNECTION.try_with(|c| {
+        line.insert("conn_id".to_string(), c.conn_id.into());
+        line.insert("peer".to_string(), c.peer.0.to_hex().into());
+        line.insert(
+            "roles".to_string(),
+            c.roles
+                .iter()
+                .map(|role| Value::from(format!("{:?}", role).to_lowercase()))
+                .collect::<Vec<Value>>()
+                .into(),
+        );
+        if let Some((ref trace_id, name)) = *c.message.borrow() {
+            line.insert("trace_id".to_string(), trace_id.as_str().into());
+            if let Some(name) = name {
+                line.insert("message_type".to_string(), name.into());
+            }
+        }
+    });
+
+    Value::Object(line).to_string()
+}
+
+#[cfg(all(test, feature = "daemon"))]
+mod tests {
+    use super::{json_line, set_message, with_connection};
+    use crate::sessions::Role;
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    use serde_json::Value;
+
+    fn logged(message: &str) -> Value {
+        let record = log::Record::builder()
+            .level(log::Level::Warn)
+            .target("revault_coordinatord::daemon")
+            .build();
+        serde_json::from_str(&json_line(&record, message.to_string())).unwrap()
+    }
+
+    #[test]
+    fn connection_context() {
+        let line = logged("Starting");
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "revault_coordinatord::daemon");
+        assert_eq!(line["message"], "Starting");
+        assert!(line.get("conn_id").is_none());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let peer = NoisePubKey([1; 32]);
+        runtime.block_on(with_connection(
+            3,
+            peer,
+            &[Role::Manager, Role::Stakeholder],
+            async {
+                let line = logged("Connected");
+                assert_eq!(line["conn_id"], 3);
+                assert_eq!(line["peer"], "01".repeat(32));
+                assert_eq!(line["roles"], serde_json::json!(["manager", "stakeholder"]));
+                assert!(line.get("trace_id").is_none());
+
+                set_message("3-1", Some("get_sigs"));
+                let line = logged("Processing");
+                assert_eq!(line["trace_id"], "3-1");
+                assert_eq!(line["message_type"], "get_sigs");
+                set_message("3-2", None);
+                let line = logged("Processing");
+                assert_eq!(line["trace_id"], "3-2");
+                assert!(line.get("message_type").is_none());
+            },
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+// A transport between the coordinator and clients running in the same process, for high-volume
+// tests to exercise the processing of the messages without the sockets and the Noise handshake.
+// Clients are authenticated by their key as with the Noise transport, and their messages go
+// through the exact same processing.
+
+use crate::{daemon::Transport, peers::Peers};
+use revault_net::noise::PublicKey as NoisePubKey;
+
+use std::{
+    fmt,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoopbackError {
+    /// The key isn't one of the participants we accept connections from
+    UnknownKey,
+    /// The coordinator is not running anymore
+    NotRunning,
+    /// The coordinator closed our connection
+    Disconnected,
+}
+
+impl fmt::Display for LoopbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownKey => write!(f, "Unknown participant key"),
+            Self::NotRunning => write!(f, "The coordinator is not running"),
+            Self::Disconnected => write!(f, "The coordinator closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for LoopbackError {}
+
+/// The coordinator's end of an in-process connection
+pub(crate) struct LoopbackTransport {
+    remote: NoisePubKey,
+    from_client: Receiver<Vec<u8>>,
+    to_client: Sender<Vec<u8>>,
+}
+
+impl Transport for LoopbackTransport {
+    fn read(&mut self) -> Result<Vec<u8>, String> {
+        // The client dropping its end is a disconnection
+        Ok(self.from_client.recv().unwrap_or_default())
+    }
+
+    fn write(&mut self, msg: &[u8]) -> Result<(), String> {
+        self.to_client
+            .send(msg.to_vec())
+            .map_err(|_| "Loopback client disconnected".to_string())
+    }
+
+    fn remote_static(&self) -> NoisePubKey {
+        self.remote
+    }
+}
+
+/// The client's end of an in-process connection. As with the Noise transport, reading blocks
+/// until we get a response: only read after sending a message which gets one.
+#[derive(Debug)]
+pub struct LoopbackClient {
+    to_coordinator: Sender<Vec<u8>>,
+    from_coordinator: Receiver<Vec<u8>>,
+}
+
+impl LoopbackClient {
+    pub fn write(&self, msg: &[u8]) -> Result<(), LoopbackError> {
+        self.to_coordinator
+            .send(msg.to_vec())
+            .map_err(|_| LoopbackError::Disconnected)
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, LoopbackError> {
+        self.from_coordinator
+            .recv()
+            .map_err(|_| LoopbackError::Disconnected)
+    }
+}
+
+/// Establishes in-process connections with a coordinator
+#[derive(Debug, Clone)]
+pub struct LoopbackConnector {
+    peers: Peers,
+    connections: UnboundedSender<LoopbackTransport>,
+}
+
+impl LoopbackConnector {
+    pub(crate) fn new(
+        peers: Peers,
+        connections: UnboundedSender<LoopbackTransport>,
+    ) -> LoopbackConnector {
+        LoopbackConnector { peers, connections }
+    }
+
+    /// Connect as the participant with this key. Connections made before the coordinator
+    /// runs are served once it does.
+    pub fn connect(&self, pubkey: NoisePubKey) -> Result<LoopbackClient, LoopbackError> {
+        if !self.peers.get().all().contains(&pubkey) {
+            return Err(LoopbackError::UnknownKey);
+        }
+
+        let (to_coordinator, from_client) = channel();
+        let (to_client, from_coordinator) = channel();
+        self.connections
+            .send(LoopbackTransport {
+                remote: pubkey,
+                from_client,
+                to_client,
+            })
+            .map_err(|_| LoopbackError::NotRunning)?;
+
+        Ok(LoopbackClient {
+            to_coordinator,
+            from_coordinator,
+        })
+    }
+}
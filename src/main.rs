@@ -1,69 +1,765 @@
-mod config;
-mod coordinatord;
-mod db;
-mod processing;
-use crate::{
-    config::Config,
+mod cli;
+use crate::cli::{ExitCode, Output};
+use revault_coordinatord::{
+    backup::{create_backup, restore_backup},
+    catalog::explain,
+    config::{config_file_path, Config},
     coordinatord::CoordinatorD,
-    db::maybe_create_db,
-    processing::{
-        process_manager_message, process_stakeholder_message, process_stakeholdermanager_message,
-        process_watchtower_message,
+    crash::{install_panic_handler, LogRing},
+    datadir::DataDirLock,
+    db::{
+        bulk_store_sigs, capacity_report, check_migrations, export_snapshot, fetch_schema_version,
+        fetch_unexpected_txids, import_snapshot, maybe_create_db, prunable_before, prune_before,
+        Snapshot,
     },
+    keys::{
+        credential_key, key_file_encrypted, public_key, read_previous_key, rotate_key, stored_keys,
+        PreviousKey, KEY_PASSPHRASE_ENV,
+    },
+    logging::{json_line, LogFormat},
+    messages::BatchedSig,
+    redact::Redactor,
+    vectors::test_vectors,
+    Builder,
 };
 use revault_net::{
-    bitcoin::hashes::hex::ToHex,
-    noise::{PublicKey as NoisePubKey, SecretKey as NoisePrivKey},
-    sodiumoxide::{self, crypto::scalarmult::curve25519},
-    transport::KKTransport,
+    bitcoin::hashes::{hex::ToHex, sha256, Hash},
+    noise::SecretKey as NoisePrivKey,
+    sodiumoxide,
 };
 
 use std::{
     env, fs,
-    io::{Read, Write},
-    net::TcpListener,
+    io::{BufRead, BufReader, Write},
     os::unix::fs::OpenOptionsExt,
-    path::PathBuf,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
     process,
     str::FromStr,
-    sync::Arc,
+    time::Duration,
 };
 
 use daemonize_simple::Daemonize;
 use tokio::runtime::Builder as RuntimeBuilder;
 
-// No need for complex argument parsing: we only ever accept one, "--conf".
-fn parse_args(args: Vec<String>) -> Option<PathBuf> {
-    if args.len() == 1 {
-        return None;
+/// What we were asked to do
+enum Command {
+    /// Run the coordinator
+    Run,
+    /// Write an encrypted backup bundle of our key and configuration at this path
+    Backup(PathBuf),
+    /// Restore our key and configuration from the backup bundle at this path
+    Restore(PathBuf),
+    /// Print the wire protocol test vectors
+    TestVectors,
+    /// Tell what this code of our error catalog means and how to address it
+    ExplainError(u32),
+    /// Print our Noise public key, generating the key if we don't have one yet
+    GetPubkey,
+    /// Replace our Noise key, accepting the current one for this many more days
+    RotateKey(u64),
+    /// Store the signatures from this JSON file
+    ImportSigs(PathBuf),
+    /// Write a snapshot of the database content at this path
+    ExportSnapshot(PathBuf),
+    /// Fill the (empty) database with the snapshot at this path
+    ImportSnapshot(PathBuf),
+    /// Report how much we store, how fast it grows and how close we are to our limits
+    CapacityReport,
+    /// List the transactions we got signatures for although managers didn't register them
+    UnexpectedTxids,
+    /// Tell which schema migrations we would apply to the database, without applying them
+    CheckDb,
+    /// Tell what reloading the configuration from the file at this path would change
+    PreviewReload(PathBuf),
+    /// Delete the signatures and Spend transactions received before this timestamp, or only
+    /// count them on a dry run
+    PruneBefore(i64, bool),
+    /// Ask the running coordinator whether it can serve the participants
+    Health,
+}
+
+// How long we wait for the running coordinator to answer on its control socket
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "Usage: [--conf <configuration file path>] [--json] \
+                     [--backup <bundle path> | --restore <bundle path> | --test-vectors | \
+                     --explain-error <code> | --getpubkey | --rotate-key <grace days> | \
+                     --import-sigs <signatures file path> | \
+                     --export-snapshot <snapshot path> | --import-snapshot <snapshot path> | \
+                     --capacity-report | --unexpected-txids | --check-db | \
+                     --preview-reload <configuration file path> | --prune-before <YYYY-MM-DD> [--dry-run] | \
+                     --health]";
+
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> PathBuf {
+    args.next().map(PathBuf::from).unwrap_or_else(|| {
+        eprintln!("Missing value for '{}'.", flag);
+        eprintln!("{}", USAGE);
+        process::exit(ExitCode::Usage as i32);
+    })
+}
+
+// The timestamp of the start of this day, UTC
+fn date_value(args: &mut impl Iterator<Item = String>, flag: &str) -> i64 {
+    let value = flag_value(args, flag);
+    let value = value.to_string_lossy();
+    chrono::NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+        .map(|date| date.and_hms(0, 0, 0).timestamp())
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid date '{}' for '{}': {}.", value, flag, e);
+            eprintln!("{}", USAGE);
+            process::exit(ExitCode::Usage as i32);
+        })
+}
+
+fn number_value<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    let value = flag_value(args, flag);
+    let value = value.to_string_lossy();
+    T::from_str(&value).unwrap_or_else(|e| {
+        eprintln!("Invalid number '{}' for '{}': {}.", value, flag, e);
+        eprintln!("{}", USAGE);
+        process::exit(ExitCode::Usage as i32);
+    })
+}
+
+// No need for complex argument parsing: we only ever accept "--conf", "--json" and a couple
+// of one-shot commands.
+fn parse_args(args: Vec<String>) -> (Option<PathBuf>, Command, Output) {
+    let mut conf_file = None;
+    let mut command = Command::Run;
+    let mut output = Output { json: false };
+    let mut dry_run = false;
+
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--conf" => conf_file = Some(flag_value(&mut args, &arg)),
+            "--json" => output.json = true,
+            "--backup" => command = Command::Backup(flag_value(&mut args, &arg)),
+            "--restore" => command = Command::Restore(flag_value(&mut args, &arg)),
+            "--test-vectors" => command = Command::TestVectors,
+            "--explain-error" => command = Command::ExplainError(number_value(&mut args, &arg)),
+            "--getpubkey" => command = Command::GetPubkey,
+            "--rotate-key" => command = Command::RotateKey(number_value(&mut args, &arg)),
+            "--import-sigs" => command = Command::ImportSigs(flag_value(&mut args, &arg)),
+            "--export-snapshot" => command = Command::ExportSnapshot(flag_value(&mut args, &arg)),
+            "--import-snapshot" => command = Command::ImportSnapshot(flag_value(&mut args, &arg)),
+            "--capacity-report" => command = Command::CapacityReport,
+            "--unexpected-txids" => command = Command::UnexpectedTxids,
+            "--check-db" => command = Command::CheckDb,
+            "--preview-reload" => command = Command::PreviewReload(flag_value(&mut args, &arg)),
+            "--prune-before" => command = Command::PruneBefore(date_value(&mut args, &arg), false),
+            "--dry-run" => dry_run = true,
+            "--health" => command = Command::Health,
+            _ => {
+                eprintln!("Unknown argument '{}'.", arg);
+                eprintln!("{}", USAGE);
+                process::exit(ExitCode::Usage as i32);
+            }
+        }
     }
 
-    if args.len() != 3 {
-        eprintln!("Unknown arguments '{:?}'.", args);
-        eprintln!("Only '--conf <configuration file path>' is supported.");
-        process::exit(1);
+    if dry_run {
+        match command {
+            Command::PruneBefore(before, _) => command = Command::PruneBefore(before, true),
+            _ => {
+                eprintln!("'--dry-run' only applies to '--prune-before'.");
+                eprintln!("{}", USAGE);
+                process::exit(ExitCode::Usage as i32);
+            }
+        }
+    }
+
+    (conf_file, command, output)
+}
+
+// The configuration file we were pointed to, or the default one.
+fn conf_file_or_default(conf_file: Option<PathBuf>, output: &Output) -> PathBuf {
+    conf_file.unwrap_or_else(|| {
+        config_file_path().unwrap_or_else(|e| output.fail(ExitCode::Config, &e.to_string()))
+    })
+}
+
+fn read_passphrase(output: &Output, prompt: &str, code: ExitCode) -> String {
+    eprint!("{}", prompt);
+    let mut passphrase = String::new();
+    std::io::stdin()
+        .read_line(&mut passphrase)
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Internal,
+                &format!("Error reading passphrase: {}", e),
+            )
+        });
+
+    let passphrase = passphrase.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if passphrase.is_empty() {
+        output.fail(code, "The passphrase must not be empty.");
+    }
+    passphrase
+}
+
+fn current_thread_runtime(output: &Output) -> tokio::runtime::Runtime {
+    RuntimeBuilder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Internal,
+                &format!("Creating tokio runtime: {}", e),
+            )
+        })
+}
+
+fn backup(
+    coordinatord: &CoordinatorD,
+    redactor: &Redactor,
+    output: &Output,
+    conf_file: Option<PathBuf>,
+    bundle_path: &Path,
+) {
+    let conf_file = conf_file_or_default(conf_file, output);
+
+    let rt = current_thread_runtime(output);
+    let schema_version = rt
+        .block_on(fetch_schema_version(&coordinatord.db()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error fetching the database schema version: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let passphrase = read_passphrase(output, "Backup passphrase: ", ExitCode::Backup);
+    create_backup(
+        coordinatord,
+        &conf_file,
+        schema_version,
+        bundle_path,
+        passphrase.as_bytes(),
+    )
+    .unwrap_or_else(|e| output.fail(ExitCode::Backup, &e.to_string()));
+    output.success(
+        &format!("Wrote backup bundle to '{:?}'.", bundle_path),
+        serde_json::json!({ "bundle_path": bundle_path, "schema_version": schema_version }),
+    );
+}
+
+// Import signatures from a JSON array of 'sig' messages, as for a backfill. They may carry a
+// 'tx_type' as in a 'sigs' batch.
+fn import_sigs(
+    coordinatord: &CoordinatorD,
+    redactor: &Redactor,
+    output: &Output,
+    sigs_path: &Path,
+) {
+    let sigs: Vec<BatchedSig> = fs::read(sigs_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_slice(&content).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::File,
+                &format!("Error reading signatures from '{:?}': {}", sigs_path, e),
+            )
+        });
+
+    let rt = current_thread_runtime(output);
+    let db = coordinatord.db();
+    let import = rt
+        .block_on(async {
+            maybe_create_db(&db).await?;
+            bulk_store_sigs(&db, &sigs, coordinatord.max_stored_bytes)
+                .await
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+        })
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error storing signatures: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let mut message = format!(
+        "Stored {} signatures ({} were already stored, {} were refused).",
+        import.stored,
+        import.already_stored,
+        import.rejected.len()
+    );
+    let mut rejected = Vec::with_capacity(import.rejected.len());
+    for (txid, pubkey, e) in import.rejected.iter() {
+        message.push_str(&format!("\n{} by {}: {}", txid, pubkey, e));
+        rejected.push(serde_json::json!({
+            "txid": txid,
+            "pubkey": pubkey,
+            "error_code": e.error_code(),
+            "reason": e.to_string(),
+        }));
+    }
+    output.success(
+        &message,
+        serde_json::json!({
+            "stored": import.stored,
+            "already_stored": import.already_stored,
+            "rejected": rejected,
+        }),
+    );
+}
+
+// Write a snapshot of the database content, to bootstrap a standby from.
+fn export_snapshot_to(
+    coordinatord: &CoordinatorD,
+    redactor: &Redactor,
+    output: &Output,
+    snapshot_path: &Path,
+) {
+    let rt = current_thread_runtime(output);
+    let snapshot = rt
+        .block_on(export_snapshot(&coordinatord.db()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error taking the database snapshot: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let content = serde_json::to_vec(&snapshot).expect("Snapshots always serialize");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(snapshot_path)
+        .and_then(|mut fd| fd.write_all(&content))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::File,
+                &format!("Error writing snapshot to '{:?}': {}", snapshot_path, e),
+            )
+        });
+    output.success(
+        &format!(
+            "Wrote a snapshot of {} signatures and {} Spend transactions to '{:?}'.",
+            snapshot.signatures.len(),
+            snapshot.spend_txs.len(),
+            snapshot_path
+        ),
+        serde_json::json!({
+            "snapshot_path": snapshot_path,
+            "signatures": snapshot.signatures.len(),
+            "spend_txs": snapshot.spend_txs.len(),
+        }),
+    );
+}
+
+// Fill our (empty) database with the content of a snapshot taken on another coordinator.
+fn import_snapshot_from(
+    coordinatord: &CoordinatorD,
+    redactor: &Redactor,
+    output: &Output,
+    snapshot_path: &Path,
+) {
+    let snapshot: Snapshot = fs::read(snapshot_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_slice(&content).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::File,
+                &format!("Error reading snapshot from '{:?}': {}", snapshot_path, e),
+            )
+        });
+
+    let rt = current_thread_runtime(output);
+    let db = coordinatord.db();
+    rt.block_on(async {
+        maybe_create_db(&db).await?;
+        import_snapshot(&db, &snapshot)
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+    })
+    .unwrap_or_else(|e| {
+        output.fail(
+            ExitCode::Database,
+            &format!(
+                "Error importing the database snapshot: {}",
+                redactor.scrub(&e.to_string())
+            ),
+        )
+    });
+    output.success(
+        &format!(
+            "Imported {} signatures and {} Spend transactions.",
+            snapshot.signatures.len(),
+            snapshot.spend_txs.len()
+        ),
+        serde_json::json!({
+            "signatures": snapshot.signatures.len(),
+            "spend_txs": snapshot.spend_txs.len(),
+        }),
+    );
+}
+
+// Gather the figures for a capacity review, to be read as is or exported as JSON.
+fn report_capacity(coordinatord: &CoordinatorD, redactor: &Redactor, output: &Output) {
+    let rt = current_thread_runtime(output);
+    let report = rt
+        .block_on(capacity_report(
+            &coordinatord.db(),
+            coordinatord.max_stored_bytes,
+        ))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error gathering the capacity report: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let cache_hit_ratio = match report.cache_hit_ratio {
+        Some(ratio) => format!("{:.1}%", ratio * 100.0),
+        None => "unknown".to_string(),
+    };
+    let storage_ceiling = match (report.max_stored_bytes, report.days_to_storage_ceiling) {
+        (Some(max), Some(days)) => format!("{} bytes, reached in {:.0} days", max, days),
+        (Some(max), None) => format!("{} bytes, not growing", max),
+        (None, _) => "none".to_string(),
+    };
+    output.success(
+        &format!(
+            "Signatures: {} ({:.1} per day, {} in {} days)\n\
+             Spend transactions: {} for {} deposit outpoints\n\
+             Acceptance windows: {}\n\
+             Stored data: {} bytes ({:.0} per day, {} in {} days)\n\
+             Database size: {} bytes\n\
+             Database connections: {} out of {}\n\
+             Database cache hit ratio: {}\n\
+             Storage ceiling: {}",
+            report.signatures,
+            report.signatures_per_day,
+            report.projected_signatures,
+            report.projection_days,
+            report.spend_txs,
+            report.spend_outpoints,
+            report.sig_windows,
+            report.stored_bytes,
+            report.stored_bytes_per_day,
+            report.projected_stored_bytes,
+            report.projection_days,
+            report.database_bytes,
+            report.db_connections,
+            report.max_db_connections,
+            cache_hit_ratio,
+            storage_ceiling,
+        ),
+        serde_json::to_value(&report).expect("Reports always serialize"),
+    );
+}
+
+// List the transactions whose first signature was flagged, as they may be mistyped ones.
+fn list_unexpected_txids(coordinatord: &CoordinatorD, redactor: &Redactor, output: &Output) {
+    let rt = current_thread_runtime(output);
+    let unexpected = rt
+        .block_on(fetch_unexpected_txids(&coordinatord.db()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error fetching the unexpected transactions: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let mut message = format!(
+        "{} transaction(s) got signatures without being registered.",
+        unexpected.len()
+    );
+    for txid in unexpected.iter() {
+        message.push_str(&format!(
+            "\n{} (first signed by {} at {})",
+            txid.txid, txid.first_pubkey, txid.first_seen_at
+        ));
+    }
+    output.success(
+        &message,
+        serde_json::json!({ "unexpected_txids": unexpected }),
+    );
+}
+
+// Tell where the database schema stands, and which migrations we would apply at startup.
+fn check_db(coordinatord: &CoordinatorD, redactor: &Redactor, output: &Output) {
+    let rt = current_thread_runtime(output);
+    let plan = rt
+        .block_on(check_migrations(&coordinatord.db()))
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error checking the database schema: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    let current_version = match plan.current_version {
+        Some(version) => format!("at version {}", version),
+        None => "not versioned".to_string(),
+    };
+    let mut message = if plan.pending.is_empty() {
+        format!(
+            "The database schema is up to date (version {}).",
+            plan.latest_version
+        )
+    } else {
+        format!(
+            "The database schema is {}, {} migration(s) would be applied at startup:",
+            current_version,
+            plan.pending.len()
+        )
+    };
+    for migration in plan.pending.iter() {
+        message.push_str(&format!(
+            "\n{}: {}",
+            migration.version, migration.description
+        ));
+    }
+    output.success(
+        &message,
+        serde_json::to_value(&plan).expect("Plans always serialize"),
+    );
+}
+
+// Delete the data received before this date, whatever the retention period we are configured
+// with. On a dry run, only tell how much would be.
+fn prune(
+    coordinatord: &CoordinatorD,
+    redactor: &Redactor,
+    output: &Output,
+    before: i64,
+    dry_run: bool,
+) {
+    let rt = current_thread_runtime(output);
+    let db = coordinatord.db();
+    let pruned = rt
+        .block_on(async {
+            maybe_create_db(&db).await?;
+            if dry_run {
+                prunable_before(&db, before).await
+            } else {
+                prune_before(&db, before).await
+            }
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)
+        })
+        .unwrap_or_else(|e| {
+            output.fail(
+                ExitCode::Database,
+                &format!(
+                    "Error pruning the database: {}",
+                    redactor.scrub(&e.to_string())
+                ),
+            )
+        });
+
+    output.success(
+        &format!(
+            "{} {} signature(s) and {} Spend transaction(s).",
+            if dry_run { "Would delete" } else { "Deleted" },
+            pruned.signatures,
+            pruned.spend_txs
+        ),
+        serde_json::to_value(&pruned).expect("Prune counts always serialize"),
+    );
+}
+
+// Tell which settings reloading the configuration from this file would change, and which of
+// them need a restart, without applying anything.
+fn preview_reload(config: &Config, output: &Output, new_conf_file: &Path) {
+    let new_config = Config::from_file(Some(new_conf_file.to_path_buf())).unwrap_or_else(|e| {
+        output.fail(
+            ExitCode::Config,
+            &format!("Error parsing the new config: {}", e),
+        )
+    });
+    // The values checked at startup but not when parsing
+    if let Some(ref level) = new_config.log_level {
+        log::LevelFilter::from_str(level).unwrap_or_else(|e| {
+            output.fail(ExitCode::Config, &format!("Invalid log level: {}", e))
+        });
+    }
+    if let Err(e) = new_config.postgres_config() {
+        output.fail(ExitCode::Config, &e.to_string());
+    }
+
+    let changes = config.changes(&new_config);
+    let mut message = if changes.is_empty() {
+        "No setting would change.".to_string()
+    } else {
+        format!("{} setting(s) would change:", changes.len())
+    };
+    for change in changes.iter() {
+        message.push_str(&format!(
+            "\n{} ({})",
+            change.setting,
+            if change.reloadable {
+                "applied on SIGHUP"
+            } else {
+                "needs a restart"
+            }
+        ));
     }
+    output.success(&message, serde_json::json!({ "changes": changes }));
+}
 
-    Some(PathBuf::from(args[2].to_owned()))
+// Tell what this error code means, as reported to a participant, on the control socket or by
+// a one-shot command.
+fn explain_error(output: &Output, code: u32) {
+    let entry = explain(code)
+        .unwrap_or_else(|| output.fail(ExitCode::Usage, &format!("Unknown error code {}.", code)));
+
+    output.success(
+        &format!(
+            "{} ({}): {}\n{}",
+            entry.code, entry.name, entry.meaning, entry.remediation
+        ),
+        serde_json::to_value(entry).expect("Catalog entries always serialize"),
+    );
+}
+
+// Send a request without parameters on the control socket at this path, and get its result
+fn query_control(socket_file: &Path, method: &str) -> Result<serde_json::Value, String> {
+    let stream = UnixStream::connect(socket_file).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(CONTROL_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let request = serde_json::json!({ "jsonrpc": "2.0", "id": 0, "method": method });
+    (&stream)
+        .write_all(format!("{}\n", request).as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    let mut response: serde_json::Value =
+        serde_json::from_str(&line).map_err(|e| format!("Invalid response '{}': {}", line, e))?;
+    if let Some(error) = response.get("error") {
+        return Err(error["message"]
+            .as_str()
+            .unwrap_or("Unknown error")
+            .to_string());
+    }
+    Ok(response["result"].take())
+}
+
+// Ask the running coordinator whether it can reach its database and accepts connections, for
+// liveness and readiness probes. Not getting an answer is as unhealthy as a bad one.
+fn health(coordinatord: &CoordinatorD, output: &Output) {
+    if !coordinatord.control_socket {
+        output.fail(
+            ExitCode::Config,
+            "The control socket is disabled ('control_socket'), can't query the coordinator.",
+        );
+    }
+    let socket_file = coordinatord.control_socket_file();
+    let report = query_control(&socket_file, "health").unwrap_or_else(|e| {
+        output.fail(
+            ExitCode::Unhealthy,
+            &format!("Querying the coordinator on '{:?}': {}", socket_file, e),
+        )
+    });
+
+    let (database, listener) = (&report["database"], &report["listener"]);
+    if report["healthy"] != true {
+        let mut reasons = Vec::new();
+        if database["reachable"] != true {
+            reasons.push(format!(
+                "can't reach the database ({})",
+                database["error"].as_str().unwrap_or("unknown error")
+            ));
+        }
+        if listener["accepting"] != true {
+            reasons.push("not accepting connections anymore, shutting down".to_string());
+        }
+        output.fail(
+            ExitCode::Unhealthy,
+            &format!("The coordinator is unhealthy: {}.", reasons.join(", ")),
+        );
+    }
+
+    let last_write = match database["last_write"].as_i64() {
+        Some(timestamp) => format!(
+            "{} UTC",
+            chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+        ),
+        None => "none since it started".to_string(),
+    };
+    output.success(
+        &format!(
+            "The coordinator is healthy: it reaches its database and accepts connections on {}. \
+             Last data stored: {}.",
+            listener["address"], last_write
+        ),
+        report,
+    );
+}
+
+fn restore(output: &Output, conf_file: Option<PathBuf>, bundle_path: &Path) {
+    let conf_file = conf_file_or_default(conf_file, output);
+
+    let passphrase = read_passphrase(output, "Backup passphrase: ", ExitCode::Backup);
+    let schema_version = restore_backup(bundle_path, &conf_file, passphrase.as_bytes())
+        .unwrap_or_else(|e| output.fail(ExitCode::Backup, &e.to_string()));
+    let schema_message = match schema_version {
+        Some(version) => format!("The database schema was at version {}.", version),
+        None => "No database schema version was set at backup time.".to_string(),
+    };
+    output.success(
+        &format!(
+            "Restored the configuration to '{:?}' and the Noise key to its data directory.\n{}",
+            conf_file, schema_message
+        ),
+        serde_json::json!({ "conf_file": conf_file, "schema_version": schema_version }),
+    );
 }
 
 // This creates the log file automagically if it doesn't exist, and logs on stdout
-// if None is given
+// if None is given. Credentials are scrubbed from every message. The last lines are also
+// kept in the ring, for the crash diagnostics.
 fn setup_logger(
     log_file: Option<&str>,
     log_level: log::LevelFilter,
+    log_format: LogFormat,
+    redactor: Redactor,
+    log_ring: LogRing,
 ) -> Result<(), fern::InitError> {
     let dispatcher = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
+        .format(move |out, message, record| {
+            let message = redactor.scrub(&message.to_string());
+            match log_format {
+                LogFormat::Text => out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    message
+                )),
+                LogFormat::Json => out.finish(format_args!("{}", json_line(record, message))),
+            }
         })
-        .level(log_level);
+        .level(log_level)
+        .chain(Box::new(log_ring) as Box<dyn log::Log>);
 
     if let Some(log_file) = log_file {
         dispatcher.chain(fern::log_file(log_file)?).apply()?;
@@ -74,196 +770,115 @@ fn setup_logger(
     Ok(())
 }
 
-// The communication keys are (for now) hot, so we just create it ourselves on first run.
-fn read_or_create_noise_key(secret_file: PathBuf) -> NoisePrivKey {
-    let mut noise_secret = NoisePrivKey([0; 32]);
-
-    if !secret_file.as_path().exists() {
-        log::info!(
-            "No Noise private key at '{:?}', generating a new one",
-            secret_file
-        );
-        noise_secret = sodiumoxide::crypto::box_::gen_keypair().1;
-
-        let mut options = fs::OpenOptions::new();
-        // We create it in read-only but open it in write only.
-        options.write(true).create_new(true).mode(0o400);
-        let mut fd = options.open(secret_file.clone()).unwrap_or_else(|e| {
-            eprintln!("Opening Noise private key file: {}", e);
-            process::exit(1);
-        });
-        fd.write_all(&noise_secret.0).unwrap_or_else(|e| {
-            eprintln!("Writing Noise private key to '{:?}': '{}'", secret_file, e);
-            process::exit(1);
-        });
-    } else {
-        let mut noise_secret_fd = fs::File::open(secret_file).unwrap_or_else(|e| {
-            eprintln!("Error opening Noise static private key file: '{}'", e);
-            process::exit(1);
-        });
-        noise_secret_fd
-            .read_exact(&mut noise_secret.0)
-            .unwrap_or_else(|e| {
-                eprintln!("Error reading Noise static private key file: '{}'", e);
-                process::exit(1);
-            });
+// The passphrase our Noise key is encrypted with, or is to be encrypted with once generated:
+// from the environment, or else asked for if the key is already encrypted. Without one, a new
+// key is stored as is.
+fn key_passphrase(coordinatord: &CoordinatorD, output: &Output) -> Option<String> {
+    if let Some(passphrase) = env::var_os(KEY_PASSPHRASE_ENV) {
+        return Some(passphrase.to_string_lossy().to_string());
     }
+    match key_file_encrypted(&coordinatord.secret_file()) {
+        Ok(true) => Some(read_passphrase(
+            output,
+            "Noise key passphrase: ",
+            ExitCode::Key,
+        )),
+        Ok(false) => None,
+        Err(e) => output.fail(ExitCode::Key, &e.to_string()),
+    }
+}
 
-    // TODO: have a decent memory management and mlock() the key
+// Our Noise static key, along with the one before the last rotation if it's still around. We
+// get it from systemd if it passes it as a credential, or else from our data directory, where
+// we create it on first run.
+// TODO: have a decent memory management and mlock() the key
+fn noise_keys(coordinatord: &CoordinatorD, output: &Output) -> (NoisePrivKey, Option<PreviousKey>) {
+    let credential =
+        credential_key().unwrap_or_else(|e| output.fail(ExitCode::Key, &e.to_string()));
+    if let Some(noise_secret) = credential {
+        return (noise_secret, None);
+    }
 
-    assert!(noise_secret.0 != [0; 32]);
-    noise_secret
+    let passphrase = key_passphrase(coordinatord, output);
+    let passphrase = passphrase.as_ref().map(|p| p.as_bytes());
+    stored_keys(
+        &coordinatord.secret_file(),
+        &coordinatord.previous_secret_file(),
+        passphrase,
+    )
+    .unwrap_or_else(|e| output.fail(ExitCode::Key, &e.to_string()))
 }
 
-#[derive(Debug)]
-enum MessageSender {
-    Manager,
-    StakeHolder,
-    ManagerStakeholder,
-    WatchTower,
-}
+// Print our Noise public key, for the participants to be configured with.
+fn get_pubkey(coordinatord: &CoordinatorD, output: &Output) {
+    let (noise_secret, previous_key) = noise_keys(coordinatord, output);
+    let noise_pubkey = public_key(&noise_secret).0.to_hex();
 
-// Process all messages from this connection
-async fn connection_handler(
-    mut stream: KKTransport,
-    msg_sender: MessageSender,
-    pg_config: Arc<tokio_postgres::Config>,
-) {
-    loop {
-        match stream.read() {
-            Ok(msg) => {
-                // read() is nice: on non-fatal error (basically connection
-                // interruption) it'll just signal it by returning an empty
-                // buffer.
-                if msg.is_empty() {
-                    log::trace!("Empty message, connection was ended by peer.");
-                    return;
-                }
-                log::trace!(
-                    "Got message '{}' (raw: '{:x?}') from {:?}",
-                    String::from_utf8_lossy(&msg),
-                    msg,
-                    msg_sender
-                );
-
-                let response = match msg_sender {
-                    MessageSender::Manager => process_manager_message(&*pg_config, msg).await,
-                    MessageSender::StakeHolder => {
-                        process_stakeholder_message(&*pg_config, msg).await
-                    }
-                    MessageSender::WatchTower => process_watchtower_message(&*pg_config, msg).await,
-                    MessageSender::ManagerStakeholder => {
-                        process_stakeholdermanager_message(&*pg_config, msg).await
-                    }
-                };
-
-                // We close the connection on processing or response-writing
-                // error.
-                match response {
-                    Ok(Some(response)) => {
-                        log::trace!("Responding with '{}'", String::from_utf8_lossy(&response));
-
-                        if let Err(e) = stream.write(&response) {
-                            log::error!(
-                                "Writing response '{:x?}' to '{:x?}': '{}'",
-                                response,
-                                stream.remote_static(),
-                                e
-                            );
-                            return;
-                        }
-                    }
-                    Ok(None) => {}
-                    Err(e) => {
-                        log::error!(
-                            "Processing message from '{:x?}': '{}'",
-                            stream.remote_static(),
-                            e
-                        );
-                        return;
-                    }
-                }
-            }
-            Err(e) => {
-                log::trace!(
-                    "Reading error from '{:x?}': '{}'",
-                    stream.remote_static(),
-                    e
-                );
-                return;
-            }
-        }
+    let mut message = noise_pubkey.clone();
+    if let Some(ref previous_key) = previous_key {
+        message.push_str(&format!(
+            "\nPrevious key: {} (retiring at {} UTC)",
+            public_key(&previous_key.secret).0.to_hex(),
+            chrono::NaiveDateTime::from_timestamp(previous_key.retire_at, 0)
+        ));
     }
+    output.success(
+        &message,
+        serde_json::json!({
+            "noise_pubkey": noise_pubkey,
+            "previous_noise_pubkey": previous_key
+                .as_ref()
+                .map(|key| public_key(&key.secret).0.to_hex()),
+            "previous_retire_at": previous_key.as_ref().map(|key| key.retire_at),
+        }),
+    );
 }
 
-async fn tokio_main(
-    coordinatord: CoordinatorD,
-    noise_secret: NoisePrivKey,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // We use PostgreSQL for storing the signatures and spend transactions. That may
-    // seem overkill for now, but this server is expected to grow and we'll probably
-    // use more Postgre feature soon. For one, Postgre makes it easy to setup database
-    // replication.
-    maybe_create_db(&coordinatord.postgres_config).await?;
-    let postgres_config = Arc::new(coordinatord.postgres_config);
-
-    // Who we are accepting connections from. Note that we of course trust them and
-    // therefore don't make a big deal of DOS protection.
-    let managers_keys = coordinatord.managers_keys;
-    let stakeholders_keys = coordinatord.stakeholders_keys;
-    let watchtowers_keys = coordinatord.watchtowers_keys;
-    let client_pubkeys: Vec<NoisePubKey> = managers_keys
-        .clone()
-        .into_iter()
-        .chain(stakeholders_keys.clone().into_iter())
-        .chain(watchtowers_keys.clone().into_iter())
-        .collect();
-
-    // FIXME: implement a tokio feature upstream and use Tokio's TcpListener
-    let listener = TcpListener::bind(coordinatord.listen)?;
-
-    loop {
-        // This does the Noise KK handshake..
-        let kk_stream =
-            revault_net::transport::KKTransport::accept(&listener, &noise_secret, &client_pubkeys);
-
-        match kk_stream {
-            // .. So from here we are automagically using an AEAD stream
-            Ok(stream) => {
-                // Now figure out who's talking to us
-                let their_pubkey = stream.remote_static();
-                let msg_sender = match (
-                    managers_keys.contains(&their_pubkey),
-                    stakeholders_keys.contains(&their_pubkey),
-                    watchtowers_keys.contains(&their_pubkey),
-                ) {
-                    (_, _, true) => MessageSender::WatchTower,
-                    (m, s, false) => match (m, s) {
-                        (true, true) => MessageSender::ManagerStakeholder,
-                        (true, false) => MessageSender::Manager,
-                        (false, true) => MessageSender::StakeHolder,
-                        (false, false) => {
-                            unreachable!("An unknown key was able to perform the handshake?")
-                        }
-                    },
-                };
-
-                let pg_config = postgres_config.clone();
-                log::trace!(
-                    "Got a new connection from a {:?} with key {:x?}",
-                    msg_sender,
-                    their_pubkey.0.to_hex()
-                );
-
-                tokio::spawn(
-                    async move { connection_handler(stream, msg_sender, pg_config).await },
-                );
-            }
-            Err(e) => {
-                log::error!("Accepting new connection: '{}'", e);
-            }
-        }
+// Replace our Noise key with a new one. The current one is kept and accepted on its own address
+// for a grace period, once restarted.
+fn rotate_noise_key(coordinatord: &CoordinatorD, output: &Output, grace_days: u64) {
+    match credential_key() {
+        Ok(None) => {}
+        Ok(Some(_)) => output.fail(
+            ExitCode::Key,
+            "Our Noise key is a systemd credential, rotate it with systemd-creds instead.",
+        ),
+        Err(e) => output.fail(ExitCode::Key, &e.to_string()),
     }
+
+    let passphrase = key_passphrase(coordinatord, output);
+    let passphrase = passphrase.as_ref().map(|p| p.as_bytes());
+    let previous_file = coordinatord.previous_secret_file();
+    let retire_at = chrono::Utc::now().timestamp() + grace_days as i64 * 24 * 3600;
+    let noise_secret = rotate_key(
+        &coordinatord.secret_file(),
+        &previous_file,
+        passphrase,
+        retire_at,
+    )
+    .unwrap_or_else(|e| output.fail(ExitCode::Key, &e.to_string()));
+    let previous_key = read_previous_key(&previous_file, passphrase)
+        .unwrap_or_else(|e| output.fail(ExitCode::Key, &e.to_string()))
+        .expect("Just written");
+
+    let (noise_pubkey, previous_pubkey) = (
+        public_key(&noise_secret).0.to_hex(),
+        public_key(&previous_key.secret).0.to_hex(),
+    );
+    output.success(
+        &format!(
+            "Our new Noise public key is {}. Restart the coordinator to use it: the previous one \
+             ({}) is then accepted on 'previous_key_listen' until {} UTC.",
+            noise_pubkey,
+            previous_pubkey,
+            chrono::NaiveDateTime::from_timestamp(retire_at, 0)
+        ),
+        serde_json::json!({
+            "noise_pubkey": noise_pubkey,
+            "previous_noise_pubkey": previous_pubkey,
+            "previous_retire_at": retire_at,
+        }),
+    );
 }
 
 fn main() {
@@ -275,28 +890,113 @@ fn main() {
     }
 
     let args = env::args().collect();
-    let conf_file = parse_args(args);
-    let config = Config::from_file(conf_file).unwrap_or_else(|e| {
-        eprintln!("Error parsing config: {}", e);
+    let (conf_file, command, output) = parse_args(args);
+
+    sodiumoxide::init().unwrap_or_else(|_| {
+        eprintln!("Error initializing libsodium.");
         process::exit(1);
     });
+
+    // There is no configuration file to read yet if we are restoring it.
+    if let Command::Restore(bundle_path) = &command {
+        restore(&output, conf_file, bundle_path);
+        return;
+    }
+    if let Command::TestVectors = command {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&test_vectors()).expect("Serializing test vectors")
+        );
+        return;
+    }
+
+    if let Command::ExplainError(code) = command {
+        explain_error(&output, code);
+        return;
+    }
+
+    let config = Config::from_file(conf_file.clone())
+        .unwrap_or_else(|e| output.fail(ExitCode::Config, &format!("Error parsing config: {}", e)));
+    if let Command::PreviewReload(new_conf_file) = &command {
+        preview_reload(&config, &output, new_conf_file);
+        return;
+    }
     let log_level = if let Some(ref level) = &config.log_level {
-        log::LevelFilter::from_str(level.as_str()).unwrap_or_else(|e| {
-            eprintln!("Invalid log level: {}", e);
-            process::exit(1);
-        })
+        log::LevelFilter::from_str(level.as_str())
+            .unwrap_or_else(|e| output.fail(ExitCode::Config, &format!("Invalid log level: {}", e)))
     } else {
         log::LevelFilter::Info
     };
+    let log_format = config.log_format.unwrap_or(LogFormat::Text);
     let coordinatord = CoordinatorD::from_config(config).unwrap_or_else(|e| {
-        eprintln!("Error creating global state: {}", e);
-        process::exit(1);
+        output.fail(
+            ExitCode::Config,
+            &format!("Error creating global state: {}", e),
+        )
     });
 
-    sodiumoxide::init().unwrap_or_else(|_| {
-        eprintln!("Error initializing libsodium.");
-        process::exit(1);
-    });
+    // Never output the database password, be it in logs or errors. Nor the object storage's
+    // and Tor's credentials.
+    let redactor = Redactor::new();
+    redactor.add_postgres_config(&coordinatord.postgres_config);
+    if let Some(ref replica_config) = coordinatord.postgres_replica_config {
+        redactor.add_postgres_config(replica_config);
+    }
+    if let Some(ref upload) = coordinatord.snapshot_upload {
+        redactor.add_secret(&upload.store.secret_key);
+    }
+    if let Some(password) = coordinatord
+        .tor
+        .as_ref()
+        .and_then(|tor| tor.password.as_ref())
+    {
+        redactor.add_secret(password);
+    }
+
+    if let Command::Backup(bundle_path) = &command {
+        backup(&coordinatord, &redactor, &output, conf_file, bundle_path);
+        return;
+    }
+    if let Command::ImportSigs(sigs_path) = &command {
+        import_sigs(&coordinatord, &redactor, &output, sigs_path);
+        return;
+    }
+    if let Command::ExportSnapshot(snapshot_path) = &command {
+        export_snapshot_to(&coordinatord, &redactor, &output, snapshot_path);
+        return;
+    }
+    if let Command::ImportSnapshot(snapshot_path) = &command {
+        import_snapshot_from(&coordinatord, &redactor, &output, snapshot_path);
+        return;
+    }
+    if let Command::CapacityReport = command {
+        report_capacity(&coordinatord, &redactor, &output);
+        return;
+    }
+    if let Command::UnexpectedTxids = command {
+        list_unexpected_txids(&coordinatord, &redactor, &output);
+        return;
+    }
+    if let Command::CheckDb = command {
+        check_db(&coordinatord, &redactor, &output);
+        return;
+    }
+    if let Command::PruneBefore(before, dry_run) = command {
+        prune(&coordinatord, &redactor, &output, before, dry_run);
+        return;
+    }
+    if let Command::GetPubkey = command {
+        get_pubkey(&coordinatord, &output);
+        return;
+    }
+    if let Command::RotateKey(grace_days) = command {
+        rotate_noise_key(&coordinatord, &output, grace_days);
+        return;
+    }
+    if let Command::Health = command {
+        health(&coordinatord, &output);
+        return;
+    }
 
     let log_file = coordinatord.log_file();
     let log_output = if coordinatord.daemon {
@@ -304,14 +1004,39 @@ fn main() {
     } else {
         None
     };
-    setup_logger(log_output, log_level).unwrap_or_else(|e| {
+    let log_ring = LogRing::new();
+    setup_logger(
+        log_output,
+        log_level,
+        log_format,
+        redactor.clone(),
+        log_ring.clone(),
+    )
+    .unwrap_or_else(|e| {
         eprintln!("Error setting up logger: {}", e);
         process::exit(1);
     });
 
+    // On a panic, write what we know to the data directory. The configuration we run with is
+    // identified by its hash, so that the diagnostics don't contain its secrets.
+    let config_hash = fs::read(conf_file_or_default(conf_file.clone(), &output))
+        .map(|content| sha256::Hash::hash(&content))
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading configuration file: {}", e);
+            process::exit(1);
+        });
+    install_panic_handler(coordinatord.data_dir.clone(), config_hash, log_ring);
+
+    // Don't run along another coordinator using the same data directory, nor read or create
+    // our Noise key while it does.
+    let datadir_lock = DataDirLock::acquire(&coordinatord.pid_file()).unwrap_or_else(|e| {
+        eprintln!("Error locking the data directory: {}", e);
+        process::exit(1);
+    });
+
     // Our static noise private key. It needs to be hot, as we use it to decrypt every
     // incoming message.
-    let noise_secret = read_or_create_noise_key(coordinatord.secret_file());
+    let (noise_secret, previous_key) = noise_keys(&coordinatord, &output);
 
     // We use tokio for async processing and io (which we don't even fully implement
     // yet.. But hey that'd be a nice FIXME as a first contribution for upstream :))
@@ -324,12 +1049,6 @@ fn main() {
             process::exit(1);
         });
 
-    println!(
-        "Started revault_coordinatord with Noise pubkey: {}",
-        NoisePubKey(curve25519::scalarmult_base(&curve25519::Scalar(noise_secret.0)).0)
-            .0
-            .to_hex()
-    );
     log::debug!("Stakeholders keys:");
     for k in coordinatord.stakeholders_keys.iter() {
         log::debug!("   {}", k.0.to_hex());
@@ -343,21 +1062,48 @@ fn main() {
         log::debug!("   {}", k.0.to_hex());
     }
 
-    if coordinatord.daemon {
+    let daemon = coordinatord.daemon;
+    let listen = coordinatord.listen;
+    let mut builder = Builder::new(coordinatord, noise_secret).datadir_lock(datadir_lock);
+    if let Some(previous_key) = previous_key {
+        builder = builder.previous_key(previous_key);
+    }
+    let coordinator = builder
+        .reload_on_sighup(conf_file)
+        .shutdown_on_signals()
+        .redactor(redactor)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Error listening on '{}': {}", listen, e);
+            process::exit(1);
+        });
+    println!(
+        "Started revault_coordinatord with Noise pubkey: {}",
+        coordinator.noise_pubkey().0.to_hex()
+    );
+
+    if daemon {
+        // We write our PID file ourselves, it's the one we hold the lock on
         let daemon = Daemonize {
-            // TODO: Make this configurable for inits
-            pid_file: Some(coordinatord.pid_file()),
+            pid_file: None,
             ..Daemonize::default()
         };
         daemon.doit().unwrap_or_else(|e| {
             eprintln!("Error daemonizing: {}", e);
             process::exit(1);
         });
-    }
-
-    rt.block_on(tokio_main(coordinatord, noise_secret))
-        .unwrap_or_else(|e| {
-            log::error!("Error in event loop: {}", e);
+        // The lock is inherited, our id isn't
+        coordinator.record_pid().unwrap_or_else(|e| {
+            log::error!("Writing our PID file: {}", e);
             process::exit(1);
         });
+    }
+
+    rt.block_on(coordinator.run()).unwrap_or_else(|e| {
+        log::error!("Error in event loop: {}", e);
+        process::exit(1);
+    });
+    // The connections left are blocked reading from their peers, don't wait for them.
+    rt.shutdown_background();
+    log::info!("Shut down");
 }
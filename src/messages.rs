@@ -0,0 +1,444 @@
+// Extensions to the messages of the coordinator protocol as defined in revault_net. They
+// only ever add fields to the existing messages, and responses to new requests, so that
+// participants which don't know about them keep working unmodified.
+
+use revault_net::{
+    bitcoin::{
+        hashes::{sha256, Hash, HashEngine},
+        secp256k1::PublicKey,
+        OutPoint, Txid,
+    },
+    message::server::{
+        FromManager, FromParticipant, FromStakeholder, GetSpendTx, Sig, Sigs, SpendTx,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+
+/// What kind of transaction a signature is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxType {
+    Cancel,
+    Emergency,
+    UnvaultEmergency,
+    Unvault,
+    Spend,
+}
+
+impl TxType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cancel => "cancel",
+            Self::Emergency => "emergency",
+            Self::UnvaultEmergency => "unvault-emergency",
+            Self::Unvault => "unvault",
+            Self::Spend => "spend",
+        }
+    }
+}
+
+/// The optional `tx_type` of a `sig` or `get_sigs`, parsed from the same message. It tags
+/// the signature with its transaction type, or only selects the signatures with this tag.
+#[derive(Debug, Deserialize)]
+pub struct TxTypeTag {
+    #[serde(default)]
+    pub tx_type: Option<TxType>,
+}
+
+/// The optional `known_pubkeys` of a `get_sigs`, parsed from the same message. The client
+/// already has the signatures from these keys, so we leave them out of the response.
+#[derive(Debug, Deserialize)]
+pub struct KnownPubkeys {
+    #[serde(default)]
+    pub known_pubkeys: BTreeSet<PublicKey>,
+}
+
+/// When a client wants to be acknowledged a write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Once committed to our database
+    Acked,
+    /// Once also applied by the synchronous standby(s) of our database
+    Replicated,
+}
+
+/// The optional `durability` of a `sig` or `set_spend_tx`, parsed from the same message.
+/// Clients setting it get acknowledged the write along with the durability it achieved.
+#[derive(Debug, Deserialize)]
+pub struct DurabilityRequest {
+    #[serde(default)]
+    pub durability: Option<Durability>,
+}
+
+/// The optional `want_ack` of a message storing data (`sig`, `set_spend_tx`,
+/// `set_sig_window` or `register_txids`), parsed from the same message. Clients setting it get
+/// acknowledged the write, or told why it was refused as a `WriteAck` with an `error_code`.
+#[derive(Debug, Deserialize)]
+pub struct AckRequest {
+    #[serde(default)]
+    pub want_ack: bool,
+}
+
+/// The response to a `set_sig_window` or `register_txids` containing `want_ack`. It's also
+/// what we answer a message storing data we refused, if its sender expects a response: unlike
+/// with an `ErrorResponse`, the connection then stays open.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteAck {
+    pub ack: bool,
+    /// The code of our error catalog telling why it was refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<u32>,
+    /// In how many milliseconds to retry, if it was only refused for the time being
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// The response to a `sig` containing a `durability` or `want_ack`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SigAck {
+    pub ack: bool,
+    pub durability: Durability,
+}
+
+/// A `sig`, along with its optional `tx_type`, as part of a `sigs` batch
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchedSig {
+    #[serde(flatten)]
+    pub sig: Sig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_type: Option<TxType>,
+}
+
+/// A stakeholder sharing signatures for many transactions at once, typically for all the
+/// vaults it just signed. They are stored in a single database transaction: all of them or
+/// none. Those we already have are skipped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SigBatch {
+    pub sigs: Vec<BatchedSig>,
+}
+
+/// The response to a `sigs` batch
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SigBatchAck {
+    pub ack: bool,
+    /// How many of them we didn't have already
+    pub stored: u64,
+}
+
+/// The response to a `get_spend_tx`, along with the version of this announcement. A
+/// manager replacing the Spend may pass it back as `expected_version`.
+#[derive(Debug, Serialize)]
+pub struct VersionedSpendTx {
+    #[serde(flatten)]
+    pub spend_tx: SpendTx,
+    pub version: i64,
+}
+
+/// The optional `if_newer_than` of a `get_spend_tx`, parsed from the same message. A
+/// watchtower polling for a Spend passes the version it already has, and only gets the
+/// transaction again once it was replaced.
+#[derive(Debug, Deserialize)]
+pub struct IfNewerThan {
+    #[serde(default)]
+    pub if_newer_than: Option<i64>,
+}
+
+/// The response to a `get_spend_tx` whose `if_newer_than` is not older than the version of
+/// the current announcement
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotModified {
+    pub not_modified: bool,
+    pub version: i64,
+}
+
+/// The optional fields of a `set_spend_tx`, parsed from the same message
+#[derive(Debug, Deserialize)]
+pub struct SetSpendTxVersion {
+    /// The version of the announcement this Spend replaces, as returned by `get_spend_tx`
+    #[serde(default)]
+    pub expected_version: Option<i64>,
+    /// Whether to replace the announcements of another Spend for the same deposit outpoints,
+    /// whatever their version
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// The response to a `set_spend_tx` containing an `expected_version` or a `durability`. If it
+/// was not accepted, `version` is the one of the announcement which replaced the expected one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetSpendTxResult {
+    pub accepted: bool,
+    pub version: i64,
+    /// The durability achieved by the write, if one was requested and it was accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durability: Option<Durability>,
+}
+
+/// The response to a `get_sigs`, along with a Merkle root committing to the set of
+/// signatures so that clients can check the consistency of what they got from several
+/// queries, or several coordinators.
+#[derive(Debug, Serialize)]
+pub struct CommittedSigs {
+    #[serde(flatten)]
+    pub sigs: Sigs,
+    pub merkle_root: sha256::Hash,
+}
+
+impl CommittedSigs {
+    pub fn new(sigs: Sigs) -> CommittedSigs {
+        let merkle_root = sigs_merkle_root(&sigs);
+        CommittedSigs { sigs, merkle_root }
+    }
+
+    /// Only the signatures from keys which aren't known to the client. The Merkle root still
+    /// commits to the whole set, so that it can check the set it ends up with.
+    pub fn missing(mut sigs: Sigs, known_pubkeys: &BTreeSet<PublicKey>) -> CommittedSigs {
+        let merkle_root = sigs_merkle_root(&sigs);
+        sigs.signatures
+            .retain(|pubkey, _| !known_pubkeys.contains(pubkey));
+        CommittedSigs { sigs, merkle_root }
+    }
+}
+
+fn tagged_hash(tag: u8, data: &[&[u8]]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[tag]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// The root of the Merkle tree whose leaves are `SHA256(0x00 || pubkey || signature)` for
+/// each compressed pubkey and DER-encoded signature, sorted by pubkey. Each node is
+/// `SHA256(0x01 || left || right)`, the last node of a level with an odd number of them is
+/// promoted as is. The root of an empty set is all zeros.
+pub fn sigs_merkle_root(sigs: &Sigs) -> sha256::Hash {
+    let mut leaves: Vec<([u8; 33], Vec<u8>)> = sigs
+        .signatures
+        .iter()
+        .map(|(pubkey, sig)| (pubkey.serialize(), sig.serialize_der().to_vec()))
+        .collect();
+    leaves.sort();
+    let mut level: Vec<sha256::Hash> = leaves
+        .iter()
+        .map(|(pubkey, sig)| tagged_hash(0x00, &[&pubkey[..], &sig[..]]))
+        .collect();
+
+    if level.is_empty() {
+        return sha256::Hash::from_inner([0; 32]);
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => tagged_hash(0x01, &[&left[..], &right[..]]),
+                [single] => *single,
+                _ => unreachable!("Chunks of 2"),
+            })
+            .collect();
+    }
+
+    level[0]
+}
+
+/// A request for the deposit outpoints which had their Spend announced since a given version,
+/// so that watchtowers can cheaply catch up with the announcements they missed. Pass 0 to get
+/// all of them. Versions are assigned when an announcement is made but only visible once it
+/// is stored, so concurrent announcements may appear out of order: a watchtower should query
+/// from a version somewhat older than the last one it got.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetSpendOutpoints {
+    pub since_version: i64,
+}
+
+/// The response to a `get_spend_outpoints`: the sorted list of outpoints, and the version to
+/// query next time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpendOutpoints {
+    pub outpoints: Vec<OutPoint>,
+    pub version: i64,
+}
+
+/// A watchtower subscribing to the Spend transactions announced for these deposit outpoints,
+/// or for all of them if the list is empty, to wait for them with `wait_spend_txs`. The
+/// subscription outlives the connection. We answer it with a `WriteAck`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeSpends {
+    pub subscribe_spends: Vec<OutPoint>,
+}
+
+/// A watchtower waiting up to `wait_spend_txs` seconds for Spend transactions it subscribed
+/// to and didn't acknowledge yet. It acknowledges those it got in response to its previous wait
+/// by passing back their `version`s: we push the others again, even after reconnecting.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitSpendTxs {
+    pub wait_spend_txs: u64,
+    #[serde(default)]
+    pub ack_versions: Vec<i64>,
+}
+
+/// A Spend transaction pushed to a watchtower, along with the version of its announcement for
+/// this deposit outpoint
+#[derive(Debug, Serialize)]
+pub struct PushedSpendTx {
+    pub deposit_outpoint: OutPoint,
+    #[serde(flatten)]
+    pub spend_tx: SpendTx,
+    pub version: i64,
+}
+
+/// The response to a `wait_spend_txs`, oldest announcement first. It's empty if none was
+/// made before the end of the wait.
+#[derive(Debug, Serialize)]
+pub struct PushedSpendTxs {
+    pub spend_txs: Vec<PushedSpendTx>,
+}
+
+/// The software a participant runs, which it may send as its first message after the
+/// handshake. There is no response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserAgent {
+    pub user_agent: String,
+}
+
+/// A manager restricting the acceptance of signatures for this transaction to a window of
+/// `window_secs` after its first sight (its first signature, or this message)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetSigWindow {
+    pub txid: Txid,
+    pub window_secs: u64,
+}
+
+/// A manager registering the transactions it expects signatures for. The first signature for
+/// any other transaction is still accepted, but flagged as it may be for a mistyped txid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterTxids {
+    pub register_txids: Vec<Txid>,
+}
+
+/// The optional `counter` of a message storing data, parsed from the same message. A
+/// participant setting it must increase it with each such message, across connections: we
+/// refuse those whose counter isn't greater than the last one we got from it, as they may be
+/// replayed from a previous session.
+#[derive(Debug, Deserialize)]
+pub struct MessageCounter {
+    #[serde(default)]
+    pub counter: Option<i64>,
+}
+
+/// The optional `deadline_ms` of any message, parsed from the same message. The client waits
+/// for the response this many milliseconds at most: we don't keep on processing the message
+/// past that, and don't answer it anymore then. The delay is counted from when we receive the
+/// message, so that the clocks of the client and ours don't need to agree.
+#[derive(Debug, Deserialize)]
+pub struct Deadline {
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+}
+
+/// What we answer a message we refuse with, right before closing the connection. The code is
+/// one of our error catalog's, which `--explain-error` describes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error_code: u32,
+    /// In how many milliseconds to retry, if it was only refused for the time being
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+/// Any message a stakeholder may send us
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StakeholderMessage {
+    Revault(FromStakeholder),
+    SigBatch(SigBatch),
+}
+
+/// Any message a manager may send us
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ManagerMessage {
+    Revault(FromManager),
+    SetSigWindow(SetSigWindow),
+    RegisterTxids(RegisterTxids),
+}
+
+/// Any message a stakeholder-manager may send us
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ParticipantMessage {
+    Revault(FromParticipant),
+    SigBatch(SigBatch),
+    SetSigWindow(SetSigWindow),
+    RegisterTxids(RegisterTxids),
+}
+
+/// Any message a watchtower may send us
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum FromWatchtower {
+    GetSpendTx(GetSpendTx),
+    GetSpendOutpoints(GetSpendOutpoints),
+    SubscribeSpends(SubscribeSpends),
+    WaitSpendTxs(WaitSpendTxs),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sigs_merkle_root;
+    use revault_net::{
+        bitcoin::{
+            hashes::{hex::FromHex, sha256},
+            secp256k1::{PublicKey, Signature},
+        },
+        message::server::Sigs,
+    };
+
+    use std::{collections::BTreeMap, str::FromStr};
+
+    #[test]
+    fn sigs_merkle_root_vectors() {
+        let mut signatures = BTreeMap::new();
+        assert_eq!(
+            sigs_merkle_root(&Sigs {
+                signatures: signatures.clone()
+            }),
+            sha256::Hash::from_hex(
+                "0000000000000000000000000000000000000000000000000000000000000000"
+            )
+            .unwrap()
+        );
+
+        let pubkeys = [
+            "03ffae85b76dd0dd96cbf23348fb398ab93274466759201ecf29d0f68ddd9d1b6c",
+            "028c887a4a78211ff320802134046cb1db92215614ac0a078c261ed860f3067f0f",
+            "03dacf1ec4d8caaabac45e9237e09d69aadce1b8945dcc4776fe73fb9f4c31f7a4",
+        ];
+        let sigs = [
+            "304402204b0ab8a7d95d5b67d5c1b8584a3075adcac787a315f79a9b52b5a736909c975502206def9036d3d980a7cb66f2baa64ebdcd6648d70b324c6c18c349fa240dd07ca8",
+            "304402201fbe986a41b69ea65bbb94a042cb6a5edacb898f290c76d76deb5d74241d0309022065d5ad54a36962b75857ce22ddf2189e71e5a0fe6df6e6d5d0c8acdb59e16374",
+            "30440220197a312ee648b762ed795c686217f79b1b825d80bfb87f7c5e387cd713e3a026022077fb9114caafcd6a0d2362cb4eaddb19b5ea8c0fb37b257338d4dfbce239ee9e",
+        ];
+        let expected_roots = [
+            "656bb859f7d3e1a8c7fb6a4be723965b4d11a6ba285f04de7a4fd63eca576f14",
+            "2a8aa3c85b95b76e2c5095bef2c4df47717203f27c75fe0ddb0e995290bc8595",
+            "45058c044ae9ac268c305b661dfcd79667e7286fc83ed06cfe7343d8d5f1ecf0",
+        ];
+        for ((pubkey, sig), root) in pubkeys.iter().zip(sigs.iter()).zip(expected_roots.iter()) {
+            signatures.insert(
+                PublicKey::from_str(pubkey).unwrap(),
+                Signature::from_str(sig).unwrap(),
+            );
+            assert_eq!(
+                sigs_merkle_root(&Sigs {
+                    signatures: signatures.clone()
+                }),
+                sha256::Hash::from_hex(root).unwrap()
+            );
+        }
+    }
+}
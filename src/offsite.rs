@@ -0,0 +1,392 @@
+// Scheduled snapshots of the database to an S3-compatible object storage, for offsite backups
+// without any Postgres tooling. They are the snapshots of `--export-snapshot`, each uploaded
+// under the time it was taken so that we only keep the most recent ones.
+//
+// Requests are signed with AWS' Signature Version 4, and buckets are addressed by path
+// (`<endpoint>/<bucket>/<key>`), which S3-compatible storages all support.
+
+use crate::{
+    coordinatord::{S3Store, SnapshotUpload},
+    db::{export_snapshot, Db, SnapshotError},
+};
+use revault_net::bitcoin::hashes::{
+    hex::ToHex,
+    hmac::{Hmac, HmacEngine},
+    sha256, Hash, HashEngine,
+};
+
+use std::{error, fmt};
+
+use chrono::{DateTime, Utc};
+use reqwest::{Method, Url};
+
+// The name of our snapshots, followed by the time they were taken
+const SNAPSHOT_PREFIX: &str = "coordinatord-snapshot-";
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// Taking the snapshot
+    Snapshot(SnapshotError),
+    /// The storage's endpoint isn't a valid URL
+    InvalidUrl(String),
+    /// Reaching the storage
+    Http(reqwest::Error),
+    /// The storage refused a request, with this status and body
+    Refused(u16, String),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Snapshot(e) => write!(f, "Taking the snapshot: {}", e),
+            Self::InvalidUrl(e) => write!(f, "Invalid storage URL: {}", e),
+            Self::Http(e) => write!(f, "Reaching the storage: {}", e),
+            Self::Refused(status, body) => {
+                write!(f, "The storage answered with status {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl error::Error for UploadError {}
+
+impl From<SnapshotError> for UploadError {
+    fn from(e: SnapshotError) -> Self {
+        Self::Snapshot(e)
+    }
+}
+
+impl From<reqwest::Error> for UploadError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+// Percent-encode as SigV4 wants it: everything but the unreserved characters, and the slashes
+// of paths
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+// The key deriving the signatures of a day, for a region
+fn signing_key(secret_key: &str, date: &str, region: &str) -> [u8; 32] {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, b"s3");
+    hmac(&key, b"aws4_request")
+}
+
+/// A request to the storage, as it is signed. The headers must contain the `host`,
+/// `x-amz-content-sha256` and `x-amz-date` ones.
+struct SignedRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a [(String, String)],
+    headers: &'a [(&'a str, String)],
+    payload_hash: &'a str,
+}
+
+impl<'a> SignedRequest<'a> {
+    // Returns the canonical request along with the names of the signed headers
+    fn canonical(&self) -> (String, String) {
+        let mut query: Vec<String> = self
+            .query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let mut headers: Vec<(String, &str)> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.trim()))
+            .collect();
+        headers.sort();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<&str>>()
+            .join(";");
+
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.method,
+            uri_encode(self.path, false),
+            query.join("&"),
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            self.payload_hash
+        );
+        (canonical, signed_headers)
+    }
+
+    fn signature(&self, secret_key: &str, region: &str, time: DateTime<Utc>) -> String {
+        let (canonical, _) = self.canonical();
+        let date = time.format("%Y%m%d").to_string();
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}/{}/s3/aws4_request\n{}",
+            time.format("%Y%m%dT%H%M%SZ"),
+            date,
+            region,
+            sha256::Hash::hash(canonical.as_bytes()).to_hex()
+        );
+        hmac(
+            &signing_key(secret_key, &date, region),
+            string_to_sign.as_bytes(),
+        )
+        .to_hex()
+    }
+
+    fn authorization(&self, store: &S3Store, time: DateTime<Utc>) -> String {
+        let (_, signed_headers) = self.canonical();
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}/{}/s3/aws4_request, SignedHeaders={}, Signature={}",
+            store.access_key,
+            time.format("%Y%m%d"),
+            store.region,
+            signed_headers,
+            self.signature(&store.secret_key, &store.region, time)
+        )
+    }
+}
+
+// The values of this element in an XML response. The storage's responses are simple enough
+// not to need a parser.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|s| s.split(close.as_str()).next())
+        .map(|value| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+// The snapshots to delete among these, to only keep the `kept` most recent ones. Their names
+// sort by time.
+fn expired_snapshots(mut keys: Vec<String>, kept: usize) -> Vec<String> {
+    keys.retain(|key| key.starts_with(SNAPSHOT_PREFIX));
+    keys.sort();
+    let expired = keys.len().saturating_sub(kept);
+    keys.truncate(expired);
+    keys
+}
+
+/// Uploads the snapshots to the storage and deletes the old ones
+pub(crate) struct Offsite {
+    settings: SnapshotUpload,
+    http: reqwest::Client,
+}
+
+impl Offsite {
+    pub fn new(settings: SnapshotUpload) -> Offsite {
+        Offsite {
+            settings,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // Send a signed request for this object, or for the bucket itself. Returns the body of
+    // the response.
+    async fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<String, UploadError> {
+        let store = &self.settings.store;
+        let path = match key {
+            Some(key) => format!("/{}/{}", store.bucket, key),
+            None => format!("/{}", store.bucket),
+        };
+        let mut url = Url::parse(&format!("{}{}", store.endpoint, uri_encode(&path, false)))
+            .map_err(|e| UploadError::InvalidUrl(e.to_string()))?;
+        // Encoded as it is signed
+        if !query.is_empty() {
+            let query: Vec<String> = query
+                .iter()
+                .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+                .collect();
+            url.set_query(Some(&query.join("&")));
+        }
+        // The Host header reqwest sets for this URL
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+
+        let time = Utc::now();
+        let payload_hash = sha256::Hash::hash(&body).to_hex();
+        let headers = [
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", time.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        let authorization = SignedRequest {
+            method: method.as_str(),
+            path: &path,
+            query: &query,
+            headers: &headers,
+            payload_hash: &payload_hash,
+        }
+        .authorization(store, time);
+
+        let response = self
+            .http
+            .request(method, url)
+            .header("x-amz-content-sha256", &headers[1].1)
+            .header("x-amz-date", &headers[2].1)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(UploadError::Refused(status.as_u16(), body));
+        }
+
+        Ok(body)
+    }
+
+    // The keys of all our snapshots in the bucket
+    async fn list_snapshots(&self) -> Result<Vec<String>, UploadError> {
+        let mut keys = Vec::new();
+        let mut continuation = None;
+        loop {
+            let mut query = vec![
+                ("list-type".to_string(), "2".to_string()),
+                ("prefix".to_string(), SNAPSHOT_PREFIX.to_string()),
+            ];
+            if let Some(token) = continuation.take() {
+                query.push(("continuation-token".to_string(), token));
+            }
+            let listing = self.request(Method::GET, None, query, vec![]).await?;
+            keys.extend(xml_values(&listing, "Key"));
+            continuation = xml_values(&listing, "NextContinuationToken").pop();
+            if continuation.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    /// Take a snapshot of this database and upload it, then delete the old ones. Returns the
+    /// key of the new snapshot, its size and the number of snapshots deleted.
+    pub async fn upload(&self, db: &Db) -> Result<(String, usize, usize), UploadError> {
+        let snapshot = export_snapshot(db).await?;
+        let content = serde_json::to_vec(&snapshot).expect("Snapshots always serialize");
+        let key = format!(
+            "{}{}.json",
+            SNAPSHOT_PREFIX,
+            Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        let size = content.len();
+        self.request(Method::PUT, Some(&key), vec![], content)
+            .await?;
+
+        let expired = expired_snapshots(self.list_snapshots().await?, self.settings.kept);
+        for expired_key in expired.iter() {
+            self.request(Method::DELETE, Some(expired_key), vec![], vec![])
+                .await?;
+        }
+
+        Ok((key, size, expired.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expired_snapshots, uri_encode, xml_values, SignedRequest};
+
+    use chrono::{DateTime, Utc};
+
+    #[test]
+    fn sigv4_signature() {
+        // The examples of the AWS documentation
+        let secret_key = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+        let empty_hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let time = DateTime::parse_from_rfc3339("2013-05-24T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = [
+            ("Host", "examplebucket.s3.amazonaws.com".to_string()),
+            ("Range", "bytes=0-9".to_string()),
+            ("x-amz-content-sha256", empty_hash.to_string()),
+            ("x-amz-date", "20130524T000000Z".to_string()),
+        ];
+        let request = SignedRequest {
+            method: "GET",
+            path: "/test.txt",
+            query: &[],
+            headers: &headers,
+            payload_hash: empty_hash,
+        };
+        assert_eq!(
+            request.canonical().1,
+            "host;range;x-amz-content-sha256;x-amz-date"
+        );
+        assert_eq!(
+            request.signature(secret_key, "us-east-1", time),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+
+        assert_eq!(
+            uri_encode("/bucket/a key+=é", false),
+            "/bucket/a%20key%2B%3D%C3%A9"
+        );
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+    }
+
+    #[test]
+    fn snapshots_retention() {
+        let listing =
+            "<ListBucketResult><Contents><Key>coordinatord-snapshot-20210102T000000Z.json\
+                       </Key></Contents><Contents><Key>coordinatord-snapshot-20210101T000000Z.json\
+                       </Key></Contents><Contents><Key>coordinatord-snapshot-20210103T000000Z.json\
+                       </Key></Contents><NextContinuationToken>a&amp;b</NextContinuationToken>\
+                       </ListBucketResult>";
+        let keys = xml_values(listing, "Key");
+        assert_eq!(keys.len(), 3);
+        assert_eq!(xml_values(listing, "NextContinuationToken"), vec!["a&b"]);
+        assert!(xml_values(listing, "IsTruncated").is_empty());
+
+        assert_eq!(
+            expired_snapshots(keys.clone(), 2),
+            vec!["coordinatord-snapshot-20210101T000000Z.json"]
+        );
+        assert_eq!(
+            expired_snapshots(keys.clone(), 1),
+            vec![
+                "coordinatord-snapshot-20210101T000000Z.json",
+                "coordinatord-snapshot-20210102T000000Z.json"
+            ]
+        );
+        assert!(expired_snapshots(keys, 3).is_empty());
+        // Only ours are deleted
+        assert!(expired_snapshots(vec!["other".to_string()], 0).is_empty());
+    }
+}
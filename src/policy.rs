@@ -0,0 +1,132 @@
+// Where the Spend transactions announced by the managers may send the funds. This is a backstop
+// against a compromised manager wallet: its Spends paying elsewhere are refused before the
+// watchtowers get to see them.
+//
+// The change and the CPFP outputs can't be listed, as they are derived anew for each Spend. So
+// we allow a configured number of outputs to other scripts, as long as they are P2WSH as those
+// two always are.
+
+use revault_net::bitcoin::{Script, Transaction};
+
+use std::{error, fmt};
+
+/// A Spend paying outside of the configured destinations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation {
+    /// The scripts paid to although they are not among the destinations
+    pub unlisted: Vec<Script>,
+    pub max_unlisted: usize,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The Spend pays to {} output(s) outside of the allowed destinations ({}), only {} \
+             P2WSH one(s) are allowed for the change and the CPFP",
+            self.unlisted.len(),
+            self.unlisted
+                .iter()
+                .map(|s| format!("{:x}", s))
+                .collect::<Vec<String>>()
+                .join(", "),
+            self.max_unlisted
+        )
+    }
+}
+
+impl error::Error for PolicyViolation {}
+
+/// The destinations Spend transactions may pay to
+#[derive(Debug, Clone)]
+pub struct SpendPolicy {
+    destinations: Vec<Script>,
+    max_unlisted: usize,
+}
+
+impl SpendPolicy {
+    /// Only allow paying to these scripts, and to at most `max_unlisted` P2WSH others
+    pub fn new(destinations: Vec<Script>, max_unlisted: usize) -> SpendPolicy {
+        SpendPolicy {
+            destinations,
+            max_unlisted,
+        }
+    }
+
+    /// Check all the outputs of this Spend pay to the allowed destinations
+    pub fn check(&self, spend_tx: &Transaction) -> Result<(), PolicyViolation> {
+        let unlisted: Vec<Script> = spend_tx
+            .output
+            .iter()
+            .map(|txo| &txo.script_pubkey)
+            .filter(|script| !self.destinations.contains(script))
+            .cloned()
+            .collect();
+
+        if unlisted.len() > self.max_unlisted || unlisted.iter().any(|s| !s.is_v0_p2wsh()) {
+            return Err(PolicyViolation {
+                unlisted,
+                max_unlisted: self.max_unlisted,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpendPolicy;
+    use revault_net::bitcoin::{blockdata::script::Builder, Address, Script, Transaction, TxOut};
+
+    use std::str::FromStr;
+
+    fn spend_tx(scripts: &[&Script]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: scripts
+                .iter()
+                .map(|script| TxOut {
+                    value: 100_000,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn spend_destinations() {
+        let destination = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+            .unwrap()
+            .script_pubkey();
+        let other_destination = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+            .unwrap()
+            .script_pubkey();
+        let p2wsh = |b: u8| {
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[b; 32])
+                .into_script()
+        };
+        let (change, cpfp, attacker) = (p2wsh(1), p2wsh(2), p2wsh(3));
+        let policy = SpendPolicy::new(vec![destination.clone()], 2);
+
+        policy.check(&spend_tx(&[&destination])).unwrap();
+        policy
+            .check(&spend_tx(&[&cpfp, &destination, &destination, &change]))
+            .unwrap();
+
+        // Paying to an unlisted destination which isn't P2WSH
+        let violation = policy
+            .check(&spend_tx(&[&cpfp, &other_destination]))
+            .unwrap_err();
+        assert_eq!(violation.unlisted, vec![cpfp.clone(), other_destination]);
+        // Paying to more P2WSH than the change and the CPFP
+        let violation = policy
+            .check(&spend_tx(&[&cpfp, &destination, &change, &attacker]))
+            .unwrap_err();
+        assert_eq!(violation.unlisted, vec![cpfp, change, attacker]);
+    }
+}
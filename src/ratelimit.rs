@@ -0,0 +1,145 @@
+// How often peers may send us messages, as each of them costs us database queries. A peer
+// going over its rate gets disconnected. The buckets of the peers' keys are kept across
+// connections, so that reconnecting right away doesn't give a peer a fresh budget.
+
+use revault_net::noise::PublicKey as NoisePubKey;
+
+use std::{
+    collections::HashMap,
+    error, fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A message sent faster than the rate we accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    /// When the peer may send us a message again
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sending messages too fast, may send again in {} ms",
+            self.retry_after.as_millis()
+        )
+    }
+}
+
+impl error::Error for RateLimited {}
+
+/// Allows `burst` messages at once, and then `rate` messages per second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Account for a message received at `now`, unless it's over the rate
+    pub fn take(&mut self, now: Instant) -> Result<(), RateLimited> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return Err(RateLimited {
+                retry_after: Duration::from_secs_f64((1.0 - self.tokens) / self.rate),
+            });
+        }
+        self.tokens -= 1.0;
+
+        Ok(())
+    }
+}
+
+/// The bucket of each peer's key
+#[derive(Debug)]
+pub struct RateLimits {
+    rate: f64,
+    burst: u32,
+    peers: Mutex<HashMap<[u8; 32], TokenBucket>>,
+}
+
+impl RateLimits {
+    pub fn new(rate: f64, burst: u32) -> RateLimits {
+        RateLimits {
+            rate,
+            burst,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Account for a message from this peer, from any of its connections
+    pub fn check(&self, peer: &NoisePubKey, now: Instant) -> Result<(), RateLimited> {
+        let mut peers = self.peers.lock().expect("Rate limits lock poisoned");
+        peers
+            .entry(peer.0)
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst, now))
+            .take(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimited, RateLimits, TokenBucket};
+    use revault_net::noise::PublicKey as NoisePubKey;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3, start);
+
+        // The burst at once, then 2 per second
+        for _ in 0..3 {
+            bucket.take(start).unwrap();
+        }
+        assert_eq!(
+            bucket.take(start),
+            Err(RateLimited {
+                retry_after: Duration::from_millis(500)
+            })
+        );
+        bucket.take(start + Duration::from_millis(500)).unwrap();
+        bucket.take(start + Duration::from_millis(600)).unwrap_err();
+        bucket.take(start + Duration::from_millis(1000)).unwrap();
+
+        // It doesn't fill past the burst
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            bucket.take(later).unwrap();
+        }
+        bucket.take(later).unwrap_err();
+    }
+
+    #[test]
+    fn peers_rate_limits() {
+        let limits = RateLimits::new(1.0, 2);
+        let (peer_a, peer_b) = (NoisePubKey([1; 32]), NoisePubKey([2; 32]));
+        let now = Instant::now();
+
+        limits.check(&peer_a, now).unwrap();
+        limits.check(&peer_a, now).unwrap();
+        limits.check(&peer_a, now).unwrap_err();
+        // Each peer has its own bucket
+        limits.check(&peer_b, now).unwrap();
+        limits.check(&peer_a, now + Duration::from_secs(1)).unwrap();
+    }
+}
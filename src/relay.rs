@@ -0,0 +1,357 @@
+// The connections from the network go through a relay before reaching the Noise transport,
+// which reads from its socket on its own. The relay holds a peer to a deadline for each of its
+// messages, however slowly it trickles it, and stops reading from it once it sent more than a
+// message may take, so that the transport never buffers an oversized message. The framing is
+// encrypted: the relay can't tell where a message ends, the transport tells it once it read
+// one. The part of the next message read along with the previous one isn't counted against
+// its size.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+// What the Noise framing adds to a message, at most: a header and a MAC for each frame of up
+// to 64KiB. And some slack, which covers the handshake.
+const NOISE_FRAME_SIZE: usize = 65535;
+const NOISE_FRAME_OVERHEAD: usize = 64;
+const FRAMING_SLACK: usize = 1024;
+
+// How often we check whether we started waiting for a message, while reading from the peer
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How many bytes a peer may send us for a message of this size
+pub fn framed_size(message_size: usize) -> usize {
+    message_size + (message_size / NOISE_FRAME_SIZE + 1) * NOISE_FRAME_OVERHEAD + FRAMING_SLACK
+}
+
+/// Why the relay cut a connection off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutOff {
+    /// The peer didn't send us a whole message in time
+    TimedOut,
+    /// The peer sent us more than a message may take
+    Oversized,
+}
+
+#[derive(Debug)]
+struct WindowState {
+    // Until when the peer may take to send the message we wait for, if we wait for one
+    deadline: Option<Instant>,
+    // How many more bytes we read from the peer until the transport reads a message
+    budget: usize,
+    // Whether the relay is over, and whether we cut the connection off
+    closed: bool,
+    cut_off: Option<CutOff>,
+}
+
+/// What a peer may send us until the transport reads its next message
+#[derive(Debug)]
+pub struct MessageWindow {
+    state: Mutex<WindowState>,
+    changed: Condvar,
+    max_bytes: usize,
+    timeout: Option<Duration>,
+}
+
+impl MessageWindow {
+    // The handshake is held to the same size limit as a message, but to a deadline of its own
+    fn new(
+        max_message_size: usize,
+        handshake_timeout: Duration,
+        timeout: Option<Duration>,
+    ) -> MessageWindow {
+        MessageWindow {
+            state: Mutex::new(WindowState {
+                deadline: Some(Instant::now() + handshake_timeout),
+                budget: framed_size(max_message_size),
+                closed: false,
+                cut_off: None,
+            }),
+            changed: Condvar::new(),
+            max_bytes: framed_size(max_message_size),
+            timeout,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<WindowState> {
+        self.state.lock().expect("Message window lock poisoned")
+    }
+
+    /// The transport starts reading a message, the peer has until the timeout to send it
+    pub fn waiting(&self) {
+        self.state().deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.changed.notify_all();
+    }
+
+    /// The transport read a message, the peer may send the next one
+    pub fn received(&self) {
+        let mut state = self.state();
+        state.deadline = None;
+        state.budget = self.max_bytes;
+        self.changed.notify_all();
+    }
+
+    /// Why the relay cut the connection off, if it did
+    pub fn cut_off(&self) -> Option<CutOff> {
+        self.state().cut_off
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.changed.notify_all();
+    }
+
+    // Wait until we may read from the peer, and return how much and until when
+    fn wait_budget(&self) -> Result<(usize, Option<Instant>), Option<CutOff>> {
+        let mut state = self.state();
+        loop {
+            if state.closed {
+                return Err(None);
+            }
+            let now = Instant::now();
+            if state.deadline.map_or(false, |deadline| deadline <= now) {
+                let cut_off = if state.budget == 0 {
+                    CutOff::Oversized
+                } else {
+                    CutOff::TimedOut
+                };
+                state.cut_off = Some(cut_off);
+                return Err(Some(cut_off));
+            }
+            if state.budget > 0 {
+                return Ok((state.budget, state.deadline));
+            }
+            // Until the transport reads the message, or the peer ran out of time
+            state = match state.deadline {
+                Some(deadline) => {
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .expect("Message window lock poisoned")
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(state)
+                    .expect("Message window lock poisoned"),
+            };
+        }
+    }
+
+    fn consume(&self, read: usize) {
+        let mut state = self.state();
+        state.budget = state.budget.saturating_sub(read);
+    }
+}
+
+/// A connection accepted from the network, relayed to a listener of its own for the transport
+/// to accept it on
+#[derive(Debug)]
+pub struct Relayed {
+    pub listener: TcpListener,
+    pub window: Arc<MessageWindow>,
+}
+
+/// Accept a connection on this listener and start relaying it. Blocks until a peer connects.
+/// The peer has until the handshake timeout to complete the handshake, which the transport
+/// tells by reading the first message as for any other.
+pub fn accept_relayed(
+    listener: &TcpListener,
+    max_message_size: usize,
+    handshake_timeout: Duration,
+    timeout: Option<Duration>,
+) -> Result<Relayed, io::Error> {
+    let (peer, peer_addr) = listener.accept()?;
+    let relay_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let transport = TcpStream::connect(relay_listener.local_addr()?)?;
+    let window = Arc::new(MessageWindow::new(
+        max_message_size,
+        handshake_timeout,
+        timeout,
+    ));
+
+    let (peer_reader, transport_writer) = (peer.try_clone()?, transport.try_clone()?);
+    let forward_window = window.clone();
+    thread::Builder::new()
+        .name(format!("relay from {}", peer_addr))
+        .spawn(move || forward(peer_reader, transport_writer, &forward_window, peer_addr))?;
+    let backward_window = window.clone();
+    thread::Builder::new()
+        .name(format!("relay to {}", peer_addr))
+        .spawn(move || backward(transport, peer, &backward_window))?;
+
+    Ok(Relayed {
+        listener: relay_listener,
+        window,
+    })
+}
+
+// Relay what the peer sends to the transport, within the limits of the message window
+fn forward(
+    mut peer: TcpStream,
+    mut transport: TcpStream,
+    window: &MessageWindow,
+    peer_addr: SocketAddr,
+) {
+    let mut buf = vec![0; RELAY_BUFFER_SIZE];
+    loop {
+        let (budget, deadline) = match window.wait_budget() {
+            Ok(window) => window,
+            Err(Some(cut_off)) => {
+                log::debug!(
+                    "Cutting the connection from '{}' off: {:?}",
+                    peer_addr,
+                    cut_off
+                );
+                break;
+            }
+            Err(None) => break,
+        };
+        // We may start waiting for a message while reading, check it every now and then
+        let timeout = deadline.map_or(DEADLINE_CHECK_INTERVAL, |deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        if timeout == Duration::from_secs(0) {
+            continue;
+        }
+        if peer.set_read_timeout(Some(timeout)).is_err() {
+            break;
+        }
+        match peer.read(&mut buf[..budget.min(RELAY_BUFFER_SIZE)]) {
+            Ok(0) => break,
+            Ok(read) => {
+                window.consume(read);
+                if transport.write_all(&buf[..read]).is_err() {
+                    break;
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => {
+                log::trace!("Reading from '{}': '{}'", peer_addr, e);
+                break;
+            }
+        }
+    }
+    window.close();
+    let _ = peer.shutdown(Shutdown::Both);
+    let _ = transport.shutdown(Shutdown::Both);
+}
+
+// Relay what the transport sends to the peer, until either of them is done
+fn backward(mut transport: TcpStream, mut peer: TcpStream, window: &MessageWindow) {
+    let _ = io::copy(&mut transport, &mut peer);
+    window.close();
+    let _ = peer.shutdown(Shutdown::Both);
+    let _ = transport.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_relayed, framed_size, CutOff, Relayed};
+
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    fn relayed_pair(
+        max_message_size: usize,
+        handshake_timeout: Duration,
+        timeout: Option<Duration>,
+    ) -> (TcpStream, TcpStream, Relayed) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let relayed =
+            accept_relayed(&listener, max_message_size, handshake_timeout, timeout).unwrap();
+        let (transport, _) = relayed.listener.accept().unwrap();
+        (peer, transport, relayed)
+    }
+
+    #[test]
+    fn relay_both_ways() {
+        let (mut peer, mut transport, relayed) = relayed_pair(1024, Duration::from_secs(5), None);
+        peer.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        transport.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        relayed.window.received();
+        transport.write_all(b"world").unwrap();
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        // The transport closing the connection closes it for the peer
+        drop(transport);
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+        assert_eq!(relayed.window.cut_off(), None);
+    }
+
+    #[test]
+    fn relay_deadline() {
+        let (mut peer, mut transport, relayed) = relayed_pair(
+            1024,
+            Duration::from_secs(5),
+            Some(Duration::from_millis(300)),
+        );
+        relayed.window.received();
+
+        // Not waiting for a message, the peer may take its time
+        thread::sleep(Duration::from_millis(500));
+        relayed.window.waiting();
+        // But it can't trickle it past the deadline
+        peer.write_all(b"a").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        thread::sleep(Duration::from_millis(100));
+        peer.write_all(b"b").unwrap();
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        assert_eq!(transport.read(&mut buf).unwrap(), 0);
+        assert_eq!(relayed.window.cut_off(), Some(CutOff::TimedOut));
+    }
+
+    #[test]
+    fn relay_handshake_deadline() {
+        // However long a peer may take to send its messages, it has to complete the handshake
+        // quickly
+        let (_peer, mut transport, relayed) = relayed_pair(1024, Duration::from_millis(300), None);
+        let mut buf = [0; 16];
+        assert_eq!(transport.read(&mut buf).unwrap(), 0);
+        assert_eq!(relayed.window.cut_off(), Some(CutOff::TimedOut));
+
+        // Once it did, it's not held to the handshake deadline anymore
+        let (mut peer, mut transport, relayed) =
+            relayed_pair(1024, Duration::from_millis(300), None);
+        relayed.window.received();
+        thread::sleep(Duration::from_millis(500));
+        peer.write_all(b"a").unwrap();
+        assert_eq!(transport.read(&mut buf).unwrap(), 1);
+        assert_eq!(relayed.window.cut_off(), None);
+    }
+
+    #[test]
+    fn relay_oversized() {
+        let (mut peer, mut transport, relayed) =
+            relayed_pair(10, Duration::from_secs(5), Some(Duration::from_secs(1)));
+        relayed.window.received();
+        relayed.window.waiting();
+
+        // We don't read more than a message may take
+        let _ = peer.write_all(&vec![0; framed_size(10) + 100]);
+        let mut received = Vec::new();
+        transport.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), framed_size(10));
+        assert_eq!(relayed.window.cut_off(), Some(CutOff::Oversized));
+    }
+}
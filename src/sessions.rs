@@ -0,0 +1,122 @@
+// Bookkeeping of the established sessions per role, so that the devices of one role (say,
+// wallets reconnecting in a loop) can't take all of our connections.
+
+use serde::Serialize;
+
+use std::sync::{Arc, Mutex};
+
+/// What an authenticated peer is to us. A peer may have several roles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Manager = 0,
+    Stakeholder = 1,
+    Watchtower = 2,
+}
+
+/// The number of sessions per role, and how many we accept
+#[derive(Debug)]
+pub struct Sessions {
+    max: [Option<u32>; 3],
+    open: Mutex<[u32; 3]>,
+}
+
+impl Sessions {
+    pub fn new(
+        max_manager_sessions: Option<u32>,
+        max_stakeholder_sessions: Option<u32>,
+        max_watchtower_sessions: Option<u32>,
+    ) -> Sessions {
+        Sessions {
+            max: [
+                max_manager_sessions,
+                max_stakeholder_sessions,
+                max_watchtower_sessions,
+            ],
+            open: Mutex::new([0; 3]),
+        }
+    }
+
+    /// Open a session for a peer with these roles, unless one of them already has as many
+    /// sessions as we accept. It's closed when the returned `Session` is dropped.
+    pub fn open(self: &Arc<Self>, roles: &'static [Role]) -> Result<Session, Role> {
+        let mut open = self.open.lock().expect("Sessions lock poisoned");
+        for role in roles {
+            if let Some(max) = self.max[*role as usize] {
+                if open[*role as usize] >= max {
+                    return Err(*role);
+                }
+            }
+        }
+        for role in roles {
+            open[*role as usize] += 1;
+        }
+
+        Ok(Session {
+            sessions: self.clone(),
+            roles,
+        })
+    }
+
+    /// The number of sessions of managers, stakeholders and watchtowers
+    pub fn current_counts(&self) -> [u32; 3] {
+        *self.open.lock().expect("Sessions lock poisoned")
+    }
+
+    /// The number of sessions of managers, stakeholders and watchtowers, unless they are
+    /// being updated
+    pub fn counts(&self) -> Option<[u32; 3]> {
+        self.open.try_lock().ok().map(|open| *open)
+    }
+}
+
+/// An established session, counted against the limits of its roles until dropped
+#[derive(Debug)]
+pub struct Session {
+    sessions: Arc<Sessions>,
+    roles: &'static [Role],
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut open = self.sessions.open.lock().expect("Sessions lock poisoned");
+        for role in self.roles {
+            open[*role as usize] -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, Sessions};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn sessions_limits() {
+        let sessions = Arc::new(Sessions::new(Some(1), Some(2), None));
+
+        let manager = sessions.open(&[Role::Manager]).unwrap();
+        assert_eq!(sessions.open(&[Role::Manager]).unwrap_err(), Role::Manager);
+        // A peer with both roles is refused if one of them is at its limit, and doesn't
+        // count against the other then
+        assert_eq!(
+            sessions
+                .open(&[Role::Manager, Role::Stakeholder])
+                .unwrap_err(),
+            Role::Manager
+        );
+        let _stakeholder = sessions.open(&[Role::Stakeholder]).unwrap();
+        drop(manager);
+        let _manager_stakeholder = sessions.open(&[Role::Manager, Role::Stakeholder]).unwrap();
+        assert_eq!(
+            sessions.open(&[Role::Stakeholder]).unwrap_err(),
+            Role::Stakeholder
+        );
+
+        // No limit for watchtowers
+        let _watchtowers: Vec<_> = (0..100)
+            .map(|_| sessions.open(&[Role::Watchtower]).unwrap())
+            .collect();
+    }
+}
@@ -0,0 +1,191 @@
+// Supervision of our background tasks. A task which panicked, returned, or stopped making
+// progress is restarted rather than silently lost (along with, for instance, the storage
+// checks) until the coordinator is restarted.
+
+use std::{
+    future::{self, Future},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+
+// How long we wait before restarting a task, so that one failing right away doesn't use up
+// a whole core
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Shared with a supervised task, which uses it to tell it is still making progress
+#[derive(Debug, Clone)]
+pub struct Heartbeat(Arc<Mutex<Instant>>);
+
+impl Heartbeat {
+    fn new() -> Heartbeat {
+        Heartbeat(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn beat(&self) {
+        *self.0.lock().expect("Heartbeat lock poisoned") = Instant::now();
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.0.lock().expect("Heartbeat lock poisoned").elapsed()
+    }
+}
+
+// Dropping a JoinHandle detaches the task, we want it to stop along with its supervisor.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Resolves once the task didn't beat for longer than its deadline, if it has one
+async fn stalled(heartbeat: &Heartbeat, deadline: Option<Duration>) {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return future::pending().await,
+    };
+    let mut check_interval = interval(deadline);
+    loop {
+        check_interval.tick().await;
+        if heartbeat.elapsed() > deadline {
+            return;
+        }
+    }
+}
+
+/// Runs our background tasks, and restarts them when they fail
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    supervisors: Vec<JoinHandle<()>>,
+    restarts: Arc<AtomicU64>,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor::default()
+    }
+
+    /// Run the task `make_task` creates, and create a new one whenever it panics or returns.
+    /// With a `deadline`, the task must also beat its heartbeat at least this often or it is
+    /// aborted and restarted. Note that it can only be aborted while waiting on something.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, deadline: Option<Duration>, make_task: F)
+    where
+        F: Fn(Heartbeat) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let restarts = self.restarts.clone();
+        self.supervisors.push(tokio::spawn(async move {
+            loop {
+                let heartbeat = Heartbeat::new();
+                let mut task = AbortOnDrop(tokio::spawn(make_task(heartbeat.clone())));
+                let reason = tokio::select! {
+                    result = &mut task.0 => match result {
+                        Ok(()) => "returned",
+                        Err(e) if e.is_panic() => "panicked",
+                        Err(_) => "was cancelled",
+                    },
+                    _ = stalled(&heartbeat, deadline) => "stalled",
+                };
+                drop(task);
+
+                let restarts = restarts.fetch_add(1, Ordering::Relaxed) + 1;
+                log::error!(
+                    "Background task '{}' {}, restarting it ({} restart(s) so far)",
+                    name,
+                    reason,
+                    restarts
+                );
+                sleep(RESTART_DELAY).await;
+            }
+        }));
+    }
+
+    /// How many times we restarted a task, for all of them
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Stop all the tasks and don't restart them
+    pub fn abort(self) {
+        for supervisor in self.supervisors {
+            supervisor.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Supervisor;
+
+    use std::{
+        future,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{runtime::Builder as RuntimeBuilder, time::sleep};
+
+    #[test]
+    fn supervisor_restarts() {
+        let rt = RuntimeBuilder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut supervisor = Supervisor::new();
+
+            // A task which panics the first time it's run
+            let runs = Arc::new(AtomicU64::new(0));
+            let panicking_runs = runs.clone();
+            supervisor.spawn("panicking", None, move |_| {
+                let runs = panicking_runs.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("Failing on purpose");
+                    }
+                    future::pending().await
+                }
+            });
+            sleep(Duration::from_millis(1500)).await;
+            assert_eq!(runs.load(Ordering::SeqCst), 2);
+            assert_eq!(supervisor.restarts(), 1);
+
+            // A task which only beats its heartbeat from the second time it is run
+            let stalled_runs = Arc::new(AtomicU64::new(0));
+            let task_runs = stalled_runs.clone();
+            supervisor.spawn(
+                "stalling",
+                Some(Duration::from_millis(100)),
+                move |heartbeat| {
+                    let runs = task_runs.clone();
+                    async move {
+                        if runs.fetch_add(1, Ordering::SeqCst) > 0 {
+                            loop {
+                                heartbeat.beat();
+                                sleep(Duration::from_millis(10)).await;
+                            }
+                        }
+                        future::pending().await
+                    }
+                },
+            );
+            sleep(Duration::from_millis(1700)).await;
+            assert_eq!(stalled_runs.load(Ordering::SeqCst), 2);
+            assert_eq!(supervisor.restarts(), 2);
+
+            supervisor.abort();
+        });
+    }
+}
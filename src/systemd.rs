@@ -0,0 +1,126 @@
+// Telling systemd where we stand when it runs us as a `Type=notify` service: that we are ready
+// once the database schema is ensured and we listen, what we are up to for `systemctl status`,
+// that we are still alive if its watchdog is enabled, and that we are shutting down. Outside of
+// systemd (`NOTIFY_SOCKET` unset) this does nothing.
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    process,
+    time::Duration,
+};
+
+// The socket systemd listens on for our notifications
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+// How often its watchdog expects to hear from us, in microseconds, and which process it is for
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// Whether systemd expects notifications from us
+pub fn is_notified() -> bool {
+    env::var_os(NOTIFY_SOCKET_ENV).is_some()
+}
+
+fn send(socket: &OsStr, state: &str) -> Result<(), io::Error> {
+    // We can't address the abstract sockets with the standard library
+    if socket.as_bytes().starts_with(b"@") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "abstract notification sockets are not supported",
+        ));
+    }
+    let datagram = UnixDatagram::unbound()?;
+    datagram.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// Send this state, newline-separated assignments such as `READY=1`, to systemd if it expects
+/// it. A failure is only logged, we keep on serving the participants.
+pub fn notify(state: &str) {
+    if let Some(socket) = env::var_os(NOTIFY_SOCKET_ENV) {
+        if let Err(e) = send(&socket, state) {
+            log::error!("Notifying systemd of '{}': '{}'", state, e);
+        }
+    }
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, our_pid: u32) -> Option<Duration> {
+    // It may be meant for another process, such as the one we forked from
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != our_pid {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// How often systemd's watchdog expects a keepalive from us, if it's enabled for us
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var(WATCHDOG_USEC_ENV).ok().as_deref(),
+        env::var(WATCHDOG_PID_ENV).ok().as_deref(),
+        process::id(),
+    )
+}
+
+/// Our status as `systemctl status` shows it, out of the number of sessions of managers,
+/// stakeholders and watchtowers and of the messages being processed
+pub fn status(sessions: [u32; 3], in_flight: usize) -> String {
+    let [managers, stakeholders, watchtowers] = sessions;
+    format!(
+        "STATUS={} manager(s), {} stakeholder(s) and {} watchtower(s) connected, {} message(s) \
+         being processed",
+        managers, stakeholders, watchtowers, in_flight
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_watchdog, send, status};
+
+    use std::{ffi::OsStr, os::unix::net::UnixDatagram, time::Duration};
+
+    #[test]
+    fn watchdog_settings() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("43"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(Some("not a number"), None, 42), None);
+        assert_eq!(parse_watchdog(None, Some("42"), 42), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let socket_path = std::env::temp_dir().join(format!(
+            "revault_coordinatord_notify_{}",
+            std::process::id()
+        ));
+        let socket = UnixDatagram::bind(&socket_path).unwrap();
+        let state = format!("READY=1\n{}", status([1, 2, 0], 3));
+        send(socket_path.as_os_str(), &state).unwrap();
+        let mut buf = [0; 256];
+        let read = socket.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..read],
+            "READY=1\nSTATUS=1 manager(s), 2 stakeholder(s) and 0 watchtower(s) connected, 3 \
+             message(s) being processed"
+                .as_bytes()
+        );
+        std::fs::remove_file(socket_path).unwrap();
+
+        assert!(send(OsStr::new("@abstract"), "READY=1").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+// Publishing our listener as a Tor onion service, through the control port of a running Tor.
+// The service is tied to the control connection: Tor removes it as soon as the connection is
+// closed, be it by us exiting or Tor restarting, so that we never leave a dangling one behind.
+//
+// The key of the service is created by Tor on first use and stored in the data directory, so
+// that the onion address our participants connect to stays the same across restarts.
+
+use crate::coordinatord::TorSettings;
+use revault_net::bitcoin::hashes::hex::ToHex;
+
+use std::{
+    error, fmt, fs,
+    io::{self, Write},
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+#[derive(Debug)]
+pub enum TorError {
+    /// Reaching the control port, or reading the cookie or the key of the service
+    Io(io::Error),
+    /// Tor refused a command, with this reply
+    Refused(String),
+    /// None of the authentication methods Tor accepts is one we can use
+    NoAuthMethod(Vec<String>),
+}
+
+impl fmt::Display for TorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Refused(reply) => write!(f, "Tor refused the command: '{}'", reply),
+            Self::NoAuthMethod(methods) => write!(
+                f,
+                "Can't authenticate to Tor with any of '{}', set 'tor_control_password'",
+                methods.join(",")
+            ),
+        }
+    }
+}
+
+impl error::Error for TorError {}
+
+impl From<io::Error> for TorError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// A password as a quoted string of the control protocol
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// The value of this key in the lines of a reply, such as `ServiceID=<id>`. Values may be
+// quoted.
+fn reply_value(lines: &[String], key: &str) -> Option<String> {
+    lines.iter().find_map(|line| {
+        line.split(' ')
+            .filter_map(|word| word.strip_prefix(key)?.strip_prefix('='))
+            .next()
+            .map(|value| value.trim_matches('"').replace("\\\\", "\\"))
+    })
+}
+
+// The authentication methods Tor accepts, from its PROTOCOLINFO reply
+fn auth_methods(lines: &[String]) -> Vec<String> {
+    reply_value(lines, "METHODS")
+        .map(|methods| methods.split(',').map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// An onion service published through a control connection, which lives as long as it does
+pub(crate) struct OnionService {
+    /// The `.onion` address of the service
+    pub address: String,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl OnionService {
+    // Send a command and get the lines of its reply, without their status
+    async fn command(&mut self, command: &str) -> Result<Vec<String>, TorError> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = self.lines.next_line().await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Tor closed the connection")
+            })?;
+            if line.len() < 4 || !line.starts_with("250") {
+                return Err(TorError::Refused(line));
+            }
+            let is_last = &line[3..4] == " ";
+            lines.push(line[4..].to_string());
+            if is_last {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Resolves once Tor closed the control connection, and with it the service
+    pub async fn closed(&mut self) {
+        while let Ok(Some(_)) = self.lines.next_line().await {}
+    }
+}
+
+// Read the key of the service if we created it already
+fn read_key(key_file: &Path) -> Result<Option<String>, io::Error> {
+    match fs::read_to_string(key_file) {
+        Ok(key) => Ok(Some(key.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_key(key_file: &Path, key: &str) -> Result<(), io::Error> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_file)?
+        .write_all(key.as_bytes())
+}
+
+/// Connect to the control port of Tor and publish an onion service forwarding connections to
+/// `target`, with the key from `key_file` (created if need be).
+pub(crate) async fn publish(
+    settings: &TorSettings,
+    key_file: &Path,
+    target: SocketAddr,
+) -> Result<OnionService, TorError> {
+    let (reader, writer) = TcpStream::connect(settings.control).await?.into_split();
+    let mut service = OnionService {
+        address: String::new(),
+        lines: BufReader::new(reader).lines(),
+        writer,
+    };
+
+    let protocol_info = service.command("PROTOCOLINFO 1").await?;
+    let methods = auth_methods(&protocol_info);
+    let authenticate = if let Some(ref password) = settings.password {
+        format!("AUTHENTICATE {}", quoted(password))
+    } else if methods.iter().any(|m| m == "NULL") {
+        "AUTHENTICATE".to_string()
+    } else if let (true, Some(cookie_file)) = (
+        methods.iter().any(|m| m == "COOKIE"),
+        reply_value(&protocol_info, "COOKIEFILE"),
+    ) {
+        format!("AUTHENTICATE {}", fs::read(cookie_file)?.to_hex())
+    } else {
+        return Err(TorError::NoAuthMethod(methods));
+    };
+    service.command(&authenticate).await?;
+
+    let key = read_key(key_file)?;
+    let reply = service
+        .command(&format!(
+            "ADD_ONION {} Port={},{}",
+            key.as_deref().unwrap_or("NEW:ED25519-V3"),
+            settings.virtual_port,
+            target
+        ))
+        .await?;
+    if key.is_none() {
+        if let Some(new_key) = reply_value(&reply, "PrivateKey") {
+            write_key(key_file, &new_key)?;
+        }
+    }
+    let service_id = reply_value(&reply, "ServiceID")
+        .ok_or_else(|| TorError::Refused(format!("No ServiceID in '{}'", reply.join(" "))))?;
+    service.address = format!("{}.onion", service_id);
+
+    Ok(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auth_methods, quoted, reply_value};
+
+    #[test]
+    fn control_replies() {
+        let protocol_info: Vec<String> = vec![
+            "PROTOCOLINFO 1".to_string(),
+            "AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/run/tor/control.authcookie\"".to_string(),
+            "VERSION Tor=\"0.4.5.7\"".to_string(),
+            "OK".to_string(),
+        ];
+        assert_eq!(auth_methods(&protocol_info), vec!["COOKIE", "SAFECOOKIE"]);
+        assert_eq!(
+            reply_value(&protocol_info, "COOKIEFILE").as_deref(),
+            Some("/run/tor/control.authcookie")
+        );
+        assert!(auth_methods(&["OK".to_string()]).is_empty());
+
+        let add_onion: Vec<String> = vec![
+            "ServiceID=5b6ojnnunmwkqu6ljzr2vomrcinoa6mkzwptp54k2hwtxjqhwknvqyid".to_string(),
+            "PrivateKey=ED25519-V3:cHJpdmF0ZSBrZXk=".to_string(),
+            "OK".to_string(),
+        ];
+        assert_eq!(
+            reply_value(&add_onion, "ServiceID").as_deref(),
+            Some("5b6ojnnunmwkqu6ljzr2vomrcinoa6mkzwptp54k2hwtxjqhwknvqyid")
+        );
+        assert_eq!(
+            reply_value(&add_onion, "PrivateKey").as_deref(),
+            Some("ED25519-V3:cHJpdmF0ZSBrZXk=")
+        );
+        assert_eq!(reply_value(&add_onion, "Private"), None);
+
+        assert_eq!(quoted("pass\"w\\rd"), "\"pass\\\"w\\\\rd\"");
+    }
+}
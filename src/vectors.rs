@@ -0,0 +1,118 @@
+// Canonical exchanges with the coordinator, for wallet implementers to check their messages
+// against and for our own tests.
+//
+// The Noise handshake isn't covered: revault_net generates the ephemeral keys itself, so
+// its transcripts can't be reproduced.
+
+use crate::messages::CommittedSigs;
+use revault_net::{
+    bitcoin::{
+        hashes::hex::FromHex,
+        secp256k1::{PublicKey, Signature},
+        Txid,
+    },
+    message::server::{GetSigs, Sig, Sigs},
+};
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use serde::Serialize;
+
+/// Who sends the message of a test vector
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Participant {
+    Stakeholder,
+    Manager,
+}
+
+/// A message sent to the coordinator and the response it must answer with, if any. The
+/// vectors are to be replayed in order, against an empty database.
+#[derive(Debug, Serialize)]
+pub struct TestVector {
+    pub description: &'static str,
+    pub sender: Participant,
+    pub message: serde_json::Value,
+    pub response: Option<serde_json::Value>,
+}
+
+fn to_value<T: Serialize>(msg: T) -> serde_json::Value {
+    serde_json::to_value(msg).expect("Our messages always serialize")
+}
+
+pub fn test_vectors() -> Vec<TestVector> {
+    let txid_a =
+        Txid::from_hex("264595a4ace1865dfa442bb923320b8f00413711655165ac13a470db2c5384c0").unwrap();
+    let txid_b =
+        Txid::from_hex("ead1ff4c948a4993097647b84cd0aa80d3205cc8ddcd19b8aca154743c2e5cec").unwrap();
+    let unknown_txid =
+        Txid::from_hex("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+    let pubkey_a =
+        PublicKey::from_str("03ffae85b76dd0dd96cbf23348fb398ab93274466759201ecf29d0f68ddd9d1b6c")
+            .unwrap();
+    let pubkey_b =
+        PublicKey::from_str("028c887a4a78211ff320802134046cb1db92215614ac0a078c261ed860f3067f0f")
+            .unwrap();
+    let signature_a = Signature::from_str("304402204b0ab8a7d95d5b67d5c1b8584a3075adcac787a315f79a9b52b5a736909c975502206def9036d3d980a7cb66f2baa64ebdcd6648d70b324c6c18c349fa240dd07ca8").unwrap();
+    let signature_b = Signature::from_str("304402201fbe986a41b69ea65bbb94a042cb6a5edacb898f290c76d76deb5d74241d0309022065d5ad54a36962b75857ce22ddf2189e71e5a0fe6df6e6d5d0c8acdb59e16374").unwrap();
+    let signature_c = Signature::from_str("30440220197a312ee648b762ed795c686217f79b1b825d80bfb87f7c5e387cd713e3a026022077fb9114caafcd6a0d2362cb4eaddb19b5ea8c0fb37b257338d4dfbce239ee9e").unwrap();
+
+    let mut sigs_a = BTreeMap::new();
+    sigs_a.insert(pubkey_a, signature_a);
+    let mut sigs_b = BTreeMap::new();
+    sigs_b.insert(pubkey_a, signature_b);
+    sigs_b.insert(pubkey_b, signature_c);
+
+    vec![
+        TestVector {
+            description: "A stakeholder shares a signature, there is no response",
+            sender: Participant::Stakeholder,
+            message: to_value(Sig {
+                id: txid_a,
+                pubkey: pubkey_a,
+                signature: signature_a,
+            }),
+            response: None,
+        },
+        TestVector {
+            description: "A stakeholder fetches the signatures for a transaction",
+            sender: Participant::Stakeholder,
+            message: to_value(GetSigs { id: txid_a }),
+            response: Some(to_value(CommittedSigs::new(Sigs { signatures: sigs_a }))),
+        },
+        TestVector {
+            description: "Two stakeholders share a signature for the same transaction",
+            sender: Participant::Stakeholder,
+            message: to_value(Sig {
+                id: txid_b,
+                pubkey: pubkey_a,
+                signature: signature_b,
+            }),
+            response: None,
+        },
+        TestVector {
+            description: "Two stakeholders share a signature for the same transaction",
+            sender: Participant::Stakeholder,
+            message: to_value(Sig {
+                id: txid_b,
+                pubkey: pubkey_b,
+                signature: signature_c,
+            }),
+            response: None,
+        },
+        TestVector {
+            description: "A manager fetches all the signatures for a transaction",
+            sender: Participant::Manager,
+            message: to_value(GetSigs { id: txid_b }),
+            response: Some(to_value(CommittedSigs::new(Sigs { signatures: sigs_b }))),
+        },
+        TestVector {
+            description: "Fetching the signatures for an unknown transaction returns none",
+            sender: Participant::Manager,
+            message: to_value(GetSigs { id: unknown_txid }),
+            response: Some(to_value(CommittedSigs::new(Sigs {
+                signatures: BTreeMap::new(),
+            }))),
+        },
+    ]
+}
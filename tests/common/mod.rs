@@ -0,0 +1,134 @@
+// A throwaway Postgres cluster for the functional tests, so that they don't depend on (nor
+// mess with) a running instance. It's created with the `initdb` and `pg_ctl` of the local
+// Postgres installation: the ones in `$PG_BIN` if set, in `pg_config --bindir` otherwise, or
+// in the `PATH` as a last resort. Postgres refuses to run as root.
+
+use std::{
+    env,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static TEMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!(
+            "revault_coordinatord_{}_{}_{}",
+            name,
+            std::process::id(),
+            TEMP_DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).expect("Creating temporary directory");
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn bin_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("PG_BIN") {
+        return Some(dir.into());
+    }
+    let output = Command::new("pg_config").arg("--bindir").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().into())
+}
+
+fn pg_command(bin_dir: &Option<PathBuf>, name: &str) -> Command {
+    match bin_dir {
+        Some(dir) => Command::new(dir.join(name)),
+        None => Command::new(name),
+    }
+}
+
+fn run(mut command: Command) {
+    let output = command
+        .output()
+        .unwrap_or_else(|e| panic!("Running {:?}, is Postgres installed? {}", command, e));
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// A Postgres cluster only listening on localhost, trusting any local connection. It's stopped
+/// and removed when dropped.
+pub struct PostgresCluster {
+    bin_dir: Option<PathBuf>,
+    data_dir: TempDir,
+    port: u16,
+}
+
+impl PostgresCluster {
+    pub fn start() -> PostgresCluster {
+        let bin_dir = bin_dir();
+        let data_dir = TempDir::new("postgres");
+        // Any free port, it's only taken by Postgres once we release it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Getting a free port")
+            .port();
+
+        let mut initdb = pg_command(&bin_dir, "initdb");
+        initdb.arg("-D").arg(data_dir.path()).args(&[
+            "-U",
+            "coordinatord",
+            "--auth=trust",
+            "--no-sync",
+        ]);
+        run(initdb);
+
+        let mut pg_ctl = pg_command(&bin_dir, "pg_ctl");
+        pg_ctl
+            .arg("-D")
+            .arg(data_dir.path())
+            .arg("-l")
+            .arg(data_dir.path().join("postgres.log"))
+            .arg("-o")
+            .arg(format!(
+                "-p {} -k {} -c listen_addresses=127.0.0.1 -c fsync=off",
+                port,
+                data_dir.path().display()
+            ))
+            .args(&["-w", "start"]);
+        run(pg_ctl);
+
+        PostgresCluster {
+            bin_dir,
+            data_dir,
+            port,
+        }
+    }
+
+    pub fn uri(&self) -> String {
+        format!("postgresql://coordinatord@127.0.0.1:{}/postgres", self.port)
+    }
+}
+
+impl Drop for PostgresCluster {
+    fn drop(&mut self) {
+        let _ = pg_command(&self.bin_dir, "pg_ctl")
+            .arg("-D")
+            .arg(self.data_dir.path())
+            .args(&["-m", "immediate", "-w", "stop"])
+            .output();
+    }
+}
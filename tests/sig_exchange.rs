@@ -0,0 +1,115 @@
+// The coordinator run as a whole, against its own database, with the participants connecting
+// to it over the network as they would in a deployment.
+
+mod common;
+
+use common::{PostgresCluster, TempDir};
+use revault_coordinatord::{
+    catalog,
+    config::Config,
+    db::fetch_sigs,
+    vectors::{test_vectors, Participant},
+    Coordinator,
+};
+use revault_net::{
+    bitcoin::{hashes::hex::ToHex, Txid},
+    sodiumoxide::{self, crypto::box_::gen_keypair},
+    transport::KKTransport,
+};
+
+use std::{str::FromStr, thread};
+
+use tokio::runtime::Builder as RuntimeBuilder;
+
+#[test]
+fn sig_exchange() {
+    sodiumoxide::init().unwrap();
+    let postgres = PostgresCluster::start();
+    let data_dir = TempDir::new("data");
+    let (stakeholder_pubkey, stakeholder_secret) = gen_keypair();
+    let (manager_pubkey, manager_secret) = gen_keypair();
+    let config: Config = toml::from_str(&format!(
+        r#"
+        postgres_uri = "{}"
+        data_dir = "{}"
+        listen = "127.0.0.1:0"
+        managers = ["{}"]
+        stakeholders = ["{}"]
+        watchtowers = []
+        "#,
+        postgres.uri(),
+        data_dir.path().to_str().unwrap(),
+        manager_pubkey.0.to_hex(),
+        stakeholder_pubkey.0.to_hex()
+    ))
+    .unwrap();
+
+    // With the Noise key it creates in its data directory, as the binary does
+    let coordinator = Coordinator::new(config).unwrap();
+    let addr = coordinator.local_addr();
+    let coordinator_pubkey = coordinator.noise_pubkey();
+    let shutdown = coordinator.shutdown_handle();
+    let db_config = coordinator.db_config();
+
+    let participants = thread::spawn(move || {
+        let mut stakeholder =
+            KKTransport::connect(addr, &stakeholder_secret, &coordinator_pubkey).unwrap();
+        let mut manager = KKTransport::connect(addr, &manager_secret, &coordinator_pubkey).unwrap();
+
+        for vector in test_vectors() {
+            let transport = match vector.sender {
+                Participant::Stakeholder => &mut stakeholder,
+                Participant::Manager => &mut manager,
+            };
+            match vector.response {
+                Some(response) => {
+                    transport
+                        .write(&serde_json::to_vec(&vector.message).unwrap())
+                        .unwrap();
+                    let got: serde_json::Value =
+                        serde_json::from_slice(&transport.read().unwrap()).unwrap();
+                    assert_eq!(got, response, "{}", vector.description);
+                }
+                // Wait for the write to be done, as the next vector may be sent by another
+                // participant over another connection
+                None => {
+                    let mut message = vector.message;
+                    message["want_ack"] = true.into();
+                    transport
+                        .write(&serde_json::to_vec(&message).unwrap())
+                        .unwrap();
+                    let ack: serde_json::Value =
+                        serde_json::from_slice(&transport.read().unwrap()).unwrap();
+                    assert_eq!(ack["ack"], true, "{}", vector.description);
+                }
+            }
+        }
+
+        // The signatures can't be stored by a manager, which gets disconnected for trying
+        let sig = test_vectors()
+            .into_iter()
+            .find(|vector| vector.sender == Participant::Stakeholder && vector.response.is_none())
+            .unwrap()
+            .message;
+        manager.write(&serde_json::to_vec(&sig).unwrap()).unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&manager.read().unwrap()).unwrap();
+        assert_eq!(error["error_code"], catalog::OUT_OF_ROLE);
+        assert!(manager.read().map(|msg| msg.is_empty()).unwrap_or(true));
+
+        shutdown.shutdown();
+    });
+
+    let rt = RuntimeBuilder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(coordinator.run()).unwrap();
+    participants.join().unwrap();
+
+    // What they shared can be read back from the coordinator's database
+    let txid = Txid::from_str(test_vectors()[0].message["id"].as_str().unwrap()).unwrap();
+    let sigs = rt
+        .block_on(fetch_sigs(&db_config.get(), txid, None))
+        .unwrap();
+    assert_eq!(sigs.signatures.len(), 1);
+}